@@ -1,3 +1,4 @@
+use intcode::outputs::GroupOutputs;
 use intcode::{Program, Value};
 use std::collections::HashSet;
 use std::fmt::{Display, Error, Formatter};
@@ -817,14 +818,15 @@ impl Robot {
     /// this function returns None if the programm has halted, Tile and Direction otherwise
     fn think(&mut self, input: Value) -> Option<(Tile, Direction)> {
         self.brain.set_input(input);
+        let (color, direction) = self.brain.outputs().pairs().next()?;
         // first output is the tile color 0 for black, 1 for white
-        let color = match self.brain.run_pipe()? {
+        let color = match color {
             0 => Tile::Black,
             1 => Tile::White,
             _ => panic!("Unknown tile color!"),
         };
         // second output is the direction to turn, 0 means left, 1 means right
-        let direction = match self.brain.run_pipe()? {
+        let direction = match direction {
             0 => Direction::Left,
             1 => Direction::Right,
             _ => panic!("Unknown direction!"),
@@ -1,3 +1,4 @@
+use intcode::search::Search;
 use intcode::{Program, Value};
 
 fn main() {
@@ -10,33 +11,32 @@ fn main() {
         123, 13, 127, 1, 10, 127, 131, 1, 131, 2, 135, 1, 135, 5, 0, 99, 2, 14, 0, 0,
     ];
 
-    // first addresses can't exceed length of data
-    for verb in 0..input.len() {
-        for noun in 0..input.len() {
-            let mut data = input.clone();
-            data[1] = noun as Value;
-            data[2] = verb as Value;
-            let mut p = Program::new(&data);
-            p.run();
-
-            let result = p.inspect(0);
-            // before running the programm replace position 1 with the value 12
-            // and replace position 2 with the value 2
-            if noun == 12 && verb == 2 {
-                println!("Solution1: {}", result)
-            }
+    // before running the programm replace position 1 with the value 12
+    // and replace position 2 with the value 2
+    let mut p = Program::new(&input);
+    p.poke(1, 12);
+    p.poke(2, 2);
+    p.run();
+    println!("Solution1: {}", p.inspect(0));
 
-            // Find the input noun and verb that cause the program to produce the output 19690720.
-            // What is 100 * noun + verb?
-            if p.inspect(0) == 19_690_720 {
-                println!(
-                    "Solution2: {}, Result: {}, Noun: {}, Verb: {}",
-                    100 * noun + verb,
-                    result,
-                    noun,
-                    verb
-                )
-            }
-        }
+    // Find the input noun and verb that cause the program to produce the output 19690720.
+    // What is 100 * noun + verb?
+    // first addresses can't exceed length of data
+    let len = input.len() as Value;
+    let search = Search::new(&Program::new(&input))
+        .patch(1, 0..len)
+        .patch(2, 0..len);
+    let found = search.find_first(|_, mut p| {
+        p.run();
+        p.inspect(0) == 19_690_720
+    });
+    if let Some(candidate) = found {
+        let (noun, verb) = (candidate.patches[0].1, candidate.patches[1].1);
+        println!(
+            "Solution2: {}, Noun: {}, Verb: {}",
+            100 * noun + verb,
+            noun,
+            verb
+        )
     }
 }
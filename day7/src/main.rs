@@ -1,5 +1,5 @@
+use intcode::search::Search;
 use intcode::{Program, Value};
-use permutohedron::Heap;
 
 fn main() {
     let data = vec![
@@ -26,18 +26,20 @@ fn main() {
     ];
     let amplifier = Amplifier::new(data);
 
-    // Part 1
-    let mut possible_values = vec![0, 1, 2, 3, 4];
-    let permutations = Heap::new(&mut possible_values);
+    let program = Program::new(&amplifier.data);
 
-    let max = permutations.map(|p| amplifier.amplify(&p)).max();
+    // Part 1
+    let search = Search::new(&program).input_permutations(&[0, 1, 2, 3, 4]);
+    let max = search
+        .find_max(|phases, _| amplifier.amplify(&phases.inputs))
+        .map(|(_, thrust)| thrust);
     println!("Highest possible thrust input is: {:?}", max);
 
     // Part 2
-    let mut possible_values = vec![5, 6, 7, 8, 9];
-    let permutations = Heap::new(&mut possible_values);
-
-    let max = permutations.map(|p| amplifier.amplify_pipe(&p)).max();
+    let search = Search::new(&program).input_permutations(&[5, 6, 7, 8, 9]);
+    let max = search
+        .find_max(|phases, _| amplifier.amplify_pipe(&phases.inputs))
+        .map(|(_, thrust)| thrust);
     println!("Highest possible thrust input is: {:?}", max)
 }
 
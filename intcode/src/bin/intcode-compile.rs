@@ -0,0 +1,34 @@
+use intcode::compiler::{compile, compile_object};
+use std::env;
+use std::fs;
+use std::process;
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    let (object, path) = match &args[1..] {
+        [path] => (false, path),
+        [flag, path] if flag == "-c" => (true, path),
+        _ => {
+            eprintln!("Usage: {} [-c] <source>", args[0]);
+            eprintln!("  -c  output a relocatable object for intcode-link");
+            process::exit(1);
+        }
+    };
+    let source = fs::read_to_string(path).expect("Could not read source");
+
+    let result = if object {
+        compile_object(&source).map(|object| object.to_string())
+    } else {
+        compile(&source).map(|image| {
+            let values: Vec<_> = image.iter().map(i64::to_string).collect();
+            values.join(",") + "\n"
+        })
+    };
+    match result {
+        Ok(output) => print!("{}", output),
+        Err(e) => {
+            eprintln!("{}: {}", path, e);
+            process::exit(1);
+        }
+    }
+}
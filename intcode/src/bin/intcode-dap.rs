@@ -0,0 +1,11 @@
+use intcode::dap::DapServer;
+use std::io::{self, BufReader};
+use std::process;
+
+fn main() {
+    let mut server = DapServer::new(io::stdout());
+    if let Err(e) = server.serve(BufReader::new(io::stdin())) {
+        eprintln!("Debug adapter failed: {}", e);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,24 @@
+use intcode::gdb::GdbStub;
+use intcode::{parse_image, Program};
+use std::env;
+use std::fs;
+use std::process;
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    if args.len() < 2 {
+        eprintln!("Usage: {} <image> [port]", args[0]);
+        process::exit(1);
+    }
+    let port = args.get(2).map(String::as_str).unwrap_or("1234");
+
+    let text = fs::read_to_string(&args[1]).expect("Could not read image");
+    let image = parse_image(&text).expect("Could not parse image");
+    let mut stub = GdbStub::new(Program::new(&image));
+
+    println!("Waiting for debugger on 127.0.0.1:{}", port);
+    if let Err(e) = stub.listen(format!("127.0.0.1:{}", port)) {
+        eprintln!("Debugging session failed: {}", e);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,43 @@
+use intcode::link::{link, Object};
+use intcode::parse_image;
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::process;
+
+/// Reads an object file, or a plain image which becomes an object named after its file
+fn read_object(path: &str) -> Object {
+    let text = fs::read_to_string(path).expect("Could not read object");
+    if let Ok(image) = parse_image(&text) {
+        let mut object = Object::from_image(&image);
+        let name = Path::new(path).file_stem().unwrap().to_string_lossy();
+        object.define(&name, 0);
+        return object;
+    }
+    text.parse().unwrap_or_else(|e| {
+        eprintln!("{}: {}", path, e);
+        process::exit(1);
+    })
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    if args.len() < 3 {
+        eprintln!("Usage: {} <output> <object>...", args[0]);
+        process::exit(1);
+    }
+    let objects: Vec<_> = args[2..].iter().map(|path| read_object(path)).collect();
+
+    match link(&objects) {
+        Ok((image, symbols)) => {
+            let values: Vec<_> = image.iter().map(i64::to_string).collect();
+            fs::write(&args[1], values.join(",") + "\n").expect("Could not write image");
+            let map = Path::new(&args[1]).with_extension("map");
+            symbols.save(map).expect("Could not write symbol map");
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,26 @@
+use intcode::lint::{lint, Severity};
+use intcode::parse_image;
+use std::env;
+use std::fs;
+use std::process;
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    if args.len() != 2 {
+        eprintln!("Usage: {} <image>", args[0]);
+        process::exit(1);
+    }
+    let text = fs::read_to_string(&args[1]).expect("Could not read image");
+    let image = parse_image(&text).expect("Could not parse image");
+
+    let diagnostics = lint(&image);
+    for diagnostic in &diagnostics {
+        println!("{}", diagnostic);
+    }
+    if diagnostics
+        .iter()
+        .any(|diagnostic| diagnostic.severity() == Severity::Error)
+    {
+        process::exit(1);
+    }
+}
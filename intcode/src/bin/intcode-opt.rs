@@ -0,0 +1,43 @@
+use intcode::optimize::{optimize, verify};
+use intcode::parse_image;
+use std::env;
+use std::fs;
+use std::process;
+
+const MAX_STEPS: usize = 100_000_000;
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    if args.len() < 3 {
+        eprintln!("Usage: {} <image> <output> [input]...", args[0]);
+        eprintln!("  each input is a comma separated list of values to check the result with");
+        process::exit(1);
+    }
+    let text = fs::read_to_string(&args[1]).expect("Could not read image");
+    let image = parse_image(&text).expect("Could not parse image");
+    let inputs: Vec<_> = args[3..]
+        .iter()
+        .map(|input| parse_image(input).expect("Could not parse input"))
+        .collect();
+
+    let optimized = optimize(&image);
+    for rewrite in &optimized.rewrites {
+        println!("{}", rewrite);
+    }
+    match verify(&image, &optimized.image, &inputs, MAX_STEPS) {
+        Ok(comparisons) => {
+            for comparison in comparisons {
+                println!(
+                    "input {:?}: {} steps, {} before",
+                    comparison.input, comparison.optimized_steps, comparison.steps
+                );
+            }
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    }
+    let values: Vec<_> = optimized.image.iter().map(i64::to_string).collect();
+    fs::write(&args[2], values.join(",") + "\n").expect("Could not write image");
+}
@@ -0,0 +1,44 @@
+use intcode::binary::{decode_image, encode_image, is_binary};
+use intcode::parse_image;
+use intcode::snapshot::Snapshot;
+use std::env;
+use std::fs;
+use std::process;
+
+/// Converts between the text and binary formats of images and snapshots, the direction is taken
+/// from the input
+fn convert(content: &[u8]) -> Result<Vec<u8>, String> {
+    if is_binary(content) {
+        if let Ok(image) = decode_image(content) {
+            let values: Vec<_> = image.iter().map(i64::to_string).collect();
+            return Ok((values.join(",") + "\n").into_bytes());
+        }
+        let snapshot = Snapshot::from_bytes(content).map_err(|e| e.to_string())?;
+        return Ok(snapshot.to_string().into_bytes());
+    }
+    let text = String::from_utf8_lossy(content);
+    if let Ok(image) = parse_image(&text) {
+        return Ok(encode_image(&image));
+    }
+    let snapshot: Snapshot = text
+        .parse()
+        .map_err(|e: intcode::snapshot::SnapshotError| e.to_string())?;
+    Ok(snapshot.to_bytes())
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    if args.len() != 3 {
+        eprintln!("Usage: {} <input> <output>", args[0]);
+        eprintln!("  packs a text image or snapshot, or unpacks a binary one");
+        process::exit(1);
+    }
+    let content = fs::read(&args[1]).expect("Could not read input");
+    match convert(&content) {
+        Ok(converted) => fs::write(&args[2], converted).expect("Could not write output"),
+        Err(e) => {
+            eprintln!("{}: {}", args[1], e);
+            process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,52 @@
+use intcode::repl::Repl;
+use std::env;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::path::PathBuf;
+
+fn history_path() -> Option<PathBuf> {
+    env::var_os("HOME").map(|home| PathBuf::from(home).join(".intcode_history"))
+}
+
+fn main() {
+    let history = history_path()
+        .and_then(|path| fs::read_to_string(path).ok())
+        .map(|text| text.lines().map(str::to_string).collect())
+        .unwrap_or_default();
+    let mut repl = Repl::with_history(history);
+    let previous = repl.history().len();
+
+    if let Some(path) = env::args().nth(1) {
+        println!("{}", repl.execute(&format!("load {}", path)));
+    }
+
+    let stdin = io::stdin();
+    let mut lines = stdin.lock().lines();
+    loop {
+        print!("intcode> ");
+        io::stdout().flush().unwrap();
+        let line = match lines.next() {
+            Some(Ok(line)) => line,
+            _ => break,
+        };
+        if line.trim() == "quit" || line.trim() == "exit" {
+            break;
+        }
+        let output = repl.execute(&line);
+        if !output.is_empty() {
+            println!("{}", output);
+        }
+    }
+
+    if let Some(path) = history_path() {
+        let mut text = String::new();
+        for line in &repl.history()[previous..] {
+            text.push_str(line);
+            text.push('\n');
+        }
+        let file = fs::OpenOptions::new().create(true).append(true).open(path);
+        if let Err(e) = file.and_then(|mut file| file.write_all(text.as_bytes())) {
+            eprintln!("Could not save history: {}", e);
+        }
+    }
+}
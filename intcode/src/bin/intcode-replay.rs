@@ -0,0 +1,32 @@
+use intcode::replay::Recording;
+use intcode::{parse_image, Program};
+use std::env;
+use std::fs;
+use std::process;
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    if args.len() != 3 {
+        eprintln!("Usage: {} <image> <recording>", args[0]);
+        process::exit(1);
+    }
+    let text = fs::read_to_string(&args[1]).expect("Could not read image");
+    let image = parse_image(&text).expect("Could not parse image");
+    let recording = Recording::load(&args[2]).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(1);
+    });
+
+    let mut program = Program::new(&image);
+    match program.replay(&recording) {
+        Ok(()) => println!(
+            "Replayed {} events in {} steps",
+            recording.events().len(),
+            program.elapsed()
+        ),
+        Err(divergence) => {
+            eprintln!("{}", divergence);
+            process::exit(2);
+        }
+    }
+}
@@ -0,0 +1,149 @@
+use intcode::tui::{Dashboard, Monitor};
+use intcode::{parse_image, Program};
+use std::env;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::panic;
+use std::process::{self, Command, Stdio};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+const FRAME: Duration = Duration::from_millis(50);
+const SPEEDS: [usize; 8] = [1, 10, 100, 1_000, 10_000, 100_000, 1_000_000, 10_000_000];
+/// Asking `stty` for the size spawns a process, so it is only done once a second
+const SIZE_FRAMES: usize = 20;
+
+fn stty(args: &[&str]) -> Option<String> {
+    let output = Command::new("stty")
+        .args(args)
+        .stdin(Stdio::inherit())
+        .output()
+        .ok()?;
+    Some(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+fn terminal_size() -> (usize, usize) {
+    let size = stty(&["size"]).unwrap_or_default();
+    let mut parts = size.split_whitespace().map(|part| part.parse().ok());
+    match (parts.next().flatten(), parts.next().flatten()) {
+        (Some(rows), Some(columns)) => (columns, rows),
+        _ => (80, 24),
+    }
+}
+
+/// Puts the terminal back in line mode on the main screen when dropped, also while panicking
+struct Screen {
+    saved: Option<String>,
+}
+
+impl Screen {
+    fn enter() -> Self {
+        let saved = stty(&["-g"]);
+        stty(&["-icanon", "-echo"]);
+        print!("\x1b[?1049h\x1b[?25l");
+        // restore before the panic message is printed, or it would vanish with the screen
+        let restore = Screen {
+            saved: saved.clone(),
+        };
+        let hook = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            restore.restore();
+            hook(info);
+        }));
+        Screen { saved }
+    }
+    fn restore(&self) {
+        print!("\x1b[?25h\x1b[?1049l");
+        let _ = io::stdout().flush();
+        if let Some(saved) = &self.saved {
+            stty(&[saved]);
+        }
+    }
+}
+
+impl Drop for Screen {
+    fn drop(&mut self) {
+        self.restore();
+    }
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    if args.len() < 2 {
+        eprintln!("Usage: {} <image> [input...]", args[0]);
+        eprintln!("       {} <image> --ring <phase,...>", args[0]);
+        process::exit(1);
+    }
+    let text = fs::read_to_string(&args[1]).expect("Could not read image");
+    let image = parse_image(&text).expect("Could not parse image");
+
+    let mut dashboard = if args.get(2).map(String::as_str) == Some("--ring") {
+        let phases = args.get(3).map_or(String::new(), String::clone);
+        Dashboard::ring(&image, &parse_image(&phases).expect("Invalid phases"))
+    } else {
+        let mut program = Program::new(&image);
+        for value in parse_image(&args[2..].join(",")).expect("Invalid input") {
+            program.set_input(value);
+        }
+        let mut dashboard = Dashboard::new();
+        dashboard.add(Monitor::new(&args[1], program));
+        dashboard
+    };
+
+    let _screen = Screen::enter();
+
+    let (keys, key_events) = mpsc::channel();
+    thread::spawn(move || {
+        for byte in io::stdin().lock().bytes() {
+            match byte {
+                Ok(byte) if keys.send(byte).is_ok() => {}
+                _ => break,
+            }
+        }
+    });
+
+    let mut playing = false;
+    let mut hex = false;
+    let mut speed = 0;
+    let mut size = terminal_size();
+    for frame in 1.. {
+        if playing {
+            for _ in 0..SPEEDS[speed] {
+                if !dashboard.tick() {
+                    playing = false;
+                    break;
+                }
+            }
+        }
+
+        if frame % SIZE_FRAMES == 0 {
+            size = terminal_size();
+        }
+        let (width, height) = size;
+        let state = if playing { "PLAYING" } else { "PAUSED" };
+        print!(
+            "\x1b[H{}\n\x1b[7m{:<width$}\x1b[0m",
+            dashboard.render(width, height.saturating_sub(1), hex),
+            format!(
+                " {}  {} ticks/frame  space play/pause  s step  +/- speed  h hex  q quit",
+                state, SPEEDS[speed]
+            ),
+            width = width
+        );
+        io::stdout().flush().unwrap();
+
+        match key_events.recv_timeout(FRAME) {
+            Ok(b' ') => playing = !playing,
+            Ok(b's') | Ok(b'n') => {
+                playing = false;
+                dashboard.tick();
+            }
+            Ok(b'+') | Ok(b'=') => speed = (speed + 1).min(SPEEDS.len() - 1),
+            Ok(b'-') => speed = speed.saturating_sub(1),
+            Ok(b'h') => hex = !hex,
+            Ok(b'q') | Err(mpsc::RecvTimeoutError::Disconnected) => break,
+            _ => {}
+        }
+    }
+}
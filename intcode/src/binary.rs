@@ -0,0 +1,440 @@
+//! A compact binary container for images and snapshots.
+//!
+//! A container starts with the magic bytes `\0INT`, a version byte and a byte for the kind of
+//! content, `I` for an image and `S` for a snapshot, and ends with the CRC-32 of everything
+//! before it in little endian. In between, numbers are LEB128 varints, values are zigzag encoded
+//! first so small negative values stay short.
+//!
+//! Memory is stored as its length and segments of values, each with its distance to the end of
+//! the previous one. Runs of zeros are left out, so data at high addresses doesn't cost the space
+//! in between. A snapshot stores its image that way, followed by the registers, the I/O queues,
+//! and the patches and changed memory as address and values.
+
+use crate::patch::Patch;
+use crate::snapshot::Snapshot;
+use crate::Value;
+use alloc::vec::Vec;
+use core::fmt::{Display, Error, Formatter};
+
+pub const MAGIC: [u8; 4] = *b"\0INT";
+pub const VERSION: u8 = 1;
+const IMAGE: u8 = b'I';
+const SNAPSHOT: u8 = b'S';
+
+/// Shorter runs of zeros are stored with the values around them
+const MIN_GAP: usize = 4;
+/// Longest memory read, a few bytes mustn't make the reader zero-fill gigabytes
+const MAX_MEMORY: usize = 1 << 24;
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum BinaryError {
+    /// Doesn't start with `MAGIC`
+    NotBinary,
+    UnsupportedVersion(u8),
+    /// An image where a snapshot was expected or the other way round
+    WrongKind(u8),
+    Truncated,
+    Checksum {
+        expected: u32,
+        found: u32,
+    },
+    /// A varint which doesn't fit 64 bits, a segment outside the memory, a memory longer than
+    /// 2^24 values or a run past the end of the address space
+    Invalid {
+        offset: usize,
+    },
+    /// Bytes left over after the content
+    TrailingBytes,
+}
+
+impl Display for BinaryError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        match self {
+            BinaryError::NotBinary => write!(f, "not a binary intcode container"),
+            BinaryError::UnsupportedVersion(version) => {
+                write!(f, "unsupported container version {}", version)
+            }
+            BinaryError::WrongKind(kind) => {
+                write!(f, "unexpected content kind {:?}", *kind as char)
+            }
+            BinaryError::Truncated => write!(f, "container is truncated"),
+            BinaryError::Checksum { expected, found } => write!(
+                f,
+                "checksum mismatch, expected {:08x} but found {:08x}",
+                expected, found
+            ),
+            BinaryError::Invalid { offset } => write!(f, "invalid data at byte {}", offset),
+            BinaryError::TrailingBytes => write!(f, "unexpected bytes after the content"),
+        }
+    }
+}
+
+impl core::error::Error for BinaryError {}
+
+/// Whether `bytes` start like a binary container rather than text
+pub fn is_binary(bytes: &[u8]) -> bool {
+    bytes.starts_with(&MAGIC)
+}
+
+/// CRC-32 as used by zip and png
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in bytes {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xedb8_8320 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
+
+struct Writer {
+    bytes: Vec<u8>,
+}
+
+impl Writer {
+    fn new(kind: u8) -> Self {
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(&[VERSION, kind]);
+        Writer { bytes }
+    }
+    fn varint(&mut self, mut number: u64) {
+        while number >= 0x80 {
+            self.bytes.push(number as u8 | 0x80);
+            number >>= 7;
+        }
+        self.bytes.push(number as u8);
+    }
+    fn usize(&mut self, number: usize) {
+        self.varint(number as u64)
+    }
+    fn value(&mut self, value: Value) {
+        self.varint(((value << 1) ^ (value >> 63)) as u64)
+    }
+    fn values(&mut self, values: &[Value]) {
+        self.usize(values.len());
+        for &value in values {
+            self.value(value);
+        }
+    }
+    fn memory(&mut self, memory: &[Value]) {
+        let segments = segments(memory);
+        self.usize(memory.len());
+        self.usize(segments.len());
+        let mut end = 0;
+        for (start, values) in segments {
+            self.usize(start - end);
+            self.values(values);
+            end = start + values.len();
+        }
+    }
+    fn runs(&mut self, runs: &[Patch]) {
+        self.usize(runs.len());
+        for run in runs {
+            self.usize(run.address);
+            self.values(&run.values);
+        }
+    }
+    fn finish(mut self) -> Vec<u8> {
+        let checksum = crc32(&self.bytes);
+        self.bytes.extend_from_slice(&checksum.to_le_bytes());
+        self.bytes
+    }
+}
+
+/// The parts of `memory` between runs of at least `MIN_GAP` zeros, with their addresses
+fn segments(memory: &[Value]) -> Vec<(usize, &[Value])> {
+    let mut segments = Vec::new();
+    let mut start = None;
+    let mut zeros = 0;
+    for (address, &value) in memory.iter().enumerate() {
+        if value != 0 {
+            zeros = 0;
+            start = start.or(Some(address));
+        } else {
+            zeros += 1;
+            if let (Some(first), MIN_GAP) = (start, zeros) {
+                segments.push((first, &memory[first..address + 1 - MIN_GAP]));
+                start = None;
+            }
+        }
+    }
+    if let Some(first) = start {
+        segments.push((first, &memory[first..memory.len() - zeros]));
+    }
+    segments
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Reader<'a> {
+    /// Checks the header and checksum and returns a reader for the content
+    fn new(bytes: &'a [u8], kind: u8) -> Result<Self, BinaryError> {
+        if !is_binary(bytes) {
+            return Err(BinaryError::NotBinary);
+        }
+        let header = MAGIC.len() + 2;
+        if bytes.len() < header + 4 {
+            return Err(BinaryError::Truncated);
+        }
+        if bytes[MAGIC.len()] != VERSION {
+            return Err(BinaryError::UnsupportedVersion(bytes[MAGIC.len()]));
+        }
+        if bytes[MAGIC.len() + 1] != kind {
+            return Err(BinaryError::WrongKind(bytes[MAGIC.len() + 1]));
+        }
+        let (content, checksum) = bytes.split_at(bytes.len() - 4);
+        let mut expected = [0; 4];
+        expected.copy_from_slice(checksum);
+        let expected = u32::from_le_bytes(expected);
+        let found = crc32(content);
+        if expected != found {
+            return Err(BinaryError::Checksum { expected, found });
+        }
+        Ok(Reader {
+            bytes: content,
+            offset: header,
+        })
+    }
+    fn varint(&mut self) -> Result<u64, BinaryError> {
+        let start = self.offset;
+        let mut number = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = *self.bytes.get(self.offset).ok_or(BinaryError::Truncated)?;
+            self.offset += 1;
+            let bits = u64::from(byte & 0x7f);
+            if bits << shift >> shift != bits {
+                break;
+            }
+            number |= bits << shift;
+            if byte & 0x80 == 0 {
+                return Ok(number);
+            }
+        }
+        Err(BinaryError::Invalid { offset: start })
+    }
+    fn usize(&mut self) -> Result<usize, BinaryError> {
+        let start = self.offset;
+        let number = self.varint()?;
+        if number > usize::MAX as u64 {
+            return Err(BinaryError::Invalid { offset: start });
+        }
+        Ok(number as usize)
+    }
+    fn value(&mut self) -> Result<Value, BinaryError> {
+        let number = self.varint()?;
+        Ok((number >> 1) as Value ^ -((number & 1) as Value))
+    }
+    fn values(&mut self) -> Result<Vec<Value>, BinaryError> {
+        let count = self.usize()?;
+        // every value takes at least one byte, don't trust the count any further
+        let mut values = Vec::with_capacity(count.min(self.bytes.len() - self.offset));
+        for _ in 0..count {
+            values.push(self.value()?);
+        }
+        Ok(values)
+    }
+    /// The memory grows as segments arrive, up to the declared length at the end. A length over
+    /// `MAX_MEMORY` or one which can't be allocated is invalid.
+    fn memory(&mut self) -> Result<Vec<Value>, BinaryError> {
+        let len_offset = self.offset;
+        let len = self.usize()?;
+        if len > MAX_MEMORY {
+            return Err(BinaryError::Invalid { offset: len_offset });
+        }
+        let mut memory = Vec::new();
+        let grow = |memory: &mut Vec<Value>, end: usize, offset: usize| {
+            memory
+                .try_reserve_exact(end - memory.len())
+                .map_err(|_| BinaryError::Invalid { offset })?;
+            memory.resize(end, 0);
+            Ok(())
+        };
+        for _ in 0..self.usize()? {
+            let start = self.offset;
+            let gap = self.usize()?;
+            let values = self.values()?;
+            let end = memory
+                .len()
+                .checked_add(gap)
+                .and_then(|address| address.checked_add(values.len()))
+                .filter(|&end| end <= len)
+                .ok_or(BinaryError::Invalid { offset: start })?;
+            grow(&mut memory, end - values.len(), start)?;
+            memory.extend(values);
+        }
+        grow(&mut memory, len, len_offset)?;
+        Ok(memory)
+    }
+    fn runs(&mut self) -> Result<Vec<Patch>, BinaryError> {
+        let count = self.usize()?;
+        let mut runs = Vec::with_capacity(count.min(self.bytes.len() - self.offset));
+        for _ in 0..count {
+            let start = self.offset;
+            let address = self.usize()?;
+            let run = Patch {
+                address,
+                values: self.values()?,
+            };
+            if run.range().is_none() {
+                return Err(BinaryError::Invalid { offset: start });
+            }
+            runs.push(run);
+        }
+        Ok(runs)
+    }
+    fn finish(self) -> Result<(), BinaryError> {
+        if self.offset == self.bytes.len() {
+            Ok(())
+        } else {
+            Err(BinaryError::TrailingBytes)
+        }
+    }
+}
+
+pub fn encode_image(image: &[Value]) -> Vec<u8> {
+    let mut writer = Writer::new(IMAGE);
+    writer.memory(image);
+    writer.finish()
+}
+
+pub fn decode_image(bytes: &[u8]) -> Result<Vec<Value>, BinaryError> {
+    let mut reader = Reader::new(bytes, IMAGE)?;
+    let image = reader.memory()?;
+    reader.finish()?;
+    Ok(image)
+}
+
+impl Snapshot {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut writer = Writer::new(SNAPSHOT);
+        writer.memory(&self.image);
+        writer.usize(self.instruction_ptr);
+        writer.usize(self.relative_base);
+        writer.usize(self.elapsed);
+        writer.values(&self.input);
+        writer.values(&self.output);
+        writer.runs(&self.patches);
+        writer.runs(&self.memory);
+        writer.finish()
+    }
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, BinaryError> {
+        let mut reader = Reader::new(bytes, SNAPSHOT)?;
+        let snapshot = Snapshot {
+            image: reader.memory()?,
+            instruction_ptr: reader.usize()?,
+            relative_base: reader.usize()?,
+            elapsed: reader.usize()?,
+            input: reader.values()?,
+            output: reader.values()?,
+            patches: reader.runs()?,
+            memory: reader.runs()?,
+        };
+        reader.finish()?;
+        Ok(snapshot)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Program;
+    use alloc::vec;
+
+    #[test]
+    fn test_encoding() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+
+        let mut writer = Writer::new(IMAGE);
+        let values = [0, -1, 1, 63, -64, 64, Value::MAX, Value::MIN];
+        writer.values(&values);
+        writer.usize(300);
+        let bytes = writer.finish();
+        assert_eq!(bytes[6..10], [8, 0, 1, 2]);
+
+        let mut reader = Reader::new(&bytes, IMAGE).unwrap();
+        assert_eq!(reader.values().unwrap(), values);
+        assert_eq!(reader.usize().unwrap(), 300);
+        assert_eq!(reader.finish(), Ok(()));
+    }
+
+    #[test]
+    fn test_image_roundtrip() {
+        // a zero run long enough to be left out, a short one and trailing zeros
+        let mut image = vec![1101, 0, 0, 3, 99];
+        image.extend(vec![0; 1000]);
+        image.extend(&[-7, 0, 0, 8, 0, 0]);
+        let bytes = encode_image(&image);
+        assert!(bytes.len() < 30);
+        assert_eq!(decode_image(&bytes), Ok(image));
+
+        assert_eq!(decode_image(&encode_image(&[])), Ok(vec![]));
+        assert_eq!(decode_image(&encode_image(&[0; 3])), Ok(vec![0; 3]));
+    }
+
+    #[test]
+    fn test_snapshot_roundtrip() {
+        let mut p = Program::new(&[3, 1000, 4, 1000, 1105, 1, 0]);
+        p.poke(5000, 9);
+        p.set_input(-5);
+        p.set_input(6);
+        p.run_pipe();
+        let snapshot = p.snapshot();
+        assert_eq!(Snapshot::from_bytes(&snapshot.to_bytes()), Ok(snapshot));
+    }
+
+    #[test]
+    fn test_errors() {
+        let bytes = encode_image(&[1, 2, 3]);
+        assert_eq!(decode_image(b"1,2,3"), Err(BinaryError::NotBinary));
+        assert_eq!(
+            Snapshot::from_bytes(&bytes),
+            Err(BinaryError::WrongKind(b'I'))
+        );
+        let truncated = &bytes[..bytes.len() - 1];
+        let (content, checksum) = truncated.split_at(truncated.len() - 4);
+        assert_eq!(
+            decode_image(truncated),
+            Err(BinaryError::Checksum {
+                expected: u32::from_le_bytes([checksum[0], checksum[1], checksum[2], checksum[3]]),
+                found: crc32(content)
+            })
+        );
+
+        let mut corrupt = bytes.clone();
+        corrupt[7] ^= 1;
+        assert!(matches!(
+            decode_image(&corrupt),
+            Err(BinaryError::Checksum { .. })
+        ));
+
+        // a segment past the end of the memory
+        let mut writer = Writer::new(IMAGE);
+        writer.usize(2);
+        writer.usize(1);
+        writer.usize(1);
+        writer.values(&[1, 2]);
+        assert_eq!(
+            decode_image(&writer.finish()),
+            Err(BinaryError::Invalid { offset: 8 })
+        );
+
+        // lengths too large to read
+        for &len in &[MAX_MEMORY + 1, 1 << 29, usize::MAX / 2] {
+            let mut writer = Writer::new(IMAGE);
+            writer.usize(len);
+            writer.usize(0);
+            assert_eq!(
+                decode_image(&writer.finish()),
+                Err(BinaryError::Invalid { offset: 6 })
+            );
+        }
+        let mut writer = Writer::new(IMAGE);
+        writer.usize(MAX_MEMORY);
+        writer.usize(0);
+        assert_eq!(decode_image(&writer.finish()).unwrap().len(), MAX_MEMORY);
+    }
+}
@@ -0,0 +1,1059 @@
+//! A small structured language which compiles to intcode.
+//!
+//! Programs are a list of functions, execution starts at `main`. All values are integers:
+//! ```text
+//! // prints the first n fibonacci numbers
+//! fn main() {
+//!     let n = input();
+//!     let i = 0;
+//!     while i < n {
+//!         output(fib(i));
+//!         i = i + 1;
+//!     }
+//! }
+//!
+//! fn fib(n) {
+//!     if n < 2 {
+//!         return n;
+//!     }
+//!     return fib(n - 1) + fib(n - 2);
+//! }
+//! ```
+//! Statements are `let`, assignment, `if`/`else`, `while`, `return`, blocks and expressions
+//! followed by `;`. Expressions support `+ - * < <= > >= == != && || !`, unary `-`, calls and
+//! the built-ins `input()` and `output(x)`. Both sides of `&&` and `||` are always evaluated.
+//! Variables are scoped to their block and functions return 0 when they end without `return`.
+//!
+//! Functions use the relative base as stack pointer, the way `Program::backtrace()` expects:
+//! the caller stores the return address at `[rb]` and the arguments after it, then jumps. The
+//! callee moves the relative base past its frame of arguments, locals and temporaries, so they
+//! are addressed with negative offsets. Return values are passed in a cell after the code.
+
+use crate::link::{link, Object, END_SYMBOL};
+use crate::Value;
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt::{Display, Error, Formatter};
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct CompileError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl Display for CompileError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl core::error::Error for CompileError {}
+
+/// Compiles `source` into an image for `Program::new()`
+/// ```
+/// use intcode::compiler::compile;
+/// use intcode::Program;
+///
+/// let image = compile("fn main() { output(input() * 2); }").unwrap();
+/// let mut program = Program::new(&image);
+/// program.set_input(21);
+/// assert_eq!(program.run_pipe(), Some(42));
+/// ```
+pub fn compile(source: &str) -> Result<Vec<Value>, CompileError> {
+    let object = compile_object(source)?;
+    let (image, _) = link(&[object]).expect("compiled objects link on their own");
+    Ok(image)
+}
+
+/// Compiles `source` into a relocatable object with a symbol for every function. The startup
+/// code which calls `main` comes first, so the object has to be linked in first place.
+pub fn compile_object(source: &str) -> Result<Object, CompileError> {
+    let mut parser = Parser {
+        tokens: tokenize(source)?,
+        pos: 0,
+    };
+    let functions = parser.program()?;
+    Generator::new(&functions)?.generate(&functions)
+}
+
+const KEYWORDS: [&str; 6] = ["fn", "let", "if", "else", "while", "return"];
+
+// Longer symbols first, so `<=` isn't read as `<` and `=`
+const SYMBOLS: [&str; 19] = [
+    "==", "!=", "<=", ">=", "&&", "||", "(", ")", "{", "}", ",", ";", "=", "<", ">", "+", "-", "*",
+    "!",
+];
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(Value),
+    Ident(String),
+    Symbol(&'static str),
+    End,
+}
+
+impl Display for Token {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        match self {
+            Token::Number(n) => write!(f, "{}", n),
+            Token::Ident(name) => write!(f, "{}", name),
+            Token::Symbol(symbol) => write!(f, "{}", symbol),
+            Token::End => write!(f, "end of input"),
+        }
+    }
+}
+
+fn tokenize(source: &str) -> Result<Vec<(Token, usize)>, CompileError> {
+    let mut tokens = Vec::new();
+    for (i, line) in source.lines().enumerate() {
+        let line_number = i + 1;
+        let code = line.split("//").next().unwrap();
+        let mut rest = code.trim_start();
+        while !rest.is_empty() {
+            let c = rest.chars().next().unwrap();
+            let (token, len) = if c.is_ascii_digit() {
+                let len = rest
+                    .find(|c: char| !c.is_ascii_digit())
+                    .unwrap_or(rest.len());
+                let number = rest[..len].parse().map_err(|_| CompileError {
+                    line: line_number,
+                    message: format!("number too large: {}", &rest[..len]),
+                })?;
+                (Token::Number(number), len)
+            } else if c.is_alphabetic() || c == '_' {
+                let len = rest
+                    .find(|c: char| !c.is_alphanumeric() && c != '_')
+                    .unwrap_or(rest.len());
+                (Token::Ident(rest[..len].to_string()), len)
+            } else {
+                match SYMBOLS.iter().find(|symbol| rest.starts_with(*symbol)) {
+                    Some(symbol) => (Token::Symbol(symbol), symbol.len()),
+                    None => {
+                        return Err(CompileError {
+                            line: line_number,
+                            message: format!("unexpected character `{}`", c),
+                        })
+                    }
+                }
+            };
+            tokens.push((token, line_number));
+            rest = rest[len..].trim_start();
+        }
+    }
+    let last_line = source.lines().count().max(1);
+    tokens.push((Token::End, last_line));
+    Ok(tokens)
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+    Equal,
+    NotEqual,
+    And,
+    Or,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum UnaryOp {
+    Neg,
+    Not,
+}
+
+#[derive(Debug, Clone)]
+enum Expr {
+    Number(Value),
+    Var {
+        name: String,
+        line: usize,
+    },
+    Call {
+        name: String,
+        args: Vec<Expr>,
+        line: usize,
+    },
+    Unary(UnaryOp, Box<Expr>),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>),
+}
+
+#[derive(Debug, Clone)]
+enum Stmt {
+    Let(String, Expr),
+    Assign {
+        name: String,
+        value: Expr,
+        line: usize,
+    },
+    If(Expr, Vec<Stmt>, Vec<Stmt>),
+    While(Expr, Vec<Stmt>),
+    Return(Option<Expr>),
+    Block(Vec<Stmt>),
+    Expr(Expr),
+}
+
+#[derive(Debug, Clone)]
+struct Function {
+    name: String,
+    params: Vec<String>,
+    body: Vec<Stmt>,
+    line: usize,
+}
+
+struct Parser {
+    tokens: Vec<(Token, usize)>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> &Token {
+        &self.tokens[self.pos].0
+    }
+    fn line(&self) -> usize {
+        self.tokens[self.pos].1
+    }
+    fn next(&mut self) -> Token {
+        let token = self.tokens[self.pos].0.clone();
+        if token != Token::End {
+            self.pos += 1;
+        }
+        token
+    }
+    fn error<T>(&self, message: String) -> Result<T, CompileError> {
+        Err(CompileError {
+            line: self.line(),
+            message,
+        })
+    }
+    fn unexpected<T>(&self, expected: &str) -> Result<T, CompileError> {
+        self.error(format!("expected {}, found `{}`", expected, self.peek()))
+    }
+    fn eat(&mut self, symbol: &str) -> bool {
+        let matches = match self.peek() {
+            Token::Symbol(s) => *s == symbol,
+            Token::Ident(name) => name == symbol,
+            _ => false,
+        };
+        if matches {
+            self.next();
+        }
+        matches
+    }
+    fn expect(&mut self, symbol: &str) -> Result<(), CompileError> {
+        if self.eat(symbol) {
+            Ok(())
+        } else {
+            self.unexpected(&format!("`{}`", symbol))
+        }
+    }
+    fn ident(&mut self) -> Result<String, CompileError> {
+        match self.peek() {
+            Token::Ident(name) if !KEYWORDS.contains(&name.as_str()) => {
+                let name = name.clone();
+                self.next();
+                Ok(name)
+            }
+            _ => self.unexpected("a name"),
+        }
+    }
+
+    fn program(&mut self) -> Result<Vec<Function>, CompileError> {
+        let mut functions = Vec::new();
+        while *self.peek() != Token::End {
+            let line = self.line();
+            self.expect("fn")?;
+            let name = self.ident()?;
+            self.expect("(")?;
+            let mut params = Vec::new();
+            if !self.eat(")") {
+                loop {
+                    params.push(self.ident()?);
+                    if self.eat(")") {
+                        break;
+                    }
+                    self.expect(",")?;
+                }
+            }
+            let body = self.block()?;
+            functions.push(Function {
+                name,
+                params,
+                body,
+                line,
+            });
+        }
+        Ok(functions)
+    }
+    fn block(&mut self) -> Result<Vec<Stmt>, CompileError> {
+        self.expect("{")?;
+        let mut statements = Vec::new();
+        while !self.eat("}") {
+            if *self.peek() == Token::End {
+                return self.unexpected("`}`");
+            }
+            statements.push(self.statement()?);
+        }
+        Ok(statements)
+    }
+    fn statement(&mut self) -> Result<Stmt, CompileError> {
+        let line = self.line();
+        let statement = if *self.peek() == Token::Symbol("{") {
+            return Ok(Stmt::Block(self.block()?));
+        } else if self.eat("let") {
+            let name = self.ident()?;
+            self.expect("=")?;
+            Stmt::Let(name, self.expression()?)
+        } else if self.eat("if") {
+            return self.if_statement();
+        } else if self.eat("while") {
+            let condition = self.expression()?;
+            return Ok(Stmt::While(condition, self.block()?));
+        } else if self.eat("return") {
+            if *self.peek() == Token::Symbol(";") {
+                Stmt::Return(None)
+            } else {
+                Stmt::Return(Some(self.expression()?))
+            }
+        } else if self.tokens.get(self.pos + 1).map(|t| &t.0) == Some(&Token::Symbol("=")) {
+            let name = self.ident()?;
+            self.expect("=")?;
+            let value = self.expression()?;
+            Stmt::Assign { name, value, line }
+        } else {
+            Stmt::Expr(self.expression()?)
+        };
+        self.expect(";")?;
+        Ok(statement)
+    }
+    fn if_statement(&mut self) -> Result<Stmt, CompileError> {
+        let condition = self.expression()?;
+        let then = self.block()?;
+        let otherwise = if !self.eat("else") {
+            Vec::new()
+        } else if self.eat("if") {
+            vec![self.if_statement()?]
+        } else {
+            self.block()?
+        };
+        Ok(Stmt::If(condition, then, otherwise))
+    }
+
+    fn expression(&mut self) -> Result<Expr, CompileError> {
+        self.binary(0)
+    }
+    /// Parses left associative operators of precedence `level` and higher
+    fn binary(&mut self, level: usize) -> Result<Expr, CompileError> {
+        const LEVELS: [&[(&str, BinaryOp)]; 5] = [
+            &[("||", BinaryOp::Or)],
+            &[("&&", BinaryOp::And)],
+            &[
+                ("==", BinaryOp::Equal),
+                ("!=", BinaryOp::NotEqual),
+                ("<=", BinaryOp::LessEqual),
+                (">=", BinaryOp::GreaterEqual),
+                ("<", BinaryOp::Less),
+                (">", BinaryOp::Greater),
+            ],
+            &[("+", BinaryOp::Add), ("-", BinaryOp::Sub)],
+            &[("*", BinaryOp::Mul)],
+        ];
+        if level == LEVELS.len() {
+            return self.unary();
+        }
+        let mut lhs = self.binary(level + 1)?;
+        'operators: loop {
+            for (symbol, op) in LEVELS[level] {
+                if self.eat(symbol) {
+                    let rhs = self.binary(level + 1)?;
+                    lhs = Expr::Binary(*op, Box::new(lhs), Box::new(rhs));
+                    continue 'operators;
+                }
+            }
+            return Ok(lhs);
+        }
+    }
+    fn unary(&mut self) -> Result<Expr, CompileError> {
+        if self.eat("-") {
+            Ok(Expr::Unary(UnaryOp::Neg, Box::new(self.unary()?)))
+        } else if self.eat("!") {
+            Ok(Expr::Unary(UnaryOp::Not, Box::new(self.unary()?)))
+        } else {
+            self.primary()
+        }
+    }
+    fn primary(&mut self) -> Result<Expr, CompileError> {
+        let line = self.line();
+        match self.peek().clone() {
+            Token::Number(n) => {
+                self.next();
+                Ok(Expr::Number(n))
+            }
+            Token::Symbol("(") => {
+                self.next();
+                let expr = self.expression()?;
+                self.expect(")")?;
+                Ok(expr)
+            }
+            Token::Ident(_) => {
+                let name = self.ident()?;
+                if !self.eat("(") {
+                    return Ok(Expr::Var { name, line });
+                }
+                let mut args = Vec::new();
+                if !self.eat(")") {
+                    loop {
+                        args.push(self.expression()?);
+                        if self.eat(")") {
+                            break;
+                        }
+                        self.expect(",")?;
+                    }
+                }
+                Ok(Expr::Call { name, args, line })
+            }
+            _ => self.unexpected("an expression"),
+        }
+    }
+}
+
+type Label = usize;
+type Slot = usize;
+
+/// Where an instruction parameter comes from or goes to
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum Operand {
+    Immediate(Value),
+    /// The address of a label, as immediate
+    Label(Label),
+    /// The cell at a label, in position mode
+    Global(Label),
+    /// A slot of the current frame, relative to the relative base at the end of the frame
+    Local(Slot),
+    /// An offset to the relative base, to pass arguments past the current frame
+    Relative(Value),
+    /// The size of the current frame times the given factor, as immediate
+    Frame(Value),
+}
+
+impl Operand {
+    fn mode(self) -> Value {
+        match self {
+            Operand::Immediate(_) | Operand::Label(_) | Operand::Frame(_) => 1,
+            Operand::Global(_) => 0,
+            Operand::Local(_) | Operand::Relative(_) => 2,
+        }
+    }
+}
+
+const ADD: Value = 1;
+const MUL: Value = 2;
+const INPUT: Value = 3;
+const OUTPUT: Value = 4;
+const JUMP_IF_TRUE: Value = 5;
+const JUMP_IF_FALSE: Value = 6;
+const LESS_THAN: Value = 7;
+const EQUALS: Value = 8;
+const SET_RELATIVE_BASE: Value = 9;
+const HALT: Value = 99;
+
+struct Generator {
+    // name -> (entry label, parameter count)
+    functions: BTreeMap<String, (Label, usize)>,
+    code: Vec<Value>,
+    labels: Vec<Option<usize>>,
+    label_fixups: Vec<(usize, Label)>,
+    // Resolved once the frame size of the function is known
+    frame_fixups: Vec<(usize, Operand)>,
+    scopes: Vec<Vec<(String, Slot)>>,
+    next_slot: Slot,
+    frame_size: Slot,
+    return_value: Label,
+}
+
+impl Generator {
+    fn new(functions: &[Function]) -> Result<Self, CompileError> {
+        let mut generator = Generator {
+            functions: BTreeMap::new(),
+            code: Vec::new(),
+            labels: Vec::new(),
+            label_fixups: Vec::new(),
+            frame_fixups: Vec::new(),
+            scopes: Vec::new(),
+            next_slot: 0,
+            frame_size: 0,
+            return_value: 0,
+        };
+        generator.return_value = generator.label();
+        for function in functions {
+            let error = |message: String| {
+                Err(CompileError {
+                    line: function.line,
+                    message,
+                })
+            };
+            if function.name.starts_with("__") {
+                return error(format!("`{}` is a reserved name", function.name));
+            }
+            if function.name == "input" || function.name == "output" {
+                return error(format!("`{}` is a built-in function", function.name));
+            }
+            if function.name == "main" && !function.params.is_empty() {
+                return error("`main` can't have parameters".to_string());
+            }
+            let label = generator.label();
+            let entry = (label, function.params.len());
+            if generator
+                .functions
+                .insert(function.name.clone(), entry)
+                .is_some()
+            {
+                return error(format!("function `{}` is already defined", function.name));
+            }
+        }
+        if !generator.functions.contains_key("main") {
+            return Err(CompileError {
+                line: 1,
+                message: "no `main` function".to_string(),
+            });
+        }
+        Ok(generator)
+    }
+
+    fn generate(mut self, functions: &[Function]) -> Result<Object, CompileError> {
+        // The stack starts after the linked image, main returns to a halt
+        let halt = self.label();
+        let main = self.functions["main"].0;
+        self.emit(SET_RELATIVE_BASE, &[Operand::Immediate(0)]);
+        let stack = self.code.len() - 1;
+        self.call_to(main, halt);
+        self.place(halt);
+        self.emit(HALT, &[]);
+
+        for function in functions {
+            self.function(function)?;
+        }
+
+        let return_value = self.return_value;
+        self.place(return_value);
+        self.code.push(0);
+
+        let mut object = Object::new(Vec::new());
+        for (index, label) in self.label_fixups {
+            self.code[index] = self.labels[label].expect("label was never placed") as Value;
+            object.relocate(index, None);
+        }
+        object.relocate(stack, Some(END_SYMBOL));
+        object
+            .relocations
+            .sort_by_key(|relocation| relocation.offset);
+        for function in functions {
+            let entry = self.functions[&function.name].0;
+            object.define(&function.name, self.labels[entry].unwrap());
+        }
+        object.define("__return", self.labels[return_value].unwrap());
+        object.code = self.code;
+        Ok(object)
+    }
+
+    fn label(&mut self) -> Label {
+        self.labels.push(None);
+        self.labels.len() - 1
+    }
+    fn place(&mut self, label: Label) {
+        self.labels[label] = Some(self.code.len());
+    }
+    fn emit(&mut self, opcode: Value, operands: &[Operand]) {
+        let mut instruction = opcode;
+        let mut factor = 100;
+        for operand in operands {
+            instruction += operand.mode() * factor;
+            factor *= 10;
+        }
+        self.code.push(instruction);
+        for &operand in operands {
+            let index = self.code.len();
+            match operand {
+                Operand::Immediate(value) | Operand::Relative(value) => self.code.push(value),
+                Operand::Label(label) | Operand::Global(label) => {
+                    self.label_fixups.push((index, label));
+                    self.code.push(0);
+                }
+                Operand::Local(_) | Operand::Frame(_) => {
+                    self.frame_fixups.push((index, operand));
+                    self.code.push(0);
+                }
+            }
+        }
+    }
+    /// Stores the return address and jumps, arguments must already be in place
+    fn call_to(&mut self, function: Label, return_address: Label) {
+        let zero = Operand::Immediate(0);
+        self.emit(
+            ADD,
+            &[Operand::Label(return_address), zero, Operand::Relative(0)],
+        );
+        self.emit(
+            JUMP_IF_TRUE,
+            &[Operand::Immediate(1), Operand::Label(function)],
+        );
+    }
+    fn jump(&mut self, label: Label) {
+        self.emit(
+            JUMP_IF_TRUE,
+            &[Operand::Immediate(1), Operand::Label(label)],
+        );
+    }
+    fn copy(&mut self, from: Operand, to: Operand) {
+        if from != to {
+            self.emit(ADD, &[from, Operand::Immediate(0), to]);
+        }
+    }
+
+    fn temp(&mut self) -> Operand {
+        let slot = self.next_slot;
+        self.next_slot += 1;
+        self.frame_size = self.frame_size.max(self.next_slot);
+        Operand::Local(slot)
+    }
+    fn lookup(&self, name: &str, line: usize) -> Result<Operand, CompileError> {
+        self.scopes
+            .iter()
+            .rev()
+            .flat_map(|scope| scope.iter().rev())
+            .find(|(variable, _)| variable == name)
+            .map(|&(_, slot)| Operand::Local(slot))
+            .ok_or_else(|| CompileError {
+                line,
+                message: format!("unknown variable `{}`", name),
+            })
+    }
+
+    fn function(&mut self, function: &Function) -> Result<(), CompileError> {
+        let entry = self.functions[&function.name].0;
+        self.place(entry);
+        // slot 0 holds the return address, the arguments follow
+        let params = function.params.iter().cloned().zip(1..).collect();
+        self.scopes = vec![params];
+        self.next_slot = function.params.len() + 1;
+        self.frame_size = self.next_slot;
+        self.frame_fixups.clear();
+
+        self.emit(SET_RELATIVE_BASE, &[Operand::Frame(1)]);
+        for statement in &function.body {
+            self.statement(statement)?;
+        }
+        self.ret(Operand::Immediate(0));
+
+        let frame_size = self.frame_size as Value;
+        for &(index, operand) in &self.frame_fixups {
+            self.code[index] = match operand {
+                Operand::Local(slot) => slot as Value - frame_size,
+                Operand::Frame(factor) => factor * frame_size,
+                _ => unreachable!(),
+            };
+        }
+        Ok(())
+    }
+    fn ret(&mut self, value: Operand) {
+        let return_value = self.return_value;
+        self.copy(value, Operand::Global(return_value));
+        self.emit(SET_RELATIVE_BASE, &[Operand::Frame(-1)]);
+        self.emit(
+            JUMP_IF_FALSE,
+            &[Operand::Immediate(0), Operand::Relative(0)],
+        );
+    }
+    fn block(&mut self, statements: &[Stmt]) -> Result<(), CompileError> {
+        let next_slot = self.next_slot;
+        self.scopes.push(Vec::new());
+        for statement in statements {
+            self.statement(statement)?;
+        }
+        self.scopes.pop();
+        self.next_slot = next_slot;
+        Ok(())
+    }
+    fn statement(&mut self, statement: &Stmt) -> Result<(), CompileError> {
+        // temporaries only live during their statement
+        let next_slot = self.next_slot;
+        match statement {
+            Stmt::Let(name, value) => {
+                let value = self.expression(value)?;
+                self.next_slot = next_slot;
+                let variable = self.temp();
+                self.copy(value, variable);
+                let slot = self.next_slot - 1;
+                self.scopes.last_mut().unwrap().push((name.clone(), slot));
+                return Ok(());
+            }
+            Stmt::Assign { name, value, line } => {
+                let variable = self.lookup(name, *line)?;
+                let value = self.expression(value)?;
+                self.copy(value, variable);
+            }
+            Stmt::If(condition, then, otherwise) => {
+                let otherwise_label = self.label();
+                let end = self.label();
+                let condition = self.expression(condition)?;
+                self.emit(JUMP_IF_FALSE, &[condition, Operand::Label(otherwise_label)]);
+                self.next_slot = next_slot;
+                self.block(then)?;
+                self.jump(end);
+                self.place(otherwise_label);
+                self.block(otherwise)?;
+                self.place(end);
+            }
+            Stmt::While(condition, body) => {
+                let start = self.label();
+                let end = self.label();
+                self.place(start);
+                let condition = self.expression(condition)?;
+                self.emit(JUMP_IF_FALSE, &[condition, Operand::Label(end)]);
+                self.next_slot = next_slot;
+                self.block(body)?;
+                self.jump(start);
+                self.place(end);
+            }
+            Stmt::Return(value) => {
+                let value = match value {
+                    Some(value) => self.expression(value)?,
+                    None => Operand::Immediate(0),
+                };
+                self.ret(value);
+            }
+            Stmt::Block(statements) => self.block(statements)?,
+            Stmt::Expr(expr) => {
+                self.expression(expr)?;
+            }
+        }
+        self.next_slot = next_slot;
+        Ok(())
+    }
+
+    fn expression(&mut self, expr: &Expr) -> Result<Operand, CompileError> {
+        match expr {
+            Expr::Number(n) => Ok(Operand::Immediate(*n)),
+            Expr::Var { name, line } => self.lookup(name, *line),
+            Expr::Unary(op, operand) => {
+                let operand = self.expression(operand)?;
+                let (opcode, constant) = match op {
+                    UnaryOp::Neg => (MUL, -1),
+                    UnaryOp::Not => (EQUALS, 0),
+                };
+                self.operation(opcode, operand, Operand::Immediate(constant))
+            }
+            Expr::Binary(op, lhs, rhs) => {
+                let lhs = self.expression(lhs)?;
+                let rhs = self.expression(rhs)?;
+                self.binary(*op, lhs, rhs)
+            }
+            Expr::Call { name, args, line } => self.call(name, args, *line),
+        }
+    }
+    /// Emits `opcode lhs, rhs, [result]`, folding constants
+    fn operation(
+        &mut self,
+        opcode: Value,
+        lhs: Operand,
+        rhs: Operand,
+    ) -> Result<Operand, CompileError> {
+        if let (Operand::Immediate(a), Operand::Immediate(b)) = (lhs, rhs) {
+            let value = match opcode {
+                ADD => a.checked_add(b),
+                MUL => a.checked_mul(b),
+                LESS_THAN => Some((a < b) as Value),
+                _ => Some((a == b) as Value),
+            };
+            if let Some(value) = value {
+                return Ok(Operand::Immediate(value));
+            }
+        }
+        let result = self.temp();
+        self.emit(opcode, &[lhs, rhs, result]);
+        Ok(result)
+    }
+    fn binary(
+        &mut self,
+        op: BinaryOp,
+        lhs: Operand,
+        rhs: Operand,
+    ) -> Result<Operand, CompileError> {
+        let not =
+            |generator: &mut Self, value| generator.operation(EQUALS, value, Operand::Immediate(0));
+        match op {
+            BinaryOp::Add => self.operation(ADD, lhs, rhs),
+            BinaryOp::Sub => {
+                let negated = self.operation(MUL, rhs, Operand::Immediate(-1))?;
+                self.operation(ADD, lhs, negated)
+            }
+            BinaryOp::Mul => self.operation(MUL, lhs, rhs),
+            BinaryOp::Less => self.operation(LESS_THAN, lhs, rhs),
+            BinaryOp::Greater => self.operation(LESS_THAN, rhs, lhs),
+            BinaryOp::LessEqual => {
+                let greater = self.operation(LESS_THAN, rhs, lhs)?;
+                not(self, greater)
+            }
+            BinaryOp::GreaterEqual => {
+                let less = self.operation(LESS_THAN, lhs, rhs)?;
+                not(self, less)
+            }
+            BinaryOp::Equal => self.operation(EQUALS, lhs, rhs),
+            BinaryOp::NotEqual => {
+                let equal = self.operation(EQUALS, lhs, rhs)?;
+                not(self, equal)
+            }
+            BinaryOp::And => {
+                // neither side is false
+                let lhs = not(self, lhs)?;
+                let rhs = not(self, rhs)?;
+                let false_count = self.operation(ADD, lhs, rhs)?;
+                not(self, false_count)
+            }
+            BinaryOp::Or => {
+                // not both sides are false
+                let lhs = not(self, lhs)?;
+                let rhs = not(self, rhs)?;
+                let false_count = self.operation(ADD, lhs, rhs)?;
+                self.operation(LESS_THAN, false_count, Operand::Immediate(2))
+            }
+        }
+    }
+    fn call(&mut self, name: &str, args: &[Expr], line: usize) -> Result<Operand, CompileError> {
+        let (function, params) = match name {
+            "input" => (None, 0),
+            "output" => (None, 1),
+            _ => match self.functions.get(name) {
+                Some(&(label, params)) => (Some(label), params),
+                None => {
+                    return Err(CompileError {
+                        line,
+                        message: format!("unknown function `{}`", name),
+                    })
+                }
+            },
+        };
+        if args.len() != params {
+            return Err(CompileError {
+                line,
+                message: format!(
+                    "`{}` takes {} arguments but {} were given",
+                    name,
+                    params,
+                    args.len()
+                ),
+            });
+        }
+        // Evaluate everything first, calls in the arguments would overwrite the ones in place
+        let mut values = Vec::with_capacity(args.len());
+        for arg in args {
+            values.push(self.expression(arg)?);
+        }
+
+        match function {
+            None if name == "input" => {
+                let result = self.temp();
+                self.emit(INPUT, &[result]);
+                Ok(result)
+            }
+            None => {
+                self.emit(OUTPUT, &[values[0]]);
+                Ok(Operand::Immediate(0))
+            }
+            Some(function) => {
+                for (i, value) in values.into_iter().enumerate() {
+                    self.copy(value, Operand::Relative(i as Value + 1));
+                }
+                let return_address = self.label();
+                self.call_to(function, return_address);
+                self.place(return_address);
+                let result = self.temp();
+                let return_value = self.return_value;
+                self.copy(Operand::Global(return_value), result);
+                Ok(result)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Program;
+
+    fn run(source: &str, input: &[Value]) -> Vec<Value> {
+        let image = compile(source).unwrap_or_else(|e| panic!("{}", e));
+        let mut program = Program::new(&image);
+        input.iter().for_each(|&value| program.set_input(value));
+        program.run().iter().cloned().collect()
+    }
+
+    const FIBONACCI: &str = "
+        // prints the first n fibonacci numbers
+        fn main() {
+            let n = input();
+            let i = 0;
+            while i < n {
+                output(fib(i));
+                i = i + 1;
+            }
+        }
+
+        fn fib(n) {
+            if n < 2 {
+                return n;
+            }
+            return fib(n - 1) + fib(n - 2);
+        }
+    ";
+
+    #[test]
+    fn test_recursion() {
+        assert_eq!(run(FIBONACCI, &[10]), [0, 1, 1, 2, 3, 5, 8, 13, 21, 34]);
+    }
+
+    #[test]
+    fn test_operators() {
+        let source = "
+            fn main() {
+                let a = input();
+                let b = input();
+                output(a + b);
+                output(a - b);
+                output(a * -b);
+                output(a < b);
+                output(a <= b);
+                output(a > b);
+                output(a >= b);
+                output(a == b);
+                output(a != b);
+                output(a && b);
+                output(a || 0);
+                output(!a);
+                output(2 * (3 + 4) - 1);
+            }
+        ";
+        assert_eq!(
+            run(source, &[7, 3]),
+            [10, 4, -21, 0, 0, 1, 1, 0, 1, 1, 1, 0, 13]
+        );
+        assert_eq!(run(source, &[0, 0])[9..12], [0, 0, 1]);
+    }
+
+    #[test]
+    fn test_scopes_and_control_flow() {
+        let source = "
+            // sums the input until a 0, counting odd and even values
+            fn main() {
+                let sum = 0;
+                let odd = 0;
+                let value = input();
+                while value != 0 {
+                    sum = sum + value;
+                    if is_odd(value) {
+                        let one = 1;
+                        odd = odd + one;
+                    } else if value == 2 {
+                        output(-2);
+                    }
+                    value = input();
+                }
+                output(sum);
+                output(odd);
+            }
+
+            fn is_odd(n) {
+                while n > 1 {
+                    n = n - 2;
+                }
+                return n;
+            }
+        ";
+        assert_eq!(run(source, &[1, 2, 3, 4, 5, 0]), [-2, 15, 3]);
+    }
+
+    #[test]
+    fn test_nested_calls() {
+        let source = "
+            fn main() {
+                output(sub(sub(10, 3), add3(1, sub(5, 4), 2)));
+            }
+            fn sub(a, b) { return a - b; }
+            fn add3(a, b, c) { let sum = a + b; return sum + c; }
+        ";
+        assert_eq!(run(source, &[]), [3]);
+    }
+
+    #[test]
+    fn test_tooling() {
+        let image = compile(FIBONACCI).unwrap();
+        assert_eq!(crate::lint::lint(&image), []);
+
+        // the deepest point is fib(4) calling down to fib(1), below main and the outermost frame
+        let mut program = Program::new(&image);
+        program.set_input(5);
+        program.track_calls();
+        let mut depth = 0;
+        while !program.is_halted() {
+            program.step();
+            depth = depth.max(program.backtrace().len());
+        }
+        assert_eq!(depth, 6);
+        assert_eq!(program.backtrace().len(), 1);
+    }
+
+    #[test]
+    fn test_object() {
+        let object = compile_object(FIBONACCI).unwrap();
+        let padding = Object::new(vec![42; 5]);
+        let (image, symbols) = link(&[object, padding]).unwrap();
+        assert_eq!(symbols.address(END_SYMBOL), Some(image.len()));
+
+        let mut program = Program::new(&image);
+        program.set_input(3);
+        program.track_calls();
+        while program.output_queue().is_empty() {
+            program.step();
+        }
+        let frames = program.backtrace();
+        assert_eq!(frames.len(), 2);
+        assert_eq!(Some(frames[0].function), symbols.address("main"));
+        assert!(frames[0].to_string_with(&symbols).starts_with("main+"));
+        assert_eq!(program.run().iter().collect::<Vec<_>>(), [&0, &1, &1]);
+    }
+
+    #[test]
+    fn test_errors() {
+        let error = |source: &str| compile(source).unwrap_err().to_string();
+
+        assert_eq!(error("fn f() {}"), "line 1: no `main` function");
+        assert_eq!(
+            error("fn main() {\n  x = 1;\n}"),
+            "line 2: unknown variable `x`"
+        );
+        assert_eq!(
+            error("fn main() {\n  f(1);\n}\nfn f(a, b) {}"),
+            "line 2: `f` takes 2 arguments but 1 were given"
+        );
+        assert_eq!(
+            error("fn main() {\n  let x = 1\n}"),
+            "line 3: expected `;`, found `}`"
+        );
+        assert_eq!(
+            error("fn main() { let x = 5 / 2; }"),
+            "line 1: unexpected character `/`"
+        );
+        assert_eq!(
+            error("fn main() { { let x = 1; } output(x); }"),
+            "line 1: unknown variable `x`"
+        );
+    }
+}
@@ -0,0 +1,402 @@
+//! Conformance tests: random well-formed programs checked against a reference evaluator, and the
+//! table of cases in `spec/conformance.txt`.
+//!
+//! Generated programs are straight-line code with forward jumps, followed by a halt and a data
+//! area. Every parameter points into the data area or is an immediate, so programs never modify
+//! themselves, and relative offsets are chosen for the relative base at that point, which only
+//! changes outside of skipped code. The reference evaluator runs the generated instructions
+//! directly instead of decoding the image.
+
+use crate::{Addr, OpCode, Program, Value};
+use alloc::string::{String, ToString};
+use alloc::vec;
+use alloc::vec::Vec;
+
+const CASES: u64 = 500;
+const DATA: usize = 8;
+
+/// xorshift64*, good enough to pick programs
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        Rng(seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1)
+    }
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+    fn range(&mut self, low: Value, high: Value) -> Value {
+        low + (self.next() % (high - low + 1) as u64) as Value
+    }
+    fn pick<T: Copy>(&mut self, items: &[T]) -> T {
+        items[self.below(items.len())]
+    }
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum Kind {
+    Add,
+    Mul,
+    LessThan,
+    Equals,
+    Input,
+    Output,
+    JumpIfTrue,
+    JumpIfFalse,
+    SetRelativeBase,
+}
+
+impl Kind {
+    fn opcode(self) -> Value {
+        match self {
+            Kind::Add => 1,
+            Kind::Mul => 2,
+            Kind::Input => 3,
+            Kind::Output => 4,
+            Kind::JumpIfTrue => 5,
+            Kind::JumpIfFalse => 6,
+            Kind::LessThan => 7,
+            Kind::Equals => 8,
+            Kind::SetRelativeBase => 9,
+        }
+    }
+    fn parameters(self) -> usize {
+        match self {
+            Kind::Input | Kind::Output | Kind::SetRelativeBase => 1,
+            Kind::JumpIfTrue | Kind::JumpIfFalse => 2,
+            _ => 3,
+        }
+    }
+}
+
+/// A generated instruction, parameters are mode digit and value
+#[derive(Debug, Clone)]
+struct Op {
+    address: Addr,
+    kind: Kind,
+    parameters: Vec<(Value, Value)>,
+}
+
+#[derive(Debug, Clone)]
+struct Generated {
+    image: Vec<Value>,
+    ops: Vec<Op>,
+    input: Vec<Value>,
+}
+
+fn generate(rng: &mut Rng) -> Generated {
+    use Kind::*;
+
+    // Pick the instructions first, their lengths decide where the data area starts
+    let count = 1 + rng.below(12);
+    let mut kinds: Vec<(Kind, usize)> = Vec::new();
+    let mut skipped_until = 0;
+    for i in 0..count {
+        let mut choices = vec![
+            Add,
+            Mul,
+            LessThan,
+            Equals,
+            Input,
+            Output,
+            JumpIfTrue,
+            JumpIfFalse,
+        ];
+        if i >= skipped_until {
+            choices.push(SetRelativeBase);
+        }
+        let kind = rng.pick(&choices);
+        let skip = match kind {
+            JumpIfTrue | JumpIfFalse => rng.below(4).min(count - i - 1),
+            _ => 0,
+        };
+        skipped_until = skipped_until.max(i + 1 + skip);
+        kinds.push((kind, skip));
+    }
+    let mut addresses = vec![0];
+    for (kind, _) in &kinds {
+        addresses.push(addresses.last().unwrap() + 1 + kind.parameters());
+    }
+    let halt = *addresses.last().unwrap();
+    let data = halt + 1;
+
+    let mut relative_base = 0;
+    let mut ops = Vec::new();
+    let mut input = Vec::new();
+    for (i, &(kind, skip)) in kinds.iter().enumerate() {
+        let cell = |rng: &mut Rng| (data + rng.below(DATA)) as Value;
+        let read = |rng: &mut Rng| match rng.below(3) {
+            0 => (0, cell(rng)),
+            1 => (1, rng.range(-50, 50)),
+            _ => (2, cell(rng) - relative_base),
+        };
+        let parameters = match kind {
+            Add | Mul | LessThan | Equals => {
+                let write = match rng.below(2) {
+                    0 => (0, cell(rng)),
+                    _ => (2, cell(rng) - relative_base),
+                };
+                vec![read(rng), read(rng), write]
+            }
+            Input => {
+                input.push(rng.range(-1000, 1000));
+                match rng.below(2) {
+                    0 => vec![(0, cell(rng))],
+                    _ => vec![(2, cell(rng) - relative_base)],
+                }
+            }
+            Output => vec![read(rng)],
+            JumpIfTrue | JumpIfFalse => vec![read(rng), (1, addresses[i + 1 + skip] as Value)],
+            SetRelativeBase => {
+                let new = rng.range(0, (data + DATA) as Value);
+                let offset = new - relative_base;
+                relative_base = new;
+                vec![(1, offset)]
+            }
+        };
+        ops.push(Op {
+            address: addresses[i],
+            kind,
+            parameters,
+        });
+    }
+
+    let mut image = Vec::new();
+    for op in &ops {
+        let modes = op
+            .parameters
+            .iter()
+            .rev()
+            .fold(0, |modes, (mode, _)| modes * 10 + mode);
+        image.push(modes * 100 + op.kind.opcode());
+        image.extend(op.parameters.iter().map(|&(_, value)| value));
+    }
+    image.push(99);
+    image.extend((0..DATA).map(|_| rng.range(-50, 50)));
+    Generated { image, ops, input }
+}
+
+/// The addresses the parameters of an executed instruction refer to, immediates refer to
+/// themselves
+#[derive(Debug, Clone, Eq, PartialEq)]
+struct Step {
+    address: Addr,
+    parameters: Vec<Addr>,
+}
+
+#[derive(Debug, Clone, Default)]
+struct Trace {
+    steps: Vec<Step>,
+    output: Vec<Value>,
+    memory: Vec<Value>,
+    relative_base: Value,
+}
+
+/// Runs the generated instructions, None if the arithmetic overflows
+fn evaluate(generated: &Generated) -> Option<Trace> {
+    let mut trace = Trace {
+        memory: generated.image.clone(),
+        ..Trace::default()
+    };
+    let mut input = generated.input.iter();
+    let mut next = 0;
+    while let Some(op) = generated.ops.get(next) {
+        let addresses: Vec<Addr> = op
+            .parameters
+            .iter()
+            .enumerate()
+            .map(|(i, &(mode, value))| match mode {
+                0 => value as Addr,
+                1 => op.address + 1 + i,
+                _ => (trace.relative_base + value) as Addr,
+            })
+            .collect();
+        let read = |i: usize| trace.memory[addresses[i]];
+        next += 1;
+        let write = match op.kind {
+            Kind::Add => Some(read(0).checked_add(read(1))?),
+            Kind::Mul => Some(read(0).checked_mul(read(1))?),
+            Kind::LessThan => Some((read(0) < read(1)) as Value),
+            Kind::Equals => Some((read(0) == read(1)) as Value),
+            Kind::Input => Some(*input.next().unwrap()),
+            Kind::Output => {
+                trace.output.push(read(0));
+                None
+            }
+            Kind::JumpIfTrue | Kind::JumpIfFalse => {
+                if (read(0) != 0) == (op.kind == Kind::JumpIfTrue) {
+                    let target = read(1) as Addr;
+                    next = generated
+                        .ops
+                        .iter()
+                        .position(|op| op.address == target)
+                        .unwrap_or(generated.ops.len());
+                }
+                None
+            }
+            Kind::SetRelativeBase => {
+                trace.relative_base += read(0);
+                None
+            }
+        };
+        if let Some(value) = write {
+            trace.memory[*addresses.last().unwrap()] = value;
+        }
+        trace.steps.push(Step {
+            address: op.address,
+            parameters: addresses,
+        });
+    }
+    Some(trace)
+}
+
+/// Checks `check` on generated programs which don't overflow, with the reference trace
+fn for_programs<F: FnMut(&Generated, &Trace)>(mut check: F) {
+    let mut checked = 0;
+    for seed in 0..CASES {
+        let generated = generate(&mut Rng::new(seed));
+        if let Some(trace) = evaluate(&generated) {
+            check(&generated, &trace);
+            checked += 1;
+        }
+    }
+    assert!(checked > CASES / 2);
+}
+
+fn program(generated: &Generated) -> Program {
+    let mut program = Program::new(&generated.image);
+    for &value in &generated.input {
+        program.set_input(value);
+    }
+    program
+}
+
+#[test]
+fn test_arithmetic_matches_reference() {
+    for_programs(|generated, trace| {
+        let mut p = program(generated);
+        let output: Vec<_> = p.run().iter().cloned().collect();
+        assert_eq!(output, trace.output, "{:?}", generated.image);
+        assert_eq!(p.dump_memory(), trace.memory, "{:?}", generated.image);
+        assert_eq!(p.elapsed(), trace.steps.len(), "{:?}", generated.image);
+        assert_eq!(p.relative_base() as Value, trace.relative_base);
+    });
+}
+
+#[test]
+fn test_comparisons_write_booleans() {
+    let mut comparisons = 0;
+    for_programs(|generated, _| {
+        let mut p = program(generated);
+        while !p.is_halted() {
+            let opcode = p.current_instruction().opcode;
+            let target = p.write_target();
+            p.step();
+            if opcode == OpCode::LessThan || opcode == OpCode::Equals {
+                let value = p.peek(target.unwrap());
+                assert!(value == 0 || value == 1, "{:?}", generated.image);
+                comparisons += 1;
+            }
+        }
+    });
+    assert!(comparisons > 0);
+}
+
+#[test]
+fn test_relative_addressing() {
+    for_programs(|generated, trace| {
+        let mut p = program(generated);
+        for step in &trace.steps {
+            let instruction = p.current_instruction();
+            let addresses: Vec<_> = (1..instruction.opcode.len())
+                .map(|i| p.parameter_address(i, instruction.parameter_modes[i - 1]))
+                .collect();
+            let found = Step {
+                address: p.instruction_ptr(),
+                parameters: addresses,
+            };
+            assert_eq!(&found, step, "{:?}", generated.image);
+            if let Some(target) = p.write_target() {
+                assert_eq!(Some(&target), step.parameters.last());
+            }
+            p.step();
+        }
+        assert!(p.is_halted());
+    });
+}
+
+/// A case of the spec file, see there for the format
+#[derive(Debug, Default)]
+struct Case {
+    name: String,
+    image: Vec<Value>,
+    input: Vec<Value>,
+    output: Option<Vec<Value>>,
+    memory: Option<Vec<Value>>,
+}
+
+fn parse_spec(text: &str) -> Vec<Case> {
+    let mut cases: Vec<Case> = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut parts = line.splitn(2, ':');
+        let key = parts.next().unwrap().trim();
+        let value = parts
+            .next()
+            .unwrap_or_else(|| panic!("line {}: expected `key: value`", i + 1))
+            .trim();
+        let values =
+            || crate::parse_image(value).unwrap_or_else(|e| panic!("line {}: {}", i + 1, e));
+        if key == "case" {
+            cases.push(Case {
+                name: value.to_string(),
+                ..Case::default()
+            });
+            continue;
+        }
+        let case = cases
+            .last_mut()
+            .unwrap_or_else(|| panic!("line {}: `{}` outside of a case", i + 1, key));
+        match key {
+            "image" => case.image = values(),
+            "input" => case.input = values(),
+            "output" => case.output = Some(values()),
+            "memory" => case.memory = Some(values()),
+            _ => panic!("line {}: unknown key `{}`", i + 1, key),
+        }
+    }
+    cases
+}
+
+#[test]
+fn test_spec() {
+    let cases = parse_spec(include_str!("../spec/conformance.txt"));
+    assert!(cases.len() >= 20);
+    for case in cases {
+        let mut p = Program::new(&case.image);
+        for &value in &case.input {
+            p.set_input(value);
+        }
+        let output: Vec<_> = p.run().iter().cloned().collect();
+        if let Some(expected) = case.output {
+            assert_eq!(output, expected, "{}", case.name);
+        }
+        if let Some(expected) = case.memory {
+            assert_eq!(
+                p.dump_memory()[..expected.len()],
+                expected[..],
+                "{}",
+                case.name
+            );
+        }
+    }
+}
@@ -0,0 +1,364 @@
+//! Machine state comparison and detection of endless loops.
+//!
+//! Two programs are equal when their memory, instruction pointer, relative base and I/O queues
+//! are; how they got there, like the number of steps taken, doesn't matter. A machine which
+//! reaches a state it was in before without doing any I/O in between will repeat the same states
+//! forever, so once `Program::detect_loops()` is enabled such loops are found with Brent's
+//! algorithm while the program runs. Accessing a memory-mapped device counts as I/O.
+
+use crate::{Addr, Program, Value};
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::hash::{Hash, Hasher};
+
+/// An endless loop without I/O: from step `entry` on the machine repeats the same `length`
+/// states
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Cycle {
+    pub entry: usize,
+    pub length: usize,
+}
+
+/// FNV-1a, the hash maps' hasher isn't available without std
+struct Fnv(u64);
+
+impl Default for Fnv {
+    fn default() -> Self {
+        Fnv(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl Hasher for Fnv {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 = (self.0 ^ byte as u64).wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+}
+
+/// Hash of a single memory cell, combined with xor so cells can be updated one at a time.
+/// Cells holding 0 don't count, they read the same as unset ones.
+fn cell_hash(address: Addr, value: Value) -> u64 {
+    if value == 0 {
+        return 0;
+    }
+    let mut hasher = Fnv::default();
+    (address, value).hash(&mut hasher);
+    hasher.finish()
+}
+
+fn memory_hash(memory: &BTreeMap<Addr, Value>) -> u64 {
+    memory.iter().fold(0, |hash, (&address, &value)| {
+        hash ^ cell_hash(address, value)
+    })
+}
+
+impl PartialEq for Program {
+    fn eq(&self, other: &Self) -> bool {
+        let contains = |a: &Program, b: &Program| {
+            a.memory
+                .iter()
+                .all(|(&address, &value)| b.memory.get(&address).cloned().unwrap_or(0) == value)
+        };
+        self.instruction_ptr == other.instruction_ptr
+            && self.relative_base == other.relative_base
+            && self.input == other.input
+            && self.output == other.output
+            && contains(self, other)
+            && contains(other, self)
+    }
+}
+
+impl Eq for Program {}
+
+impl Hash for Program {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.instruction_ptr.hash(state);
+        self.relative_base.hash(state);
+        self.input.hash(state);
+        self.output.hash(state);
+        memory_hash(&self.memory).hash(state);
+    }
+}
+
+/// Most state hashes kept while looking for a loop through custom instructions
+const MAX_TRACE: usize = 1 << 20;
+
+/// What the entry of a cycle is found from
+#[derive(Debug, Clone)]
+enum History {
+    /// The state after the last I/O, run again in two copies
+    Start(Box<Program>),
+    /// Hashes of the states since the last I/O. Custom instructions may have side effects which
+    /// mustn't happen twice, so their programs aren't run again. At `MAX_TRACE` hashes the
+    /// search starts over from the current state.
+    Trace(Vec<u64>),
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct LoopDetector {
+    /// Kept up to date on every write, so states can be told apart without comparing memory
+    memory_hash: u64,
+    /// Step of the state after the last I/O, where the search started
+    start: usize,
+    history: History,
+    /// Brent's tortoise: the state saved at the last power of two steps since `start`
+    tortoise: Box<Program>,
+    tortoise_hash: u64,
+    power: usize,
+    length: usize,
+    cycle: Option<Cycle>,
+}
+
+impl LoopDetector {
+    fn new(program: &Program) -> Self {
+        let mut loops = LoopDetector {
+            memory_hash: memory_hash(&program.memory),
+            start: program.elapsed,
+            history: History::Trace(Vec::new()),
+            tortoise: Box::new(program.state()),
+            tortoise_hash: 0,
+            power: 1,
+            length: 1,
+            cycle: None,
+        };
+        loops.restart(program);
+        loops
+    }
+    fn restart(&mut self, program: &Program) {
+        self.tortoise_hash = self.state_hash(program);
+        self.start = program.elapsed;
+        self.history = if program.extensions.is_empty() {
+            History::Start(Box::new(program.state()))
+        } else {
+            History::Trace(alloc::vec![self.tortoise_hash])
+        };
+        *self.tortoise = program.state();
+        self.power = 1;
+        self.length = 1;
+    }
+    /// Hash of the parts of the state which change without I/O
+    fn state_hash(&self, program: &Program) -> u64 {
+        let mut hasher = Fnv::default();
+        (
+            program.instruction_ptr,
+            program.relative_base,
+            self.memory_hash,
+        )
+            .hash(&mut hasher);
+        hasher.finish()
+    }
+    pub(crate) fn write(&mut self, address: Addr, old: Value, new: Value) {
+        self.memory_hash ^= cell_hash(address, old) ^ cell_hash(address, new);
+    }
+    /// Checks the state after a step without I/O
+    fn observe(&mut self, program: &Program) {
+        let hash = self.state_hash(program);
+        if let History::Trace(trace) = &mut self.history {
+            if trace.len() == MAX_TRACE {
+                self.restart(program);
+                return;
+            }
+            trace.push(hash);
+        }
+        if hash == self.tortoise_hash && *self.tortoise == *program {
+            self.cycle = Some(self.find_entry());
+        } else if self.power == self.length {
+            *self.tortoise = program.state();
+            self.tortoise_hash = hash;
+            self.power *= 2;
+            self.length = 1;
+        } else {
+            self.length += 1;
+        }
+    }
+    /// Finds the first state of the cycle, the first one which comes back `length` steps later
+    fn find_entry(&self) -> Cycle {
+        let entry = match &self.history {
+            History::Start(start) => {
+                let mut tortoise = start.state();
+                let mut hare = start.state();
+                for _ in 0..self.length {
+                    hare.step();
+                }
+                while tortoise != hare {
+                    tortoise.step();
+                    hare.step();
+                }
+                tortoise.elapsed
+            }
+            // without I/O the hash covers the whole state
+            History::Trace(trace) => {
+                let index = (0..trace.len() - self.length)
+                    .find(|&i| trace[i] == trace[i + self.length])
+                    .unwrap();
+                self.start + index
+            }
+        };
+        Cycle {
+            entry,
+            length: self.length,
+        }
+    }
+}
+
+impl Program {
+    /// Start watching for endless loops. Once the program is found looping without I/O,
+    /// `step()` stops executing and returns false like for a halt, so `run()` and `run_pipe()`
+    /// return instead of hanging. Check `stuck()` to tell it apart from a halt.
+    pub fn detect_loops(&mut self) {
+        if self.loops.is_none() {
+            self.loops = Some(LoopDetector::new(self));
+        }
+    }
+    /// The loop the program is stuck in, if loop detection found one
+    pub fn stuck(&self) -> Option<Cycle> {
+        self.loops.as_ref().and_then(|loops| loops.cycle)
+    }
+    /// Restarts loop detection after the state was changed from outside
+    pub(crate) fn reset_loop_detection(&mut self) {
+        if self.loops.is_some() {
+            self.loops = None;
+            self.detect_loops();
+        }
+    }
+    /// Called after a step with the lengths of the I/O queues before it
+    pub(crate) fn track_loops(&mut self, queues: (usize, usize)) {
+        let mut loops = self.loops.take().unwrap();
+        // devices can change without I/O, so they count as I/O
+        let devices = self.devices.take_accessed();
+        if queues == (self.input.len(), self.output.len()) && !devices {
+            loops.observe(self);
+        } else {
+            loops.restart(self);
+        }
+        self.loops = Some(loops);
+    }
+    /// A copy of the machine state without any of the tracking. It has no custom instructions,
+    /// so it is only run when the program has none either.
+    fn state(&self) -> Program {
+        Program {
+            image: self.image.clone(),
+            memory: self.memory.clone(),
+            instruction_ptr: self.instruction_ptr,
+            relative_base: self.relative_base,
+            input: self.input.clone(),
+            output: self.output.clone(),
+            elapsed: self.elapsed,
+            patches: Vec::new(),
+            self_modifying: Default::default(),
+            extensions: Default::default(),
+            recording: None,
+            call_stack: None,
+            loops: None,
+            devices: Default::default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hash(program: &Program) -> u64 {
+        let mut hasher = Fnv::default();
+        program.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    #[test]
+    fn test_state_equality() {
+        let image = [1101, 2, 3, 5, 99, 0];
+        let mut a = Program::new(&image);
+        let mut b = Program::new(&image);
+        b.poke(5, 0);
+        b.poke(100, 0);
+        assert_eq!(a, b);
+        assert_eq!(hash(&a), hash(&b));
+
+        a.step();
+        assert_ne!(a, b);
+        b.set_instruction_ptr(4);
+        b.poke(5, 5);
+        assert_eq!(a, b);
+        assert_eq!(hash(&a), hash(&b));
+
+        b.set_input(1);
+        assert_ne!(a, b);
+    }
+
+    #[test]
+    fn test_detect_loop() {
+        // reads a value, then counts [21] from 0 to 3 over and over
+        let image = [
+            3, 20, // input
+            1001, 21, 1, 21, // 2: increment
+            8, 21, 23, 22, // is it 3
+            1006, 22, 2, // no, again
+            1101, 0, 0, 21, // yes, reset
+            1105, 1, 2, //
+            0, 0, 0, 3,
+        ];
+        let mut p = Program::new(&image);
+        p.detect_loops();
+        p.set_input(7);
+        p.run();
+
+        // the state at step 3, right after the first increment, is the first to come back
+        assert_eq!(
+            p.stuck(),
+            Some(Cycle {
+                entry: 3,
+                length: 11
+            })
+        );
+        assert!(!p.is_halted());
+        assert!(!p.step());
+    }
+
+    #[test]
+    fn test_custom_opcode_in_loop() {
+        use core::sync::atomic::{AtomicUsize, Ordering};
+        // counts the custom instruction forever
+        let count = alloc::sync::Arc::new(AtomicUsize::new(0));
+        let counter = count.clone();
+        let mut p = Program::new(&[42, 1105, 1, 0]);
+        p.register_opcode(42, "count", 0, move |_| {
+            counter.fetch_add(1, Ordering::Relaxed);
+        });
+        p.detect_loops();
+        p.run();
+
+        assert_eq!(
+            p.stuck(),
+            Some(Cycle {
+                entry: 0,
+                length: 2
+            })
+        );
+        // finding the entry doesn't execute the instruction again
+        assert_eq!(count.load(Ordering::Relaxed), p.elapsed().div_ceil(2));
+    }
+
+    #[test]
+    fn test_loops_with_io() {
+        // outputs 1 forever
+        let mut p = Program::new(&[104, 1, 1105, 1, 0]);
+        p.detect_loops();
+        for _ in 0..1000 {
+            assert!(p.step());
+        }
+        assert_eq!(p.stuck(), None);
+
+        let mut p = Program::new(&[3, 0, 99]);
+        p.detect_loops();
+        p.set_input(1);
+        p.run();
+        assert!(p.is_halted());
+        assert_eq!(p.stuck(), None);
+    }
+}
@@ -0,0 +1,911 @@
+//! A Debug Adapter Protocol server, so editors can debug intcode programs.
+//!
+//! intcode has no source lines, breakpoints are set on instructions from the disassembly view.
+//! Memory references are intcode addresses, `readMemory` exposes every address as 8 little
+//! endian bytes. Input is queued with `input <values>` in the debug console, which also
+//! evaluates `[address]` to the value stored there.
+
+use crate::json::Json;
+use crate::{parse_image, Program, Value};
+use std::collections::HashSet;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::ops::Range;
+use std::sync::mpsc::{self, TryRecvError};
+use std::thread;
+
+const CELL_SIZE: usize = 8;
+// Instructions executed between checks for new requests while running
+const CHUNK_SIZE: usize = 10_000;
+// Larger reads and disassemblies are rejected, they would only exhaust memory
+const MAX_READ: usize = 1 << 20;
+const MAX_INSTRUCTIONS: usize = 10_000;
+
+const REGISTERS: i64 = 1;
+const INPUT: i64 = 2;
+const OUTPUT: i64 = 3;
+// Locals of the stack frame with id `n` are `LOCALS + n - 1`
+const LOCALS: i64 = 100;
+
+enum Step {
+    Continue,
+    Stop(Json),
+    Exited,
+}
+
+pub struct DapServer<W: Write> {
+    writer: W,
+    seq: i64,
+    program: Option<Program>,
+    breakpoints: HashSet<usize>,
+    stop_on_entry: bool,
+    running: bool,
+    // continuing from a breakpoint must not stop on it again
+    resuming: bool,
+}
+
+impl<W: Write> DapServer<W> {
+    pub fn new(writer: W) -> Self {
+        DapServer {
+            writer,
+            seq: 0,
+            program: None,
+            breakpoints: HashSet::new(),
+            stop_on_entry: false,
+            running: false,
+            resuming: false,
+        }
+    }
+    pub fn program(&self) -> Option<&Program> {
+        self.program.as_ref()
+    }
+
+    /// Serves requests from `reader` until the client disconnects.
+    /// Requests are read on a separate thread, so a running program can be paused.
+    pub fn serve<R: BufRead + Send + 'static>(&mut self, mut reader: R) -> io::Result<()> {
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            while let Ok(Some(message)) = read_message(&mut reader) {
+                if sender.send(message).is_err() {
+                    break;
+                }
+            }
+        });
+
+        loop {
+            let message = if self.running {
+                match receiver.try_recv() {
+                    Ok(message) => Some(message),
+                    Err(TryRecvError::Empty) => None,
+                    Err(TryRecvError::Disconnected) => return Ok(()),
+                }
+            } else {
+                match receiver.recv() {
+                    Ok(message) => Some(message),
+                    Err(_) => return Ok(()),
+                }
+            };
+            match message {
+                Some(message) => {
+                    if !self.handle(&message)? {
+                        return Ok(());
+                    }
+                }
+                None => self.run_chunk(CHUNK_SIZE)?,
+            }
+        }
+    }
+
+    /// Handles a single request, returns false once the client disconnected
+    pub fn handle(&mut self, request: &Json) -> io::Result<bool> {
+        let command = request.get("command").as_str().unwrap_or_default();
+        let args = request.get("arguments");
+        let mut after = Vec::new();
+        let mut step = false;
+
+        let body = match command {
+            "initialize" => {
+                after.push(("initialized", Json::Null));
+                Ok(Json::object(vec![
+                    ("supportsConfigurationDoneRequest", true.into()),
+                    ("supportsDisassembleRequest", true.into()),
+                    ("supportsInstructionBreakpoints", true.into()),
+                    ("supportsReadMemoryRequest", true.into()),
+                    ("supportsWriteMemoryRequest", true.into()),
+                    ("supportsSteppingGranularity", true.into()),
+                    ("supportsTerminateRequest", true.into()),
+                ]))
+            }
+            "launch" => self.launch(args),
+            "setBreakpoints" => {
+                let breakpoints = args
+                    .get("breakpoints")
+                    .as_array()
+                    .iter()
+                    .map(|_| {
+                        Json::object(vec![
+                            ("verified", false.into()),
+                            (
+                                "message",
+                                "intcode has no source lines, use instruction breakpoints".into(),
+                            ),
+                        ])
+                    })
+                    .collect();
+                Ok(Json::object(vec![(
+                    "breakpoints",
+                    Json::Array(breakpoints),
+                )]))
+            }
+            "setExceptionBreakpoints" => {
+                Ok(Json::object(vec![("breakpoints", Json::Array(Vec::new()))]))
+            }
+            "setInstructionBreakpoints" => Ok(self.set_instruction_breakpoints(args)),
+            "configurationDone" => {
+                if self.stop_on_entry {
+                    after.push(("stopped", stopped("entry", None)));
+                } else {
+                    self.running = true;
+                }
+                Ok(Json::Null)
+            }
+            "threads" => Ok(Json::object(vec![(
+                "threads",
+                Json::Array(vec![Json::object(vec![
+                    ("id", 1i64.into()),
+                    ("name", "intcode".into()),
+                ])]),
+            )])),
+            "stackTrace" => self.with_program(stack_trace),
+            "scopes" => Ok(scopes(args.get("frameId").as_i64())),
+            "variables" => self.with_program(|program| {
+                variables(program, args.get("variablesReference").as_i64())
+            }),
+            "continue" => {
+                self.running = true;
+                self.resuming = true;
+                Ok(Json::object(vec![("allThreadsContinued", true.into())]))
+            }
+            "next" | "stepIn" | "stepOut" => {
+                self.running = false;
+                step = true;
+                Ok(Json::Null)
+            }
+            "pause" => {
+                self.running = false;
+                after.push(("stopped", stopped("pause", None)));
+                Ok(Json::Null)
+            }
+            "readMemory" => self.with_program(|program| read_memory(program, args)),
+            "writeMemory" => self.write_memory(args),
+            "disassemble" => self.with_program(|program| disassemble(program, args)),
+            "evaluate" => self.evaluate(args),
+            "disconnect" | "terminate" => {
+                self.respond(request, Ok(Json::Null))?;
+                return Ok(false);
+            }
+            _ => Err(format!("Unsupported request: {}", command)),
+        };
+
+        self.respond(request, body)?;
+        for (event, body) in after {
+            self.event(event, body)?;
+        }
+        if step {
+            match self.step_once(true)? {
+                Step::Continue => self.event("stopped", stopped("step", None))?,
+                Step::Stop(body) => self.event("stopped", body)?,
+                Step::Exited => {}
+            }
+        }
+        Ok(true)
+    }
+
+    /// Runs at most `steps` instructions, sending events for output and stops
+    pub fn run_chunk(&mut self, steps: usize) -> io::Result<()> {
+        for _ in 0..steps {
+            let resuming = self.resuming;
+            self.resuming = false;
+            match self.step_once(resuming)? {
+                Step::Continue => {}
+                Step::Stop(body) => {
+                    self.running = false;
+                    return self.event("stopped", body);
+                }
+                Step::Exited => {
+                    self.running = false;
+                    return Ok(());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Executes one instruction, unless execution has to stop before it.
+    // Breakpoints are ignored for the first instruction, so we can continue from them.
+    fn step_once(&mut self, first: bool) -> io::Result<Step> {
+        let program = match self.program.as_mut() {
+            Some(program) => program,
+            None => {
+                return Ok(Step::Stop(stopped(
+                    "exception",
+                    Some("No program launched"),
+                )))
+            }
+        };
+        if !first && self.breakpoints.contains(&program.instruction_ptr()) {
+            return Ok(Step::Stop(stopped("instruction breakpoint", None)));
+        }
+        if program.is_halted() {
+            self.event("exited", Json::object(vec![("exitCode", 0i64.into())]))?;
+            self.event("terminated", Json::Null)?;
+            return Ok(Step::Exited);
+        }
+        if program.needs_input() {
+            return Ok(Step::Stop(stopped(
+                "input",
+                Some("Waiting for input, queue some with `input <values>` in the debug console"),
+            )));
+        }
+        if let Err(message) = program.try_step() {
+            return Ok(Step::Stop(stopped("exception", Some(&message))));
+        }
+        for value in program.take_output() {
+            let body = Json::object(vec![
+                ("category", "stdout".into()),
+                ("output", format!("{}\n", value).into()),
+            ]);
+            self.event("output", body)?;
+        }
+        Ok(Step::Continue)
+    }
+
+    fn launch(&mut self, args: &Json) -> Result<Json, String> {
+        let image = match (args.get("program").as_str(), args.get("image").as_str()) {
+            (Some(path), _) => {
+                let text = fs::read_to_string(path).map_err(|e| e.to_string())?;
+                parse_image(&text).map_err(|e| e.to_string())?
+            }
+            (None, Some(text)) => parse_image(text).map_err(|e| e.to_string())?,
+            (None, None) => return Err("Either `program` or `image` is required".to_string()),
+        };
+        let mut program = Program::new(&image);
+        program.track_calls();
+        for input in args.get("input").as_array() {
+            program.set_input(input.as_i64().ok_or("`input` must be integers")?);
+        }
+        self.program = Some(program);
+        self.stop_on_entry = args.get("stopOnEntry").as_bool().unwrap_or(false);
+        Ok(Json::Null)
+    }
+
+    fn set_instruction_breakpoints(&mut self, args: &Json) -> Json {
+        self.breakpoints.clear();
+        let breakpoints = args
+            .get("breakpoints")
+            .as_array()
+            .iter()
+            .map(|breakpoint| {
+                let reference = breakpoint.get("instructionReference").as_str();
+                let offset = breakpoint.get("offset").as_i64().unwrap_or(0);
+                let address = reference
+                    .and_then(|r| r.parse::<i64>().ok())
+                    .and_then(|address| address.checked_add(offset));
+                match address {
+                    Some(address) if address >= 0 => {
+                        let address = address as usize;
+                        self.breakpoints.insert(address);
+                        Json::object(vec![
+                            ("verified", true.into()),
+                            ("instructionReference", address.to_string().into()),
+                        ])
+                    }
+                    _ => Json::object(vec![
+                        ("verified", false.into()),
+                        ("message", "Invalid instruction reference".into()),
+                    ]),
+                }
+            })
+            .collect();
+        Json::object(vec![("breakpoints", Json::Array(breakpoints))])
+    }
+
+    fn write_memory(&mut self, args: &Json) -> Result<Json, String> {
+        let program = self.program.as_mut().ok_or("No program launched")?;
+        let bytes = args
+            .get("data")
+            .as_str()
+            .and_then(decode_base64)
+            .ok_or("Invalid base64 data")?;
+        let range = memory_range(args, bytes.len())?;
+
+        for (byte, value) in range.zip(&bytes) {
+            let address = byte / CELL_SIZE;
+            let mut cell = program.peek(address).to_le_bytes();
+            cell[byte % CELL_SIZE] = *value;
+            program.poke(address, Value::from_le_bytes(cell));
+        }
+        Ok(Json::object(vec![("bytesWritten", bytes.len().into())]))
+    }
+
+    fn evaluate(&mut self, args: &Json) -> Result<Json, String> {
+        let program = self.program.as_mut().ok_or("No program launched")?;
+        let expression = args.get("expression").as_str().unwrap_or_default().trim();
+
+        let result = if let Some(values) = expression.strip_prefix("input") {
+            let values = parse_image(&values.replace(' ', ",")).map_err(|e| e.to_string())?;
+            for value in &values {
+                program.set_input(*value);
+            }
+            format!("queued {} input value(s)", values.len())
+        } else {
+            let address = expression.trim_start_matches('[').trim_end_matches(']');
+            let address: usize = address
+                .parse()
+                .map_err(|_| format!("Can't evaluate `{}`", expression))?;
+            program.peek(address).to_string()
+        };
+        Ok(Json::object(vec![
+            ("result", result.into()),
+            ("variablesReference", 0i64.into()),
+        ]))
+    }
+
+    fn with_program<F>(&self, f: F) -> Result<Json, String>
+    where
+        F: FnOnce(&Program) -> Result<Json, String>,
+    {
+        f(self.program.as_ref().ok_or("No program launched")?)
+    }
+
+    fn respond(&mut self, request: &Json, body: Result<Json, String>) -> io::Result<()> {
+        let mut fields = vec![
+            ("type", "response".into()),
+            ("request_seq", request.get("seq").clone()),
+            ("command", request.get("command").clone()),
+        ];
+        match body {
+            Ok(body) => {
+                fields.push(("success", true.into()));
+                if body != Json::Null {
+                    fields.push(("body", body));
+                }
+            }
+            Err(message) => {
+                fields.push(("success", false.into()));
+                fields.push(("message", message.into()));
+            }
+        }
+        self.send(fields)
+    }
+
+    fn event(&mut self, event: &str, body: Json) -> io::Result<()> {
+        let mut fields = vec![("type", "event".into()), ("event", event.into())];
+        if body != Json::Null {
+            fields.push(("body", body));
+        }
+        self.send(fields)
+    }
+
+    fn send(&mut self, mut fields: Vec<(&str, Json)>) -> io::Result<()> {
+        self.seq += 1;
+        fields.push(("seq", self.seq.into()));
+        let message = Json::object(fields).to_string();
+        write!(
+            self.writer,
+            "Content-Length: {}\r\n\r\n{}",
+            message.len(),
+            message
+        )?;
+        self.writer.flush()
+    }
+}
+
+fn stopped(reason: &str, description: Option<&str>) -> Json {
+    let mut fields = vec![
+        ("reason", reason.into()),
+        ("threadId", 1i64.into()),
+        ("allThreadsStopped", true.into()),
+    ];
+    if let Some(description) = description {
+        fields.push(("description", description.into()));
+    }
+    Json::object(fields)
+}
+
+fn stack_trace(program: &Program) -> Result<Json, String> {
+    let frames: Vec<_> = program
+        .backtrace()
+        .iter()
+        .enumerate()
+        .map(|(i, frame)| {
+            let instruction = program
+                .disassemble(frame.address)
+                .map_or_else(|| "??".to_string(), |d| d.to_string());
+            let name = if frame.return_address.is_some() {
+                format!("{}: {} in {}", frame.address, instruction, frame.function)
+            } else {
+                format!("{}: {}", frame.address, instruction)
+            };
+            Json::object(vec![
+                ("id", (i + 1).into()),
+                ("name", name.into()),
+                ("line", 0i64.into()),
+                ("column", 0i64.into()),
+                (
+                    "instructionPointerReference",
+                    frame.address.to_string().into(),
+                ),
+            ])
+        })
+        .collect();
+    Ok(Json::object(vec![
+        ("totalFrames", frames.len().into()),
+        ("stackFrames", Json::Array(frames)),
+    ]))
+}
+
+fn scopes(frame: Option<i64>) -> Json {
+    let scope = |name: &str, reference: i64| {
+        Json::object(vec![
+            ("name", name.into()),
+            ("variablesReference", reference.into()),
+            ("expensive", false.into()),
+        ])
+    };
+    Json::object(vec![(
+        "scopes",
+        Json::Array(vec![
+            scope("Locals", LOCALS + frame.unwrap_or(1) - 1),
+            scope("Registers", REGISTERS),
+            scope("Input", INPUT),
+            scope("Output", OUTPUT),
+        ]),
+    )])
+}
+
+fn variables(program: &Program, reference: Option<i64>) -> Result<Json, String> {
+    let variable = |name: String, value: String| {
+        Json::object(vec![
+            ("name", name.into()),
+            ("value", value.into()),
+            ("variablesReference", 0i64.into()),
+        ])
+    };
+    let queue = |values: Vec<Value>| {
+        values
+            .into_iter()
+            .enumerate()
+            .map(|(i, value)| variable(format!("[{}]", i), value.to_string()))
+            .collect()
+    };
+    let variables = match reference {
+        Some(REGISTERS) => vec![
+            variable("ip".to_string(), program.instruction_ptr().to_string()),
+            variable("rb".to_string(), program.relative_base().to_string()),
+            variable("elapsed".to_string(), program.elapsed().to_string()),
+        ],
+        Some(INPUT) => queue(program.input_queue().iter().cloned().collect()),
+        Some(OUTPUT) => queue(program.output_queue().iter().cloned().collect()),
+        Some(reference) if reference >= LOCALS => {
+            let backtrace = program.backtrace();
+            let frame = backtrace
+                .get((reference - LOCALS) as usize)
+                .ok_or("Unknown stack frame")?;
+            frame
+                .locals
+                .iter()
+                .enumerate()
+                .map(|(i, value)| {
+                    let name = format!("[{}]", frame.base + 1 + i);
+                    variable(name, value.to_string())
+                })
+                .collect()
+        }
+        _ => return Err("Unknown variables reference".to_string()),
+    };
+    Ok(Json::object(vec![("variables", Json::Array(variables))]))
+}
+
+fn memory_reference(args: &Json) -> Result<usize, String> {
+    args.get("memoryReference")
+        .as_str()
+        .and_then(|r| r.parse().ok())
+        .ok_or_else(|| "Invalid memory reference".to_string())
+}
+
+fn offset(args: &Json) -> Result<usize, String> {
+    let offset = args.get("offset").as_i64().unwrap_or(0);
+    if offset < 0 {
+        return Err("Negative offsets are not supported".to_string());
+    }
+    Ok(offset as usize)
+}
+
+// The bytes from the memory reference plus offset on, an error if they don't fit in the address
+// space
+fn memory_range(args: &Json, count: usize) -> Result<Range<usize>, String> {
+    let (reference, offset) = (memory_reference(args)?, offset(args)?);
+    let start = reference
+        .checked_mul(CELL_SIZE)
+        .and_then(|start| start.checked_add(offset));
+    let end = start.and_then(|start| start.checked_add(count));
+    let (start, end) = start.zip(end).ok_or("Memory range out of bounds")?;
+    Ok(start..end)
+}
+
+fn read_memory(program: &Program, args: &Json) -> Result<Json, String> {
+    let count = args.get("count").as_i64().unwrap_or(0).max(0) as usize;
+    if count > MAX_READ {
+        return Err(format!("At most {} bytes can be read at once", MAX_READ));
+    }
+    let range = memory_range(args, count)?;
+    let start = range.start;
+    let bytes: Vec<u8> = range
+        .map(|byte| program.peek(byte / CELL_SIZE).to_le_bytes()[byte % CELL_SIZE])
+        .collect();
+    Ok(Json::object(vec![
+        ("address", (start / CELL_SIZE).to_string().into()),
+        ("data", encode_base64(&bytes).into()),
+    ]))
+}
+
+fn disassemble(program: &Program, args: &Json) -> Result<Json, String> {
+    let reference = memory_reference(args)?;
+    let instruction_offset = args.get("instructionOffset").as_i64().unwrap_or(0);
+    let count = args.get("instructionCount").as_i64().unwrap_or(0).max(0) as usize;
+    if count > MAX_INSTRUCTIONS || instruction_offset.unsigned_abs() > MAX_INSTRUCTIONS as u64 {
+        return Err(format!(
+            "At most {} instructions can be disassembled at once",
+            MAX_INSTRUCTIONS
+        ));
+    }
+
+    // walk back over linearly decoded instructions for negative offsets
+    let mut invalid = 0;
+    let start = if instruction_offset < 0 {
+        let starts = program.instruction_starts(reference);
+        let back = instruction_offset.unsigned_abs() as usize;
+        invalid = back.saturating_sub(starts.len());
+        starts
+            .get(starts.len().saturating_sub(back))
+            .cloned()
+            .unwrap_or(reference)
+    } else {
+        program
+            .disassemble_range(reference, instruction_offset as usize + 1)
+            .last()
+            .map_or(reference, |(address, _)| *address)
+    };
+
+    let mut instructions: Vec<Json> = (0..invalid.min(count))
+        .map(|_| {
+            Json::object(vec![
+                ("address", "0".into()),
+                ("instruction", "".into()),
+                ("presentationHint", "invalid".into()),
+            ])
+        })
+        .collect();
+    for (address, disassembly) in program.disassemble_range(start, count - instructions.len()) {
+        let len = disassembly.as_ref().map_or(1, |d| d.len());
+        let bytes: Vec<String> = (address..=address + (len - 1))
+            .map(|a| program.peek(a).to_string())
+            .collect();
+        let text = match disassembly {
+            Some(disassembly) => disassembly.to_string(),
+            None => format!(".data {}", program.peek(address)),
+        };
+        instructions.push(Json::object(vec![
+            ("address", address.to_string().into()),
+            ("instructionBytes", bytes.join(",").into()),
+            ("instruction", text.into()),
+        ]));
+    }
+    Ok(Json::object(vec![(
+        "instructions",
+        Json::Array(instructions),
+    )]))
+}
+
+/// Reads a single `Content-Length` framed message, None once the stream ended
+pub fn read_message<R: BufRead>(reader: &mut R) -> io::Result<Option<Json>> {
+    let mut length = None;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let line = line.trim();
+        if line.is_empty() {
+            if length.is_some() {
+                break;
+            }
+            continue;
+        }
+        if let Some(value) = line.strip_prefix("Content-Length:") {
+            length = value.trim().parse::<usize>().ok();
+        }
+    }
+    let mut content = vec![0; length.unwrap_or(0)];
+    reader.read_exact(&mut content)?;
+    let message = Json::parse(&String::from_utf8_lossy(&content))
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Invalid JSON message"))?;
+    Ok(Some(message))
+}
+
+const BASE64: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+fn encode_base64(bytes: &[u8]) -> String {
+    let mut encoded = String::new();
+    for chunk in bytes.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, b)| n | (u32::from(*b) << (16 - 8 * i)));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(BASE64[((n >> (18 - 6 * i)) & 0x3f) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+fn decode_base64(text: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::new();
+    let mut n = 0u32;
+    let mut bits = 0;
+    for c in text.bytes().filter(|c| *c != b'=') {
+        let value = BASE64.iter().position(|b| *b == c)? as u32;
+        n = (n << 6) | value;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push(((n >> bits) & 0xff) as u8);
+        }
+    }
+    Some(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn request(seq: i64, command: &str, arguments: Json) -> Json {
+        Json::object(vec![
+            ("seq", seq.into()),
+            ("type", "request".into()),
+            ("command", command.into()),
+            ("arguments", arguments),
+        ])
+    }
+
+    fn messages(output: &[u8]) -> Vec<Json> {
+        let mut reader = Cursor::new(output);
+        let mut messages = Vec::new();
+        while let Some(message) = read_message(&mut reader).unwrap() {
+            messages.push(message);
+        }
+        messages
+    }
+
+    fn describe(message: &Json) -> String {
+        match message.get("type").as_str() {
+            Some("response") => format!("response {}", message.get("command").as_str().unwrap()),
+            _ => format!("event {}", message.get("event").as_str().unwrap()),
+        }
+    }
+
+    #[test]
+    fn test_session() {
+        let mut server = DapServer::new(Vec::new());
+        let launch = Json::object(vec![
+            ("image", "3,9,4,9,4,9,99,0,0,0".into()),
+            ("stopOnEntry", true.into()),
+        ]);
+        let breakpoint = Json::object(vec![(
+            "breakpoints",
+            Json::Array(vec![Json::object(vec![(
+                "instructionReference",
+                "4".into(),
+            )])]),
+        )]);
+        let input = Json::object(vec![("expression", "input 7".into())]);
+
+        server
+            .handle(&request(1, "initialize", Json::Null))
+            .unwrap();
+        server.handle(&request(2, "launch", launch)).unwrap();
+        server
+            .handle(&request(3, "setInstructionBreakpoints", breakpoint))
+            .unwrap();
+        server
+            .handle(&request(4, "configurationDone", Json::Null))
+            .unwrap();
+        server.handle(&request(5, "continue", Json::Null)).unwrap();
+        server.run_chunk(100).unwrap();
+        server.handle(&request(6, "evaluate", input)).unwrap();
+        server.handle(&request(7, "continue", Json::Null)).unwrap();
+        server.run_chunk(100).unwrap();
+        server.handle(&request(8, "next", Json::Null)).unwrap();
+        server.handle(&request(9, "continue", Json::Null)).unwrap();
+        server.run_chunk(100).unwrap();
+
+        let messages = messages(&server.writer);
+        let log: Vec<_> = messages.iter().map(describe).collect();
+        assert_eq!(
+            log,
+            [
+                "response initialize",
+                "event initialized",
+                "response launch",
+                "response setInstructionBreakpoints",
+                "response configurationDone",
+                "event stopped",
+                "response continue",
+                "event stopped",
+                "response evaluate",
+                "response continue",
+                "event output",
+                "event stopped",
+                "response next",
+                "event output",
+                "event stopped",
+                "response continue",
+                "event exited",
+                "event terminated",
+            ]
+        );
+        let reasons: Vec<_> = messages
+            .iter()
+            .filter_map(|m| m.get("body").get("reason").as_str())
+            .collect();
+        assert_eq!(
+            reasons,
+            ["entry", "input", "instruction breakpoint", "step"]
+        );
+        assert_eq!(messages[10].get("body").get("output").as_str(), Some("7\n"));
+    }
+
+    #[test]
+    fn test_inspection_requests() {
+        let mut server = DapServer::new(Vec::new());
+        let launch = Json::object(vec![
+            ("image", "1101,1,2,5,99,-1".into()),
+            ("input", Json::Array(vec![3i64.into()])),
+        ]);
+        server.handle(&request(1, "launch", launch)).unwrap();
+
+        let memory = Json::object(vec![
+            ("memoryReference", "4".into()),
+            ("count", 16i64.into()),
+        ]);
+        let write = Json::object(vec![
+            ("memoryReference", "0".into()),
+            ("data", encode_base64(&[0x4e, 0x04]).into()),
+        ]);
+        let disassemble = Json::object(vec![
+            ("memoryReference", "4".into()),
+            ("instructionOffset", (-1i64).into()),
+            ("instructionCount", 3i64.into()),
+        ]);
+        let variables = Json::object(vec![("variablesReference", INPUT.into())]);
+        server.handle(&request(2, "readMemory", memory)).unwrap();
+        server.handle(&request(3, "writeMemory", write)).unwrap();
+        server
+            .handle(&request(4, "disassemble", disassemble))
+            .unwrap();
+        server.handle(&request(5, "variables", variables)).unwrap();
+        server
+            .handle(&request(6, "stackTrace", Json::Null))
+            .unwrap();
+
+        let messages = messages(&server.writer);
+        let bodies: Vec<_> = messages.iter().map(|m| m.get("body")).collect();
+
+        let data = bodies[1].get("data").as_str().unwrap();
+        let bytes = decode_base64(data).unwrap();
+        assert_eq!(bytes[..8], 99i64.to_le_bytes());
+        assert_eq!(bytes[8..], (-1i64).to_le_bytes());
+
+        let listing: Vec<_> = bodies[3]
+            .get("instructions")
+            .as_array()
+            .iter()
+            .map(|i| i.get("instruction").as_str().unwrap().to_string())
+            .collect();
+        assert_eq!(listing, ["mul 1, 2, [5]", "hlt", ".data -1"]);
+
+        let input = &bodies[4].get("variables").as_array()[0];
+        assert_eq!(input.get("value").as_str(), Some("3"));
+
+        let frame = &bodies[5].get("stackFrames").as_array()[0];
+        assert_eq!(frame.get("name").as_str(), Some("0: mul 1, 2, [5]"));
+    }
+
+    #[test]
+    fn test_memory_out_of_bounds() {
+        let mut server = DapServer::new(Vec::new());
+        let launch = Json::object(vec![("image", "99".into())]);
+        server.handle(&request(1, "launch", launch)).unwrap();
+
+        let huge = (usize::MAX / CELL_SIZE).to_string();
+        let read = Json::object(vec![
+            ("memoryReference", huge.as_str().into()),
+            ("count", 16i64.into()),
+        ]);
+        let offset = Json::object(vec![
+            ("memoryReference", huge.as_str().into()),
+            ("offset", i64::MAX.into()),
+            ("count", 1i64.into()),
+        ]);
+        let write = Json::object(vec![
+            ("memoryReference", huge.as_str().into()),
+            ("offset", 8i64.into()),
+            ("data", encode_base64(&[1]).into()),
+        ]);
+        server.handle(&request(2, "readMemory", read)).unwrap();
+        server.handle(&request(3, "readMemory", offset)).unwrap();
+        server.handle(&request(4, "writeMemory", write)).unwrap();
+
+        let messages = messages(&server.writer);
+        for response in &messages[1..] {
+            assert_eq!(response.get("success"), &Json::Bool(false));
+            assert_eq!(
+                response.get("message").as_str(),
+                Some("Memory range out of bounds")
+            );
+        }
+    }
+
+    #[test]
+    fn test_limits_and_bad_opcode() {
+        let mut server = DapServer::new(Vec::new());
+        let launch = Json::object(vec![("image", "50,99".into())]);
+        let read = Json::object(vec![
+            ("memoryReference", "0".into()),
+            ("count", i64::MAX.into()),
+        ]);
+        let disassemble = Json::object(vec![
+            ("memoryReference", "0".into()),
+            ("instructionCount", i64::MAX.into()),
+        ]);
+        server.handle(&request(1, "launch", launch)).unwrap();
+        server.handle(&request(2, "readMemory", read)).unwrap();
+        server
+            .handle(&request(3, "disassemble", disassemble))
+            .unwrap();
+        server
+            .handle(&request(4, "configurationDone", Json::Null))
+            .unwrap();
+        server.run_chunk(100).unwrap();
+
+        let messages = messages(&server.writer);
+        assert_eq!(
+            messages[1].get("message").as_str(),
+            Some("At most 1048576 bytes can be read at once")
+        );
+        assert_eq!(
+            messages[2].get("message").as_str(),
+            Some("At most 10000 instructions can be disassembled at once")
+        );
+        let stopped = &messages.last().unwrap().get("body");
+        assert_eq!(stopped.get("reason").as_str(), Some("exception"));
+        assert_eq!(
+            stopped.get("description").as_str(),
+            Some("Unknown instruction 50 at 0")
+        );
+    }
+
+    #[test]
+    fn test_base64() {
+        for bytes in &[&b""[..], b"f", b"fo", b"foo", b"foob", b"\xff\x00\x10"] {
+            assert_eq!(decode_base64(&encode_base64(bytes)).unwrap(), *bytes);
+        }
+        assert_eq!(encode_base64(b"foob"), "Zm9vYg==");
+    }
+}
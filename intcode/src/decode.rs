@@ -0,0 +1,129 @@
+use crate::extension::Extensions;
+use crate::{
+    custom_instruction_from_value, try_instruction_from_value, Addr, Instruction, OpCode,
+    ParameterMode, Value,
+};
+use alloc::collections::BTreeMap;
+use alloc::vec;
+
+/// Statically decodes every instruction reachable from address 0.
+///
+/// Execution is followed through fall-through and immediate jump targets. Jumps through memory
+/// can't be resolved without running the program, so code only reachable that way is missed.
+/// Custom instructions are assumed to continue with the next instruction.
+pub(crate) fn reachable_instructions(
+    image: &[Value],
+    extensions: &Extensions,
+) -> BTreeMap<Addr, Instruction> {
+    let mut instructions = BTreeMap::new();
+    let mut pending = vec![0];
+
+    while let Some(addr) = pending.pop() {
+        if addr >= image.len() || instructions.contains_key(&addr) {
+            continue;
+        }
+        let instruction = match custom_instruction_from_value(image[addr], extensions)
+            .or_else(|| try_instruction_from_value(image[addr]))
+        {
+            Some(instruction) => instruction,
+            None => continue,
+        };
+        let (next, target) = successors(image, addr, &instruction, |_| false);
+        pending.extend(next);
+        match target {
+            Some(target) if target >= 0 => pending.push(target as Addr),
+            _ => {}
+        }
+        instructions.insert(addr, instruction);
+    }
+    instructions
+}
+
+/// Where execution can continue after the instruction at `addr`: the following instruction
+/// unless it's a halt or an always taken jump, and the target of an immediate jump unless it's
+/// never taken. Jump targets aren't checked against the image.
+///
+/// Conditions are known for immediate parameters and for position parameters pointing at a cell
+/// for which `is_constant` holds.
+pub(crate) fn successors<F>(
+    image: &[Value],
+    addr: Addr,
+    instruction: &Instruction,
+    is_constant: F,
+) -> (Option<Addr>, Option<Value>)
+where
+    F: Fn(Addr) -> bool,
+{
+    let next = addr + instruction.opcode.len();
+    match instruction.opcode {
+        OpCode::Halt => (None, None),
+        OpCode::JumpIfTrue | OpCode::JumpIfFalse => {
+            let parameter = image.get(addr + 1).cloned();
+            let target = image.get(addr + 2).cloned();
+            let [condition_mode, target_mode, _] = instruction.parameter_modes;
+
+            let condition = match (condition_mode, parameter) {
+                (ParameterMode::Immediate, _) => parameter,
+                (ParameterMode::Position, Some(position))
+                    if position >= 0 && is_constant(position as Addr) =>
+                {
+                    image.get(position as Addr).cloned()
+                }
+                _ => None,
+            };
+            let jumps = condition
+                .map(|condition| (condition != 0) == (instruction.opcode == OpCode::JumpIfTrue));
+            let next = if jumps != Some(true) {
+                Some(next)
+            } else {
+                None
+            };
+            let target = if jumps != Some(false) && target_mode == ParameterMode::Immediate {
+                target
+            } else {
+                None
+            };
+            (next, target)
+        }
+        _ => (Some(next), None),
+    }
+}
+
+/// Maps every memory cell of the reachable instructions to the address of its instruction
+pub(crate) fn code_cells(image: &[Value], extensions: &Extensions) -> BTreeMap<Addr, Addr> {
+    let mut cells = BTreeMap::new();
+    for (addr, instruction) in reachable_instructions(image, extensions) {
+        for cell in addr..addr + instruction.opcode.len() {
+            cells.insert(cell, addr);
+        }
+    }
+    cells
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    #[test]
+    fn test_reachable_instructions() {
+        // jump over data, the second jump is always taken
+        let image = vec![1105, 1, 4, 42, 1006, 3, 11, 1106, 0, 11, 123, 99];
+        let addresses: Vec<_> = reachable_instructions(&image, &Extensions::new())
+            .keys()
+            .cloned()
+            .collect();
+
+        assert_eq!(addresses, [0, 4, 7, 11]);
+    }
+
+    #[test]
+    fn test_code_cells() {
+        let image = vec![1, 0, 0, 0, 99, 7];
+        let cells = code_cells(&image, &Extensions::new());
+
+        assert_eq!(cells.get(&3), Some(&0));
+        assert_eq!(cells.get(&4), Some(&4));
+        assert_eq!(cells.get(&5), None);
+    }
+}
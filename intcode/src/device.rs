@@ -0,0 +1,347 @@
+//! Memory-mapped devices: address ranges whose reads and writes are handled by an object instead
+//! of memory.
+//!
+//! Devices see the offset into their range and the number of steps executed so far. They belong
+//! to the program: clones get copies of them, and they aren't part of snapshots or of the state
+//! compared by `Program::eq()`. Between runs they are reached with `Program::device()` and
+//! `Program::device_mut()`.
+
+use crate::{Addr, Program, Value};
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::any::{type_name, Any};
+use core::fmt::{Debug, Display, Error, Formatter};
+use core::ops::Range;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+pub trait Device: Clone + Send + Sync + 'static {
+    /// Reads the cell at `offset` into the mapped range. Reads shouldn't change the device,
+    /// debuggers and the disassembler read memory too.
+    fn read(&self, offset: usize, step: usize) -> Value;
+    fn write(&mut self, offset: usize, value: Value, step: usize);
+}
+
+/// `Device` without the parts which keep it from being a trait object
+trait Mappable: Send + Sync {
+    fn read(&self, offset: usize, step: usize) -> Value;
+    fn write(&mut self, offset: usize, value: Value, step: usize);
+    fn boxed_clone(&self) -> Box<dyn Mappable>;
+    fn as_any(&self) -> &dyn Any;
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+}
+
+impl<D: Device> Mappable for D {
+    fn read(&self, offset: usize, step: usize) -> Value {
+        Device::read(self, offset, step)
+    }
+    fn write(&mut self, offset: usize, value: Value, step: usize) {
+        Device::write(self, offset, value, step)
+    }
+    fn boxed_clone(&self) -> Box<dyn Mappable> {
+        Box::new(self.clone())
+    }
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+type Mapped = (Range<Addr>, Box<dyn Mappable>);
+
+#[derive(Default)]
+pub(crate) struct Devices {
+    mapped: Vec<Mapped>,
+    /// Set by every access, loop detection can't see what happens inside a device
+    accessed: AtomicBool,
+}
+
+impl Clone for Devices {
+    fn clone(&self) -> Self {
+        Devices {
+            mapped: self
+                .mapped
+                .iter()
+                .map(|(range, device)| (range.clone(), device.boxed_clone()))
+                .collect(),
+            accessed: AtomicBool::new(false),
+        }
+    }
+}
+
+impl Debug for Devices {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        let ranges: Vec<_> = self.mapped.iter().map(|(range, _)| range).collect();
+        f.debug_struct("Devices").field("mapped", &ranges).finish()
+    }
+}
+
+impl Devices {
+    pub(crate) fn is_empty(&self) -> bool {
+        self.mapped.is_empty()
+    }
+    /// The index of the device mapped at `address` and the offset into its range
+    fn find(&self, address: Addr) -> Option<(usize, usize)> {
+        let index = self
+            .mapped
+            .iter()
+            .position(|(range, _)| range.contains(&address))?;
+        self.accessed.store(true, Ordering::Relaxed);
+        Some((index, address - self.mapped[index].0.start))
+    }
+    /// None if no device is mapped at `address`
+    pub(crate) fn read(&self, address: Addr, step: usize) -> Option<Value> {
+        let (index, offset) = self.find(address)?;
+        Some(self.mapped[index].1.read(offset, step))
+    }
+    /// False if no device is mapped at `address`
+    pub(crate) fn write(&mut self, address: Addr, value: Value, step: usize) -> bool {
+        match self.find(address) {
+            Some((index, offset)) => {
+                self.mapped[index].1.write(offset, value, step);
+                true
+            }
+            None => false,
+        }
+    }
+    /// Whether a device was accessed since the last call
+    pub(crate) fn take_accessed(&self) -> bool {
+        self.accessed.swap(false, Ordering::Relaxed)
+    }
+}
+
+impl Program {
+    /// Hands reads and writes of `range` to `device` and returns its id, ids count up from 0.
+    /// Panics if the range is empty or overlaps a device which is already mapped.
+    pub fn map_device<D: Device>(&mut self, range: Range<usize>, device: D) -> usize {
+        if range.is_empty() {
+            panic!("Cannot map a device to the empty range {:?}", range)
+        }
+        let overlaps = |(mapped, _): &Mapped| mapped.start < range.end && range.start < mapped.end;
+        if self.devices.mapped.iter().any(overlaps) {
+            panic!("Address range {:?} overlaps a mapped device", range)
+        }
+        self.devices.mapped.push((range, Box::new(device)));
+        self.devices.mapped.len() - 1
+    }
+    /// The device with id `id`. Panics if there is none or it isn't a `D`.
+    pub fn device<D: Device>(&self, id: usize) -> &D {
+        self.devices.mapped[id]
+            .1
+            .as_any()
+            .downcast_ref()
+            .unwrap_or_else(|| panic!("Device {} is not a {}", id, type_name::<D>()))
+    }
+    /// The device with id `id`. Panics if there is none or it isn't a `D`.
+    pub fn device_mut<D: Device>(&mut self, id: usize) -> &mut D {
+        self.devices.mapped[id]
+            .1
+            .as_any_mut()
+            .downcast_mut()
+            .unwrap_or_else(|| panic!("Device {} is not a {}", id, type_name::<D>()))
+    }
+}
+
+/// A text terminal in two cells. Writing to cell 0 prints a character, reading it gives the next
+/// typed character or 0. Cell 1 holds the number of typed characters, writing to it drops the
+/// first one.
+#[derive(Debug, Clone, Default)]
+pub struct Console {
+    typed: VecDeque<Value>,
+    text: String,
+}
+
+impl Console {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Queues `line` and a newline for the program to read
+    pub fn type_line(&mut self, line: &str) {
+        self.typed
+            .extend(line.chars().map(|c| Value::from(c as u32)));
+        self.typed.push_back(10);
+    }
+    /// Everything the program printed
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+}
+
+impl Device for Console {
+    fn read(&self, offset: usize, _: usize) -> Value {
+        match offset {
+            0 => self.typed.front().cloned().unwrap_or(0),
+            _ => self.typed.len() as Value,
+        }
+    }
+    fn write(&mut self, offset: usize, value: Value, _: usize) {
+        match offset {
+            0 => self.text.push(value as u8 as char),
+            _ => {
+                self.typed.pop_front();
+            }
+        }
+    }
+}
+
+/// A single cell counting the steps executed. Writing a value restarts the count from it.
+#[derive(Debug, Clone, Default)]
+pub struct Timer {
+    start: Value,
+}
+
+impl Timer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Device for Timer {
+    fn read(&self, _: usize, step: usize) -> Value {
+        step as Value - self.start
+    }
+    fn write(&mut self, _: usize, value: Value, step: usize) {
+        self.start = step as Value - value;
+    }
+}
+
+/// `width * height` cells of pixels, row by row
+#[derive(Debug, Clone)]
+pub struct Framebuffer {
+    width: usize,
+    pixels: Vec<Value>,
+}
+
+impl Framebuffer {
+    pub fn new(width: usize, height: usize) -> Self {
+        Framebuffer {
+            width,
+            pixels: vec![0; width * height],
+        }
+    }
+    pub fn width(&self) -> usize {
+        self.width
+    }
+    pub fn height(&self) -> usize {
+        self.pixels.len() / self.width
+    }
+    /// The number of cells to map
+    pub fn len(&self) -> usize {
+        self.pixels.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.pixels.is_empty()
+    }
+    pub fn pixel(&self, x: usize, y: usize) -> Value {
+        self.pixels[y * self.width + x]
+    }
+    pub fn pixels(&self) -> &[Value] {
+        &self.pixels
+    }
+}
+
+/// Shows set pixels as `#` and pixels holding 0 as `.`, one line per row
+impl Display for Framebuffer {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        for row in self.pixels.chunks(self.width) {
+            let row: String = row
+                .iter()
+                .map(|&v| if v == 0 { '.' } else { '#' })
+                .collect();
+            writeln!(f, "{}", row)?;
+        }
+        Ok(())
+    }
+}
+
+impl Device for Framebuffer {
+    fn read(&self, offset: usize, _: usize) -> Value {
+        self.pixels[offset]
+    }
+    fn write(&mut self, offset: usize, value: Value, _: usize) {
+        self.pixels[offset] = value;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::string::ToString;
+
+    #[test]
+    fn test_console() {
+        // echoes typed characters until there are none left
+        let mut p = Program::new(&[
+            1006, 1001, 14, // no characters left, halt
+            1001, 1000, 0, 1000, // print the next character
+            1101, 0, 0, 1001, // drop it
+            1105, 1, 0, //
+            99,
+        ]);
+        let console = p.map_device(1000..1002, Console::new());
+        p.device_mut::<Console>(console).type_line("ok");
+        p.run();
+        assert_eq!(p.device::<Console>(console).text(), "ok\n");
+        assert_eq!(p.peek(1001), 0);
+    }
+
+    #[test]
+    fn test_timer() {
+        // outputs the timer, sets it to 5 and outputs it again
+        let mut p = Program::new(&[4, 500, 1101, 5, 0, 500, 4, 500, 99]);
+        p.map_device(500..501, Timer::new());
+        assert_eq!(p.run().iter().collect::<Vec<_>>(), [&0, &6]);
+
+        // waits until the timer reaches 10, a loop without I/O that still ends
+        let mut p = Program::new(&[1007, 500, 10, 20, 1005, 20, 0, 99]);
+        p.map_device(500..501, Timer::new());
+        p.detect_loops();
+        p.run();
+        assert!(p.is_halted());
+        assert_eq!(p.stuck(), None);
+    }
+
+    #[test]
+    fn test_framebuffer() {
+        // draws a diagonal and copies the first pixel into the last one
+        let mut p = Program::new(&[1101, 1, 0, 100, 1101, 2, 0, 104, 1001, 100, 0, 107, 99]);
+        let screen = p.map_device(100..108, Framebuffer::new(4, 2));
+        p.run();
+        assert_eq!(p.peek(107), 1);
+        let screen = p.device::<Framebuffer>(screen);
+        assert_eq!(screen.to_string(), "#...\n#..#\n");
+        assert_eq!(screen.pixel(0, 1), 2);
+    }
+
+    #[test]
+    fn test_clones_own_devices() {
+        // writes its input to the framebuffer
+        let mut p = Program::new(&[3, 100, 99]);
+        let screen = p.map_device(100..101, Framebuffer::new(1, 1));
+        let mut clone = p.clone();
+        clone.set_input(1);
+        clone.run();
+        assert_eq!(clone.device::<Framebuffer>(screen).pixels(), [1]);
+        assert_eq!(p.device::<Framebuffer>(screen).pixels(), [0]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Device 0 is not a")]
+    fn test_wrong_device_type() {
+        let mut p = Program::new(&[99]);
+        let timer = p.map_device(10..11, Timer::new());
+        p.device::<Console>(timer);
+    }
+
+    #[test]
+    #[should_panic(expected = "overlaps a mapped device")]
+    fn test_overlapping_devices() {
+        let mut p = Program::new(&[99]);
+        p.map_device(10..20, Framebuffer::new(5, 2));
+        p.map_device(19..20, Timer::new());
+    }
+}
@@ -0,0 +1,224 @@
+use crate::{Program, Value};
+use alloc::collections::BTreeSet;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt::{Debug, Display, Error, Formatter};
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Change<T> {
+    pub old: T,
+    pub new: T,
+}
+
+impl<T: PartialEq> Change<T> {
+    fn between(old: T, new: T) -> Option<Self> {
+        if old == new {
+            None
+        } else {
+            Some(Change { old, new })
+        }
+    }
+}
+
+/// Consecutive changed addresses starting at `start`, `old` and `new` have the same length
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Run {
+    pub start: usize,
+    pub old: Vec<Value>,
+    pub new: Vec<Value>,
+}
+
+impl Run {
+    pub fn len(&self) -> usize {
+        self.new.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.new.is_empty()
+    }
+    pub fn end(&self) -> usize {
+        self.start + self.len()
+    }
+}
+
+/// Differences between two program states.
+/// Unset memory reads as 0, so writing a 0 to a fresh address is not reported as a change.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct Diff {
+    pub memory: Vec<Run>,
+    pub instruction_ptr: Option<Change<usize>>,
+    pub relative_base: Option<Change<usize>>,
+    pub input: Option<Change<Vec<Value>>>,
+    pub output: Option<Change<Vec<Value>>>,
+}
+
+impl Diff {
+    pub fn is_empty(&self) -> bool {
+        *self == Diff::default()
+    }
+    /// Every changed address with its old and new value
+    pub fn changes(&self) -> impl Iterator<Item = (usize, Change<Value>)> + '_ {
+        self.memory.iter().flat_map(|run| {
+            (run.start..)
+                .zip(run.old.iter().zip(&run.new))
+                .map(|(address, (&old, &new))| (address, Change { old, new }))
+        })
+    }
+}
+
+impl Display for Diff {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        fn register<T: Debug>(
+            f: &mut Formatter<'_>,
+            name: &str,
+            change: &Option<Change<T>>,
+        ) -> Result<(), Error> {
+            if let Some(change) = change {
+                writeln!(f, "{}: {:?} -> {:?}", name, change.old, change.new)?;
+            }
+            Ok(())
+        }
+        register(f, "instruction_ptr", &self.instruction_ptr)?;
+        register(f, "relative_base", &self.relative_base)?;
+        register(f, "input", &self.input)?;
+        register(f, "output", &self.output)?;
+        for run in &self.memory {
+            writeln!(
+                f,
+                "[{}..{}]: {:?} -> {:?}",
+                run.start,
+                run.end(),
+                run.old,
+                run.new
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl Program {
+    /// `diff()` compares this state to `other`, treating `self` as the old state. Only memory is
+    /// compared, memory-mapped devices aren't read.
+    pub fn diff(&self, other: &Program) -> Diff {
+        let raw = |program: &Program, address| program.memory.get(&address).cloned().unwrap_or(0);
+        let addresses: BTreeSet<usize> = self
+            .memory
+            .keys()
+            .chain(other.memory.keys())
+            .cloned()
+            .collect();
+
+        let mut memory: Vec<Run> = Vec::new();
+        for address in addresses {
+            let old = raw(self, address);
+            let new = raw(other, address);
+            if old == new {
+                continue;
+            }
+            match memory.last_mut() {
+                Some(run) if run.end() == address => {
+                    run.old.push(old);
+                    run.new.push(new);
+                }
+                _ => memory.push(Run {
+                    start: address,
+                    old: vec![old],
+                    new: vec![new],
+                }),
+            }
+        }
+
+        Diff {
+            memory,
+            instruction_ptr: Change::between(self.instruction_ptr, other.instruction_ptr),
+            relative_base: Change::between(self.relative_base, other.relative_base),
+            input: Change::between(
+                self.input.iter().cloned().collect(),
+                other.input.iter().cloned().collect(),
+            ),
+            output: Change::between(
+                self.output.iter().cloned().collect(),
+                other.output.iter().cloned().collect(),
+            ),
+        }
+    }
+    /// `diff_image()` compares the original image this program was created from to the current
+    /// state
+    pub fn diff_image(&self) -> Diff {
+        Program::new(self.image()).diff(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_diff_image() {
+        let mut p = Program::new(&[1, 0, 0, 0, 1101, 1, 1, 11, 4, 0, 99]);
+        p.run();
+        let diff = p.diff_image();
+
+        assert_eq!(
+            diff.memory,
+            [
+                Run {
+                    start: 0,
+                    old: vec![1],
+                    new: vec![2]
+                },
+                Run {
+                    start: 11,
+                    old: vec![0],
+                    new: vec![2]
+                }
+            ]
+        );
+        assert_eq!(diff.instruction_ptr, Some(Change { old: 0, new: 10 }));
+        assert_eq!(diff.relative_base, None);
+        assert_eq!(diff.input, None);
+        assert_eq!(
+            diff.output,
+            Some(Change {
+                old: vec![],
+                new: vec![2]
+            })
+        );
+    }
+
+    #[test]
+    fn test_diff_skips_devices() {
+        #[derive(Clone)]
+        struct Constant;
+        impl crate::device::Device for Constant {
+            fn read(&self, _: usize, _: usize) -> Value {
+                7
+            }
+            fn write(&mut self, _: usize, _: Value, _: usize) {}
+        }
+        let mut p = Program::new(&[1101, 1, 1, 1, 99]);
+        p.map_device(0..4, Constant);
+        assert_eq!(p.peek(2), 7);
+        assert!(p.diff_image().is_empty());
+        assert!(p.snapshot().memory.is_empty());
+    }
+
+    #[test]
+    fn test_diff_groups_runs() {
+        let old = Program::new(&[99]);
+        let mut new = old.clone();
+        new.fill(5..8, 1);
+        new.poke(9, 1);
+        new.poke(1_000_000, 7);
+        new.poke(2, 0);
+
+        let diff = old.diff(&new);
+        let runs: Vec<_> = diff.memory.iter().map(|r| (r.start, r.end())).collect();
+        assert_eq!(runs, [(5, 8), (9, 10), (1_000_000, 1_000_001)]);
+        assert_eq!(diff.changes().count(), 5);
+        assert_eq!(
+            diff.changes().last(),
+            Some((1_000_000, Change { old: 0, new: 7 }))
+        );
+        assert!(old.diff(&old).is_empty());
+    }
+}
@@ -0,0 +1,204 @@
+use crate::extension::Extensions;
+use crate::link::SymbolMap;
+use crate::{
+    custom_instruction_from_value, try_instruction_from_value, Instruction, OpCode, ParameterMode,
+    Program, Value,
+};
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::fmt::{Display, Error, Formatter};
+
+/// A decoded instruction with its parameters, as found at `address`
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Disassembly {
+    pub address: usize,
+    pub mnemonic: String,
+    pub parameters: Vec<(ParameterMode, Value)>,
+}
+
+impl Disassembly {
+    /// Number of memory cells taken by the instruction and its parameters
+    pub fn len(&self) -> usize {
+        self.parameters.len() + 1
+    }
+    pub fn is_empty(&self) -> bool {
+        false
+    }
+    /// Address of the following instruction
+    pub fn next(&self) -> usize {
+        self.address + self.len()
+    }
+}
+
+impl Disassembly {
+    /// Like `to_string()`, but with position parameters and immediate jump targets written as
+    /// symbols, e.g. `jt [flag], main+12`
+    pub fn to_string_with(&self, symbols: &SymbolMap) -> String {
+        let is_jump = self.mnemonic == "jt" || self.mnemonic == "jf";
+        let mut text = self.mnemonic.clone();
+        for (i, (mode, value)) in self.parameters.iter().enumerate() {
+            text += if i == 0 { " " } else { ", " };
+            let symbol = |value: Value| match value {
+                value if value >= 0 => symbols.format(value as usize),
+                value => value.to_string(),
+            };
+            text += &match mode {
+                ParameterMode::Position => format!("[{}]", symbol(*value)),
+                ParameterMode::Immediate if is_jump && i == 1 => symbol(*value),
+                ParameterMode::Immediate => value.to_string(),
+                ParameterMode::Relative if *value < 0 => format!("[rb{}]", value),
+                ParameterMode::Relative => format!("[rb+{}]", value),
+            };
+        }
+        text
+    }
+}
+
+/// Formats like `add [9], 3, [rb-1]`: position parameters in brackets, immediates bare and
+/// relative parameters as offsets to the relative base
+impl Display for Disassembly {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        write!(f, "{}", self.to_string_with(&SymbolMap::new()))
+    }
+}
+
+fn mnemonic(opcode: OpCode, extensions: &Extensions) -> String {
+    let mnemonic = match opcode {
+        OpCode::Halt => "hlt",
+        OpCode::Add => "add",
+        OpCode::Mul => "mul",
+        OpCode::Input => "in",
+        OpCode::Output => "out",
+        OpCode::JumpIfTrue => "jt",
+        OpCode::JumpIfFalse => "jf",
+        OpCode::LessThan => "lt",
+        OpCode::Equals => "eq",
+        OpCode::SetRelativeBase => "arb",
+        OpCode::Custom { opcode, .. } => return extensions[&opcode].name.clone(),
+    };
+    mnemonic.to_string()
+}
+
+/// Decodes the instruction at `address` of `memory`, None if the value isn't a valid instruction
+/// or its parameters would lie past the end of the address space
+pub(crate) fn disassemble<M>(
+    memory: M,
+    address: usize,
+    extensions: &Extensions,
+) -> Option<Disassembly>
+where
+    M: Fn(usize) -> Value,
+{
+    let value = memory(address);
+    let Instruction {
+        opcode,
+        parameter_modes,
+    } = custom_instruction_from_value(value, extensions)
+        .or_else(|| try_instruction_from_value(value))?;
+    address.checked_add(opcode.len() - 1)?;
+    let parameters = (1..opcode.len())
+        .map(|i| (parameter_modes[i - 1], memory(address + i)))
+        .collect();
+
+    Some(Disassembly {
+        address,
+        mnemonic: mnemonic(opcode, extensions),
+        parameters,
+    })
+}
+
+impl Program {
+    /// Decodes the instruction at `address` in the current memory
+    pub fn disassemble(&self, address: usize) -> Option<Disassembly> {
+        disassemble(|a| self.value_at(a), address, &self.extensions)
+    }
+    /// Decodes `count` consecutive instructions starting at `address`, fewer if the address space
+    /// ends first. Values which aren't instructions are returned as None and skipped one cell at
+    /// a time.
+    pub fn disassemble_range(
+        &self,
+        address: usize,
+        count: usize,
+    ) -> Vec<(usize, Option<Disassembly>)> {
+        let mut listing = Vec::new();
+        let mut address = Some(address);
+        while let Some(current) = address.filter(|_| listing.len() < count) {
+            let disassembly = self.disassemble(current);
+            let len = disassembly.as_ref().map_or(1, Disassembly::len);
+            address = current.checked_add(len);
+            listing.push((current, disassembly));
+        }
+        listing
+    }
+    /// Start addresses of all instructions before `address` when decoding linearly from 0
+    pub fn instruction_starts(&self, address: usize) -> Vec<usize> {
+        let mut starts = Vec::new();
+        let mut current = 0;
+        while current < address {
+            starts.push(current);
+            current = self.disassemble(current).map_or(current + 1, |d| d.next());
+        }
+        starts
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_disassemble() {
+        let p = Program::new(&[1001, 9, -3, 9, 21107, 1, 2, -4, 109, 7, 99, 12345]);
+
+        let listing: Vec<_> = p
+            .disassemble_range(0, 5)
+            .into_iter()
+            .map(|(address, d)| (address, d.map(|d| d.to_string())))
+            .collect();
+        assert_eq!(
+            listing,
+            [
+                (0, Some("add [9], -3, [9]".to_string())),
+                (4, Some("lt 1, 2, [rb-4]".to_string())),
+                (8, Some("arb 7".to_string())),
+                (10, Some("hlt".to_string())),
+                (11, None)
+            ]
+        );
+        assert_eq!(p.instruction_starts(9), [0, 4, 8]);
+
+        // an add whose parameters would wrap around the address space
+        let mut p = Program::new(&[99]);
+        p.poke(usize::MAX - 1, 1);
+        let listing = p.disassemble_range(usize::MAX - 1, 5);
+        assert_eq!(listing.len(), 2);
+        assert!(listing.iter().all(|(_, d)| d.is_none()));
+    }
+
+    #[test]
+    fn test_disassemble_with_symbols() {
+        let p = Program::new(&[1005, 9, 7, 1106, -1, 0, 99, 99, 99, 0]);
+        let mut symbols = SymbolMap::new();
+        symbols.insert(6, "done");
+        symbols.insert(9, "flag");
+
+        assert_eq!(p.disassemble(0).unwrap().to_string(), "jt [9], 7");
+        assert_eq!(
+            p.disassemble(0).unwrap().to_string_with(&symbols),
+            "jt [flag], done+1"
+        );
+        assert_eq!(
+            p.disassemble(3).unwrap().to_string_with(&symbols),
+            "jf -1, 0"
+        );
+    }
+
+    #[test]
+    fn test_disassemble_custom_opcode() {
+        let mut p = Program::new(&[242, 5, 99]);
+        p.register_opcode(42, "dbg", 1, |_| {});
+
+        assert_eq!(p.disassemble(0).unwrap().to_string(), "dbg [rb+5]");
+    }
+}
@@ -0,0 +1,224 @@
+use crate::replay::Event;
+use crate::{try_opcode_from_value, Addr, ParameterMode, ParameterModes, Program, Value};
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use core::fmt::{Debug, Error, Formatter};
+
+pub type Handler = Arc<dyn Fn(&mut Context<'_>) + Send + Sync>;
+
+/// A user defined instruction, see `Program::register_opcode()`
+#[derive(Clone)]
+pub struct CustomOpCode {
+    pub name: String,
+    pub parameters: usize,
+    handler: Handler,
+}
+
+impl Debug for CustomOpCode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        f.debug_struct("CustomOpCode")
+            .field("name", &self.name)
+            .field("parameters", &self.parameters)
+            .finish()
+    }
+}
+
+pub(crate) type Extensions = BTreeMap<Value, CustomOpCode>;
+
+/// `Context` gives a custom instruction access to its parameters, memory and I/O
+pub struct Context<'a> {
+    program: &'a mut Program,
+    custom: &'a CustomOpCode,
+    parameter_modes: ParameterModes,
+    jumped: bool,
+}
+
+impl<'a> Context<'a> {
+    /// Address of the instruction being executed
+    pub fn address(&self) -> usize {
+        self.program.instruction_ptr
+    }
+    /// Reads parameter 1-3, respecting its parameter mode. Panics if the instruction has fewer
+    /// parameters.
+    pub fn read(&self, parameter: usize) -> Value {
+        self.check_parameter(parameter);
+        self.program
+            .param(parameter, self.parameter_modes[parameter - 1])
+    }
+    /// Writes `value` to the address given by parameter 1-3, respecting its parameter mode.
+    /// Panics if the instruction has fewer parameters.
+    pub fn write(&mut self, parameter: usize, value: Value) {
+        self.check_parameter(parameter);
+        let target_addr = self
+            .program
+            .address_at(self.program.instruction_ptr + parameter);
+        self.program
+            .set(target_addr, value, self.parameter_modes[parameter - 1]);
+    }
+    fn check_parameter(&self, parameter: usize) {
+        if !(1..=self.custom.parameters).contains(&parameter) {
+            panic!(
+                "Parameter {} of {} is out of range, it has {} parameters",
+                parameter, self.custom.name, self.custom.parameters
+            )
+        }
+    }
+    /// Reads memory directly, unset addresses read as 0
+    pub fn peek(&self, address: usize) -> Value {
+        self.program.value_at(address)
+    }
+    /// Writes memory directly, ignoring parameter modes
+    pub fn poke(&mut self, address: usize, value: Value) {
+        self.program.set(address, value, ParameterMode::Position);
+    }
+    pub fn relative_base(&self) -> usize {
+        self.program.relative_base
+    }
+    /// Takes the next value from the input queue
+    pub fn input(&mut self) -> Option<Value> {
+        let value = self.program.input.pop_front()?;
+        let step = self.program.elapsed;
+        self.program.record(Event::Input { step, value });
+        Some(value)
+    }
+    pub fn output(&mut self, value: Value) {
+        self.program.output.push_back(value);
+        let step = self.program.elapsed;
+        self.program.record(Event::Output { step, value });
+    }
+    /// Continue execution at `address` instead of the next instruction
+    pub fn jump(&mut self, address: usize) {
+        self.program.instruction_ptr = address;
+        self.jumped = true;
+    }
+}
+
+impl Program {
+    /// `register_opcode()` teaches the program a new instruction with up to three parameters.
+    /// `handler` runs whenever `opcode` is executed, parameter modes work like for built-in
+    /// instructions. Opcodes which are neither built-in nor registered still panic.
+    ///
+    /// ```
+    /// use intcode::Program;
+    ///
+    /// // 10: write the larger of two parameters to the third
+    /// let mut p = Program::new(&[1110, 3, 7, 9, 4, 9, 99, 0, 0, 0]);
+    /// p.register_opcode(10, "max", 3, |ctx| {
+    ///     let max = ctx.read(1).max(ctx.read(2));
+    ///     ctx.write(3, max)
+    /// });
+    /// assert_eq!(p.run()[0], 7);
+    /// ```
+    pub fn register_opcode<F>(&mut self, opcode: Value, name: &str, parameters: usize, handler: F)
+    where
+        F: Fn(&mut Context<'_>) + Send + Sync + 'static,
+    {
+        if !(0..100).contains(&opcode) || try_opcode_from_value(opcode).is_some() {
+            panic!("Opcode {} is reserved or out of range", opcode)
+        }
+        if parameters > 3 {
+            panic!("Parameters 0-3 are supported. Got: {}", parameters)
+        }
+        let custom = CustomOpCode {
+            name: name.to_string(),
+            parameters,
+            handler: Arc::new(handler),
+        };
+        self.extensions.insert(opcode, custom);
+        // loop detection only runs copies of programs without custom instructions
+        self.reset_loop_detection();
+    }
+    /// All registered custom instructions by opcode
+    pub fn custom_opcodes(&self) -> impl Iterator<Item = (Value, &CustomOpCode)> {
+        self.extensions
+            .iter()
+            .map(|(opcode, custom)| (*opcode, custom))
+    }
+
+    // Runs the handler and returns the number of steps to advance the instruction pointer
+    pub(crate) fn execute_custom(
+        &mut self,
+        opcode: Value,
+        parameter_modes: ParameterModes,
+    ) -> Addr {
+        let custom = self.extensions[&opcode].clone();
+        let mut context = Context {
+            program: self,
+            custom: &custom,
+            parameter_modes,
+            jumped: false,
+        };
+        (custom.handler)(&mut context);
+        if context.jumped {
+            0
+        } else {
+            custom.parameters + 1
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+    use std::sync::Mutex;
+
+    #[test]
+    fn test_debug_print_opcode() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let sink = log.clone();
+
+        // 50: log a value without touching the output queue
+        let mut p = Program::new(&[1101, 20, 22, 9, 50, 9, 150, 99, 99, 0]);
+        p.register_opcode(50, "debug", 1, move |ctx| {
+            let value = ctx.read(1);
+            sink.lock().unwrap().push((ctx.address(), value));
+        });
+        let output = p.run();
+
+        assert!(output.is_empty());
+        assert_eq!(*log.lock().unwrap(), [(4, 42), (6, 99)]);
+    }
+
+    #[test]
+    fn test_syscall_opcode_with_io_and_jump() {
+        // 20: read two inputs, output their sum and jump to the first parameter
+        let mut p = Program::new(&[20, 4, 99, 99, 4, 0, 99]);
+        p.register_opcode(20, "syscall", 1, |ctx| {
+            let sum = ctx.input().unwrap() + ctx.input().unwrap();
+            ctx.output(sum);
+            let target = ctx.peek(ctx.address() + 1);
+            ctx.jump(target as usize);
+        });
+        p.set_input(40);
+        p.set_input(2);
+
+        assert_eq!(p.run().iter().cloned().collect::<Vec<_>>(), [42, 20]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Oops, unrecognized opcode: 50")]
+    fn test_unregistered_opcode_faults() {
+        let mut p = Program::new(&[50, 0, 99]);
+        p.register_opcode(51, "unused", 0, |_| {});
+        p.run();
+    }
+
+    #[test]
+    #[should_panic(expected = "Parameter 2 of debug is out of range, it has 1 parameters")]
+    fn test_parameter_out_of_range() {
+        let mut p = Program::new(&[50, 0, 99]);
+        p.register_opcode(50, "debug", 1, |ctx| {
+            ctx.read(2);
+        });
+        p.run();
+    }
+
+    #[test]
+    #[should_panic(expected = "reserved")]
+    fn test_builtin_opcodes_are_reserved() {
+        let mut p = Program::new(&[99]);
+        p.register_opcode(1, "add", 3, |_| {});
+    }
+}
@@ -0,0 +1,403 @@
+//! Machines as futures: input is read from a stream, output is written to a sink, and a
+//! machine waiting for input is suspended until a value arrives.
+//!
+//! Machines talk to each other through `channel()`s and run as tasks on the single-threaded
+//! `Executor`. A day 7 amplifier ring:
+//! ```
+//! use intcode::futures::{channel, Executor};
+//! use intcode::Program;
+//!
+//! let image = [
+//!     3, 26, 1001, 26, -4, 26, 3, 27, 1002, 27, 2, 27, 1, 27, 26, 27, 4, 27, 1001, 28, -1, 28,
+//!     1005, 28, 6, 99, 0, 0, 5,
+//! ];
+//! let phases = [9, 8, 7, 6, 5];
+//! let mut machines = vec![Program::new(&image); phases.len()];
+//! let (senders, mut receivers): (Vec<_>, Vec<_>) = phases.iter().map(|_| channel()).unzip();
+//! for (sender, &phase) in senders.iter().zip(&phases) {
+//!     sender.send(phase);
+//! }
+//! senders[0].send(0);
+//!
+//! let mut executor = Executor::new();
+//! for (i, (machine, input)) in machines.iter_mut().zip(&mut receivers).enumerate() {
+//!     let output = senders[(i + 1) % phases.len()].clone();
+//!     executor.spawn(machine.run_async(input, output));
+//! }
+//! assert_eq!(executor.run(), 0);
+//! drop(executor);
+//! assert_eq!(receivers[0].try_recv(), Some(139629729));
+//! ```
+
+use crate::{Program, Value};
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::future::Future;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Wake, Waker};
+
+/// Values arriving over time
+pub trait ValueStream {
+    /// The next value, `Ready(None)` once no more values will arrive
+    fn poll_next(&mut self, cx: &mut Context<'_>) -> Poll<Option<Value>>;
+}
+
+/// A destination for values which may ask the sender to wait
+pub trait ValueSink {
+    /// Ready once the sink can take a value
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<()>;
+    /// Takes a value, only called after `poll_ready()` returned Ready
+    fn send(&mut self, value: Value);
+}
+
+impl<S: ValueStream + ?Sized> ValueStream for &mut S {
+    fn poll_next(&mut self, cx: &mut Context<'_>) -> Poll<Option<Value>> {
+        (**self).poll_next(cx)
+    }
+}
+
+impl<S: ValueSink + ?Sized> ValueSink for &mut S {
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        (**self).poll_ready(cx)
+    }
+    fn send(&mut self, value: Value) {
+        (**self).send(value)
+    }
+}
+
+/// Values which are all available up front, ends when empty
+impl ValueStream for VecDeque<Value> {
+    fn poll_next(&mut self, _: &mut Context<'_>) -> Poll<Option<Value>> {
+        Poll::Ready(self.pop_front())
+    }
+}
+
+/// Collects everything sent
+impl ValueSink for Vec<Value> {
+    fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<()> {
+        Poll::Ready(())
+    }
+    fn send(&mut self, value: Value) {
+        self.push(value)
+    }
+}
+
+#[derive(Debug, Default)]
+struct Channel {
+    queue: VecDeque<Value>,
+    waker: Option<Waker>,
+    senders: usize,
+}
+
+/// Creates an unbounded single-threaded channel. The receiving end ends once all senders are
+/// dropped and the queued values are taken.
+pub fn channel() -> (Sender, Receiver) {
+    let channel = Rc::new(RefCell::new(Channel {
+        senders: 1,
+        ..Channel::default()
+    }));
+    (
+        Sender {
+            channel: channel.clone(),
+        },
+        Receiver { channel },
+    )
+}
+
+#[derive(Debug)]
+pub struct Sender {
+    channel: Rc<RefCell<Channel>>,
+}
+
+impl Sender {
+    /// Queues `value` and wakes the receiving task. Never waits, the channel is unbounded.
+    pub fn send(&self, value: Value) {
+        let mut channel = self.channel.borrow_mut();
+        channel.queue.push_back(value);
+        if let Some(waker) = channel.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+impl Clone for Sender {
+    fn clone(&self) -> Self {
+        self.channel.borrow_mut().senders += 1;
+        Sender {
+            channel: self.channel.clone(),
+        }
+    }
+}
+
+impl Drop for Sender {
+    fn drop(&mut self) {
+        let mut channel = self.channel.borrow_mut();
+        channel.senders -= 1;
+        if channel.senders == 0 {
+            if let Some(waker) = channel.waker.take() {
+                waker.wake();
+            }
+        }
+    }
+}
+
+impl ValueSink for Sender {
+    fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<()> {
+        Poll::Ready(())
+    }
+    fn send(&mut self, value: Value) {
+        Sender::send(self, value)
+    }
+}
+
+#[derive(Debug)]
+pub struct Receiver {
+    channel: Rc<RefCell<Channel>>,
+}
+
+impl Receiver {
+    /// Waits for the next value, None once all senders are gone
+    pub fn recv(&mut self) -> impl Future<Output = Option<Value>> + '_ {
+        Next(self)
+    }
+    /// The next value if one is queued
+    pub fn try_recv(&mut self) -> Option<Value> {
+        self.channel.borrow_mut().queue.pop_front()
+    }
+}
+
+impl ValueStream for Receiver {
+    fn poll_next(&mut self, cx: &mut Context<'_>) -> Poll<Option<Value>> {
+        let mut channel = self.channel.borrow_mut();
+        match channel.queue.pop_front() {
+            Some(value) => Poll::Ready(Some(value)),
+            None if channel.senders == 0 => Poll::Ready(None),
+            None => {
+                channel.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+struct Next<'a, S: ?Sized>(&'a mut S);
+
+impl<S: ValueStream + ?Sized> Future for Next<'_, S> {
+    type Output = Option<Value>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.get_mut().0.poll_next(cx)
+    }
+}
+
+struct Ready<'a, S: ?Sized>(&'a mut S);
+
+impl<S: ValueSink + ?Sized> Future for Ready<'_, S> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.get_mut().0.poll_ready(cx)
+    }
+}
+
+/// Steps `run_async()` executes before it lets other tasks run
+const YIELD_STEPS: usize = 10_000;
+
+/// Pending once, waking itself so the task is polled again after the others which are ready
+struct YieldNow(bool);
+
+impl Future for YieldNow {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let yielded = &mut self.get_mut().0;
+        if *yielded {
+            return Poll::Ready(());
+        }
+        *yielded = true;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
+
+impl Program {
+    /// Runs the program, taking a value from `input` whenever it needs one and sending every
+    /// output to `output`. Finishes when the program halts, or when it needs input after the
+    /// stream has ended, check `is_halted()` to tell them apart. It yields every `YIELD_STEPS`
+    /// steps, so a machine computing without I/O doesn't keep other tasks from running.
+    pub async fn run_async<I: ValueStream, O: ValueSink>(&mut self, mut input: I, mut output: O) {
+        let mut budget = YIELD_STEPS;
+        loop {
+            while let Some(value) = self.output.pop_front() {
+                Ready(&mut output).await;
+                output.send(value);
+            }
+            if self.is_halted() {
+                return;
+            }
+            if self.needs_input() {
+                match Next(&mut input).await {
+                    Some(value) => self.input.push_back(value),
+                    None => return,
+                }
+            }
+            self.step();
+            budget -= 1;
+            if budget == 0 {
+                YieldNow(false).await;
+                budget = YIELD_STEPS;
+            }
+        }
+    }
+}
+
+type Task<'a> = Pin<Box<dyn Future<Output = ()> + 'a>>;
+
+struct TaskWaker {
+    id: usize,
+    ready: Arc<Mutex<VecDeque<usize>>>,
+}
+
+impl Wake for TaskWaker {
+    fn wake(self: Arc<Self>) {
+        self.ready.lock().unwrap().push_back(self.id);
+    }
+}
+
+/// Polls tasks on the current thread whenever they are woken
+#[derive(Default)]
+pub struct Executor<'a> {
+    tasks: Vec<Option<Task<'a>>>,
+    ready: Arc<Mutex<VecDeque<usize>>>,
+}
+
+impl<'a> Executor<'a> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Adds a task, it runs at the next `run()`
+    pub fn spawn<F: Future<Output = ()> + 'a>(&mut self, future: F) {
+        self.ready.lock().unwrap().push_back(self.tasks.len());
+        self.tasks.push(Some(Box::pin(future)));
+    }
+    /// Runs until no task can make progress and returns the number of unfinished tasks, which
+    /// are waiting for something no other task will provide
+    pub fn run(&mut self) -> usize {
+        loop {
+            let id = match self.ready.lock().unwrap().pop_front() {
+                Some(id) => id,
+                None => break,
+            };
+            let task = match &mut self.tasks[id] {
+                Some(task) => task,
+                None => continue,
+            };
+            let waker = Waker::from(Arc::new(TaskWaker {
+                id,
+                ready: self.ready.clone(),
+            }));
+            if task
+                .as_mut()
+                .poll(&mut Context::from_waker(&waker))
+                .is_ready()
+            {
+                self.tasks[id] = None;
+            }
+        }
+        self.tasks.iter().filter(|task| task.is_some()).count()
+    }
+}
+
+/// Runs a single future to completion on a new `Executor`, None if it got stuck waiting
+pub fn block_on<F: Future>(future: F) -> Option<F::Output> {
+    let mut output = None;
+    let mut executor = Executor::new();
+    executor.spawn(async { output = Some(future.await) });
+    executor.run();
+    drop(executor);
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    // Outputs twice every input, forever
+    const DOUBLER: [Value; 12] = [3, 11, 1002, 11, 2, 11, 4, 11, 1105, 1, 0, 0];
+
+    #[test]
+    fn test_ready_values() {
+        let mut p = Program::new(&DOUBLER);
+        let mut output = Vec::new();
+        block_on(p.run_async(VecDeque::from(vec![1, 2, 3]), &mut output)).unwrap();
+        assert_eq!(output, [2, 4, 6]);
+        assert!(p.needs_input());
+    }
+
+    #[test]
+    fn test_tasks() {
+        // two doublers in a row, fed and drained by tasks
+        let (to_first, first_input) = channel();
+        let (to_second, second_input) = channel();
+        let (to_consumer, mut results) = channel();
+        let mut first = Program::new(&DOUBLER);
+        let mut second = Program::new(&DOUBLER);
+        let mut received = Vec::new();
+
+        let mut executor = Executor::new();
+        executor.spawn(async {
+            while let Some(value) = results.recv().await {
+                received.push(value);
+            }
+        });
+        executor.spawn(first.run_async(first_input, to_second));
+        executor.spawn(second.run_async(second_input, to_consumer));
+        executor.spawn(async move {
+            for value in 1..=3 {
+                to_first.send(value);
+            }
+        });
+        // the producer is done, so the end of its channel shuts everything down
+        assert_eq!(executor.run(), 0);
+        drop(executor);
+        assert_eq!(received, [4, 8, 12]);
+        assert_eq!(first.elapsed(), second.elapsed());
+    }
+
+    #[test]
+    fn test_yield() {
+        // counts down for twice `YIELD_STEPS` steps before halting
+        let image = [1001, 9, -1, 9, 1005, 9, 0, 99, 0, YIELD_STEPS as Value];
+        let mut spinner = Program::new(&image);
+        let spinner_done = Cell::new(false);
+        let finished_first = Cell::new(false);
+
+        let mut executor = Executor::new();
+        executor.spawn(async {
+            spinner.run_async(VecDeque::new(), Vec::new()).await;
+            spinner_done.set(true);
+        });
+        executor.spawn(async { finished_first.set(!spinner_done.get()) });
+        assert_eq!(executor.run(), 0);
+        drop(executor);
+        assert!(finished_first.get());
+        assert_eq!(spinner.elapsed(), 2 * YIELD_STEPS);
+    }
+
+    #[test]
+    fn test_stuck() {
+        let (sender, receiver) = channel();
+        let mut p = Program::new(&DOUBLER);
+        let mut output = Vec::new();
+
+        let mut executor = Executor::new();
+        executor.spawn(p.run_async(receiver, &mut output));
+        sender.send(5);
+        assert_eq!(executor.run(), 1);
+        drop(executor);
+        assert_eq!(output, [10]);
+    }
+}
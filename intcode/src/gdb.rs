@@ -0,0 +1,613 @@
+//! A GDB remote serial protocol stub exposing a `Program` as a debugging target.
+//!
+//! The target has two 64 bit registers, `ip` (0) and `rb` (1). Memory is byte addressed, every
+//! intcode address is mapped to 8 little endian bytes, so address `n` is found at `8 * n`. The
+//! registers hold such byte addresses too, and only multiples of 8 can be written to them.
+//! Input is queued with `monitor input <values>`, output is printed to the debugger console.
+
+use crate::Program;
+use std::collections::HashSet;
+use std::convert::TryFrom;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+
+const CELL_SIZE: usize = 8;
+// Largest packet advertised to the debugger, memory reads are clamped to fit in a reply
+const PACKET_SIZE: usize = 0x4000;
+// How many instructions to run between checks for an interrupt while continuing
+const INTERRUPT_INTERVAL: usize = 10_000;
+
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.intcode.core">
+    <reg name="ip" bitsize="64" type="code_ptr" regnum="0"/>
+    <reg name="rb" bitsize="64" type="data_ptr" regnum="1"/>
+  </feature>
+</target>"#;
+
+/// A byte stream to a debugger which can be polled for a pending interrupt (`0x03`)
+pub trait Connection: Read + Write {
+    fn poll_interrupt(&mut self) -> io::Result<bool> {
+        Ok(false)
+    }
+}
+
+impl Connection for TcpStream {
+    fn poll_interrupt(&mut self) -> io::Result<bool> {
+        let mut byte = [0];
+        self.set_nonblocking(true)?;
+        let result = self.peek(&mut byte);
+        self.set_nonblocking(false)?;
+        match result {
+            Ok(1) if byte[0] == 0x03 => {
+                self.read_exact(&mut byte)?;
+                Ok(true)
+            }
+            Ok(_) => Ok(false),
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+// Why execution stopped, reported to the debugger as a stop reply
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum Stop {
+    Trap,
+    Interrupted,
+    WaitingForInput,
+    /// The next instruction can't be executed, like an unknown opcode written with `M`
+    Illegal,
+    Exited,
+}
+
+pub struct GdbStub {
+    program: Program,
+    breakpoints: HashSet<usize>,
+}
+
+impl GdbStub {
+    pub fn new(program: Program) -> Self {
+        GdbStub {
+            program,
+            breakpoints: HashSet::new(),
+        }
+    }
+    pub fn program(&self) -> &Program {
+        &self.program
+    }
+    /// Accepts a single debugger connection on `addr` and serves it until it detaches
+    pub fn listen<A: ToSocketAddrs>(&mut self, addr: A) -> io::Result<()> {
+        let listener = TcpListener::bind(addr)?;
+        let (stream, _) = listener.accept()?;
+        stream.set_nodelay(true)?;
+        self.serve(stream)
+    }
+    /// Serves the protocol on `connection` until the debugger detaches, kills or disconnects
+    pub fn serve<C: Connection>(&mut self, mut connection: C) -> io::Result<()> {
+        while let Some(packet) = read_packet(&mut connection)? {
+            connection.write_all(b"+")?;
+            match self.handle(&packet, &mut connection)? {
+                Some(reply) => write_packet(&mut connection, &reply)?,
+                None => {
+                    write_packet(&mut connection, "OK")?;
+                    break;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Returns the reply to `packet`, or None if the session should end
+    fn handle<C: Connection>(
+        &mut self,
+        packet: &str,
+        connection: &mut C,
+    ) -> io::Result<Option<String>> {
+        let (command, args) = packet.split_at(packet.chars().next().map_or(0, char::len_utf8));
+        let reply = match command {
+            "?" => self.stop_reply(Stop::Trap),
+            "g" => {
+                let ip = byte_address(self.program.instruction_ptr());
+                let rb = byte_address(self.program.relative_base());
+                format!("{}{}", encode_u64(ip), encode_u64(rb))
+            }
+            "G" => match (
+                decode_u64(args.get(..16)).and_then(cell_address),
+                decode_u64(args.get(16..32)).and_then(cell_address),
+            ) {
+                (Some(ip), Some(rb)) => {
+                    self.program.set_instruction_ptr(ip);
+                    self.program.set_relative_base(rb);
+                    "OK".to_string()
+                }
+                _ => "E01".to_string(),
+            },
+            "p" => match usize::from_str_radix(args, 16) {
+                Ok(0) => encode_u64(byte_address(self.program.instruction_ptr())),
+                Ok(1) => encode_u64(byte_address(self.program.relative_base())),
+                _ => "E01".to_string(),
+            },
+            "P" => {
+                let mut parts = args.splitn(2, '=');
+                let register = parts.next().and_then(|r| usize::from_str_radix(r, 16).ok());
+                match (register, decode_u64(parts.next()).and_then(cell_address)) {
+                    (Some(0), Some(value)) => {
+                        self.program.set_instruction_ptr(value);
+                        "OK".to_string()
+                    }
+                    (Some(1), Some(value)) => {
+                        self.program.set_relative_base(value);
+                        "OK".to_string()
+                    }
+                    _ => "E01".to_string(),
+                }
+            }
+            "m" => match parse_address_length(args) {
+                Some((address, length)) => self
+                    .read_memory(address, length)
+                    .unwrap_or_else(|| "E01".to_string()),
+                None => "E01".to_string(),
+            },
+            "M" => {
+                let mut parts = args.splitn(2, ':');
+                let range = parts.next().and_then(parse_address_length);
+                match (range, parts.next().and_then(decode_hex)) {
+                    (Some((address, length)), Some(bytes)) if bytes.len() == length => {
+                        match self.write_memory(address, &bytes) {
+                            Some(()) => "OK".to_string(),
+                            None => "E01".to_string(),
+                        }
+                    }
+                    _ => "E01".to_string(),
+                }
+            }
+            "Z" | "z" => {
+                let mut parts = args.split(',');
+                let kind = parts.next();
+                let address = parts.next().and_then(|a| usize::from_str_radix(a, 16).ok());
+                match (kind, address) {
+                    (Some("0"), Some(address)) | (Some("1"), Some(address)) => {
+                        let cell = address / CELL_SIZE;
+                        if command == "Z" {
+                            self.breakpoints.insert(cell);
+                        } else {
+                            self.breakpoints.remove(&cell);
+                        }
+                        "OK".to_string()
+                    }
+                    _ => String::new(),
+                }
+            }
+            "s" => {
+                let stop = self.step();
+                self.stop_reply_with_output(stop, connection)?
+            }
+            "c" => {
+                let stop = self.resume(connection)?;
+                self.stop_reply_with_output(stop, connection)?
+            }
+            "H" => "OK".to_string(),
+            "T" => "OK".to_string(),
+            "D" | "k" => return Ok(None),
+            "q" => self.query(args),
+            _ => String::new(),
+        };
+        Ok(Some(reply))
+    }
+
+    fn query(&mut self, args: &str) -> String {
+        if args.starts_with("Supported") {
+            format!(
+                "PacketSize={:x};qXfer:features:read+;swbreak+;hwbreak+",
+                PACKET_SIZE
+            )
+        } else if args == "Attached" {
+            "1".to_string()
+        } else if args == "C" {
+            "QC1".to_string()
+        } else if args == "fThreadInfo" {
+            "m1".to_string()
+        } else if args == "sThreadInfo" {
+            "l".to_string()
+        } else if let Some(range) = args.strip_prefix("Xfer:features:read:target.xml:") {
+            let mut parts = range.split(',');
+            let offset = parts.next().and_then(|o| usize::from_str_radix(o, 16).ok());
+            let length = parts.next().and_then(|l| usize::from_str_radix(l, 16).ok());
+            match (offset, length) {
+                (Some(offset), Some(length)) if offset <= TARGET_XML.len() => {
+                    let end = match offset.checked_add(length) {
+                        Some(end) => end.min(TARGET_XML.len()),
+                        None => return "E01".to_string(),
+                    };
+                    let marker = if end == TARGET_XML.len() { 'l' } else { 'm' };
+                    format!("{}{}", marker, &TARGET_XML[offset..end])
+                }
+                _ => "E01".to_string(),
+            }
+        } else if let Some(command) = args.strip_prefix("Rcmd,") {
+            let command = decode_hex(command)
+                .map(|bytes| String::from_utf8_lossy(&bytes).into_owned())
+                .unwrap_or_default();
+            encode_hex(self.monitor(&command).as_bytes())
+        } else {
+            String::new()
+        }
+    }
+
+    // Handles `monitor` commands, returns the text printed in the debugger
+    fn monitor(&mut self, command: &str) -> String {
+        let mut words = command.split_whitespace();
+        match words.next() {
+            Some("input") => {
+                let values: Result<Vec<_>, _> = words.map(str::parse).collect();
+                match values {
+                    Ok(values) => {
+                        for value in &values {
+                            self.program.set_input(*value);
+                        }
+                        format!("queued {} input value(s)\n", values.len())
+                    }
+                    Err(e) => format!("invalid input: {}\n", e),
+                }
+            }
+            Some("queues") => format!(
+                "input: {:?}\noutput: {:?}\n",
+                self.program.input_queue(),
+                self.program.output_queue()
+            ),
+            Some("elapsed") => format!("{}\n", self.program.elapsed()),
+            _ => "commands: input <values...>, queues, elapsed\n".to_string(),
+        }
+    }
+
+    fn step(&mut self) -> Stop {
+        if self.program.is_halted() {
+            Stop::Exited
+        } else if self.program.needs_input() {
+            Stop::WaitingForInput
+        } else {
+            match self.program.try_step() {
+                Ok(_) => Stop::Trap,
+                Err(_) => Stop::Illegal,
+            }
+        }
+    }
+
+    fn resume<C: Connection>(&mut self, connection: &mut C) -> io::Result<Stop> {
+        // always execute the instruction at a breakpoint we are stopped on
+        let mut stop = self.step();
+        let mut steps = 0;
+        while stop == Stop::Trap {
+            if self.breakpoints.contains(&self.program.instruction_ptr()) {
+                break;
+            }
+            steps += 1;
+            if steps == INTERRUPT_INTERVAL {
+                steps = 0;
+                if connection.poll_interrupt()? {
+                    return Ok(Stop::Interrupted);
+                }
+            }
+            stop = self.step();
+        }
+        Ok(stop)
+    }
+
+    // Sends console output for produced values before the stop reply
+    fn stop_reply_with_output<C: Connection>(
+        &mut self,
+        stop: Stop,
+        connection: &mut C,
+    ) -> io::Result<String> {
+        for value in self.program.take_output() {
+            let line = format!("output: {}\n", value);
+            write_packet(connection, &format!("O{}", encode_hex(line.as_bytes())))?;
+        }
+        if stop == Stop::WaitingForInput {
+            let line = "waiting for input, queue some with `monitor input <values>`\n";
+            write_packet(connection, &format!("O{}", encode_hex(line.as_bytes())))?;
+        }
+        Ok(self.stop_reply(stop))
+    }
+
+    fn stop_reply(&self, stop: Stop) -> String {
+        match stop {
+            Stop::Trap | Stop::WaitingForInput => "S05".to_string(),
+            Stop::Interrupted => "S02".to_string(),
+            Stop::Illegal => "S04".to_string(),
+            Stop::Exited => "W00".to_string(),
+        }
+    }
+
+    // None if the range doesn't fit in the address space. Reads which wouldn't fit in a reply
+    // are cut short, the debugger asks for the rest with another packet.
+    fn read_memory(&self, address: usize, length: usize) -> Option<String> {
+        let length = length.min(PACKET_SIZE / 2);
+        let bytes: Vec<u8> = (address..address.checked_add(length)?)
+            .map(|byte| {
+                let cell = self.program.peek(byte / CELL_SIZE);
+                cell.to_le_bytes()[byte % CELL_SIZE]
+            })
+            .collect();
+        Some(encode_hex(&bytes))
+    }
+
+    // None if the range doesn't fit in the address space
+    fn write_memory(&mut self, address: usize, bytes: &[u8]) -> Option<()> {
+        let end = address.checked_add(bytes.len())?;
+        let first = address / CELL_SIZE;
+        let last = end.saturating_sub(1) / CELL_SIZE;
+        for cell in first..=last {
+            let mut cell_bytes = self.program.peek(cell).to_le_bytes();
+            for (i, cell_byte) in cell_bytes.iter_mut().enumerate() {
+                let byte = cell * CELL_SIZE + i;
+                if byte >= address && byte < end {
+                    *cell_byte = bytes[byte - address];
+                }
+            }
+            self.program.poke(cell, i64::from_le_bytes(cell_bytes));
+        }
+        Some(())
+    }
+}
+
+// Reads the next `$packet#checksum`, skipping acknowledgements. Returns None on disconnect.
+fn read_packet<R: Read>(reader: &mut R) -> io::Result<Option<String>> {
+    let mut byte = [0];
+    loop {
+        if reader.read(&mut byte)? == 0 {
+            return Ok(None);
+        }
+        if byte[0] == b'$' {
+            break;
+        }
+    }
+    let mut packet = Vec::new();
+    loop {
+        if reader.read(&mut byte)? == 0 {
+            return Ok(None);
+        }
+        match byte[0] {
+            b'#' => break,
+            b'}' => {
+                reader.read_exact(&mut byte)?;
+                packet.push(byte[0] ^ 0x20);
+            }
+            b => packet.push(b),
+        }
+    }
+    let mut checksum = [0; 2];
+    reader.read_exact(&mut checksum)?;
+    Ok(Some(String::from_utf8_lossy(&packet).into_owned()))
+}
+
+fn write_packet<W: Write>(writer: &mut W, data: &str) -> io::Result<()> {
+    let checksum = data.bytes().fold(0u8, |sum, b| sum.wrapping_add(b));
+    write!(writer, "${}#{:02x}", data, checksum)?;
+    writer.flush()
+}
+
+fn parse_address_length(args: &str) -> Option<(usize, usize)> {
+    let mut parts = args.splitn(2, ',');
+    let address = usize::from_str_radix(parts.next()?, 16).ok()?;
+    let length = usize::from_str_radix(parts.next()?, 16).ok()?;
+    Some((address, length))
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 == 1 {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+// The byte address of intcode address `cell`
+fn byte_address(cell: usize) -> u64 {
+    (cell as u64).wrapping_mul(CELL_SIZE as u64)
+}
+
+// The intcode address at byte address `byte`, None if it isn't the start of a cell
+fn cell_address(byte: u64) -> Option<usize> {
+    if !byte.is_multiple_of(CELL_SIZE as u64) {
+        return None;
+    }
+    usize::try_from(byte / CELL_SIZE as u64).ok()
+}
+
+// Registers are transferred in target byte order, which is little endian
+fn encode_u64(value: u64) -> String {
+    encode_hex(&value.to_le_bytes())
+}
+
+fn decode_u64(hex: Option<&str>) -> Option<u64> {
+    let bytes = decode_hex(hex?)?;
+    if bytes.len() != 8 {
+        return None;
+    }
+    let mut value = [0; 8];
+    value.copy_from_slice(&bytes);
+    Some(u64::from_le_bytes(value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    struct MockConnection {
+        incoming: Cursor<Vec<u8>>,
+        outgoing: Vec<u8>,
+    }
+
+    impl Read for MockConnection {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.incoming.read(buf)
+        }
+    }
+
+    impl Write for MockConnection {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.outgoing.write(buf)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Connection for MockConnection {}
+
+    impl Connection for &mut MockConnection {}
+
+    // Runs a session and returns the payloads of all packets sent back
+    fn session(stub: &mut GdbStub, packets: &[&str]) -> Vec<String> {
+        let mut incoming = Vec::new();
+        for packet in packets {
+            write_packet(&mut incoming, packet).unwrap();
+        }
+        let mut connection = MockConnection {
+            incoming: Cursor::new(incoming),
+            outgoing: Vec::new(),
+        };
+        stub.serve(&mut connection).unwrap();
+
+        let mut outgoing = Cursor::new(connection.outgoing);
+        let mut replies = Vec::new();
+        while let Some(reply) = read_packet(&mut outgoing).unwrap() {
+            replies.push(reply);
+        }
+        replies
+    }
+
+    #[test]
+    fn test_registers_and_memory() {
+        let mut stub = GdbStub::new(Program::new(&[1101, 1, 2, 5, 99, -1]));
+        let replies = session(
+            &mut stub,
+            &[
+                "?",
+                "g",
+                "m28,8",
+                "M0,8:4e04000000000000",
+                "s",
+                "p0",
+                "m20,8",
+                "P1=1800000000000000",
+                "P1=0300000000000000",
+                "D",
+            ],
+        );
+
+        assert_eq!(
+            replies,
+            [
+                "S05",
+                "00000000000000000000000000000000",
+                "ffffffffffffffff",
+                "OK",
+                "S05",
+                "2000000000000000",
+                "6300000000000000",
+                "OK",
+                "E01",
+                "OK"
+            ]
+        );
+        assert_eq!(stub.program().peek(0), 1102);
+        assert_eq!(stub.program().peek(5), 2);
+        assert_eq!(stub.program().relative_base(), 3);
+    }
+
+    #[test]
+    fn test_breakpoints_and_continue() {
+        // outputs the input twice
+        let mut stub = GdbStub::new(Program::new(&[3, 9, 4, 9, 4, 9, 99, 0, 0, 0]));
+        let input = encode_hex(b"input 7");
+        let replies = session(
+            &mut stub,
+            &[
+                "c",
+                &format!("qRcmd,{}", input),
+                "Z0,20,1",
+                "c",
+                "z0,20,1",
+                "c",
+                "k",
+            ],
+        );
+
+        let console = |text: &str| format!("O{}", encode_hex(text.as_bytes()));
+        assert_eq!(
+            replies,
+            [
+                console("waiting for input, queue some with `monitor input <values>`\n"),
+                "S05".to_string(),
+                encode_hex(b"queued 1 input value(s)\n"),
+                "OK".to_string(),
+                console("output: 7\n"),
+                "S05".to_string(),
+                "OK".to_string(),
+                console("output: 7\n"),
+                "W00".to_string(),
+                "OK".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_break_at_reported_pc() {
+        // counts [7] up forever
+        let mut stub = GdbStub::new(Program::new(&[1001, 7, 1, 7, 1105, 1, 0, 0]));
+        let replies = session(&mut stub, &["s", "p0", "D"]);
+        let pc = decode_u64(Some(&replies[1])).unwrap();
+
+        let breakpoint = format!("Z0,{:x},1", pc);
+        let replies = session(&mut stub, &[&breakpoint, "c", "c", "p0", "D"]);
+        assert_eq!(replies, ["OK", "S05", "S05", encode_u64(pc).as_str(), "OK"]);
+        assert_eq!(stub.program().instruction_ptr(), 4);
+        assert_eq!(stub.program().peek(7), 3);
+    }
+
+    #[test]
+    fn test_address_overflow() {
+        let mut stub = GdbStub::new(Program::new(&[99]));
+        let replies = session(
+            &mut stub,
+            &[
+                "mfffffffffffffff8,10",
+                "Mfffffffffffffff8,10:00000000000000000000000000000000",
+                "qXfer:features:read:target.xml:10,ffffffffffffffff",
+                "D",
+            ],
+        );
+        assert_eq!(replies, ["E01", "E01", "E01", "OK"]);
+    }
+
+    #[test]
+    fn test_limits_and_illegal_instruction() {
+        let mut stub = GdbStub::new(Program::new(&[99]));
+        let replies = session(
+            &mut stub,
+            &["m0,ffffffff", "M0,8:3200000000000000", "c", "s", "D"],
+        );
+        assert_eq!(replies[0].len(), PACKET_SIZE);
+        assert_eq!(replies[1..], ["OK", "S04", "S04", "OK"]);
+        assert_eq!(stub.program().instruction_ptr(), 0);
+    }
+
+    #[test]
+    fn test_target_description() {
+        let mut stub = GdbStub::new(Program::new(&[99]));
+        let replies = session(&mut stub, &["qXfer:features:read:target.xml:0,10", "D"]);
+
+        assert_eq!(replies[0], format!("m{}", &TARGET_XML[..16]));
+    }
+}
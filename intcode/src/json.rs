@@ -0,0 +1,280 @@
+//! Just enough JSON for the debug adapter protocol, the crate has no dependencies
+
+use std::collections::BTreeMap;
+use std::fmt::{Display, Error, Formatter};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    String(String),
+    Array(Vec<Json>),
+    Object(BTreeMap<String, Json>),
+}
+
+impl Json {
+    pub fn object(fields: Vec<(&str, Json)>) -> Json {
+        Json::Object(
+            fields
+                .into_iter()
+                .map(|(key, value)| (key.to_string(), value))
+                .collect(),
+        )
+    }
+    pub fn get(&self, key: &str) -> &Json {
+        match self {
+            Json::Object(fields) => fields.get(key).unwrap_or(&Json::Null),
+            _ => &Json::Null,
+        }
+    }
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Json::String(s) => Some(s),
+            _ => None,
+        }
+    }
+    pub fn as_i64(&self) -> Option<i64> {
+        match self {
+            Json::Int(i) => Some(*i),
+            Json::Float(f) if f.fract() == 0.0 => Some(*f as i64),
+            _ => None,
+        }
+    }
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            Json::Bool(b) => Some(*b),
+            _ => None,
+        }
+    }
+    pub fn as_array(&self) -> &[Json] {
+        match self {
+            Json::Array(values) => values,
+            _ => &[],
+        }
+    }
+    pub fn parse(text: &str) -> Option<Json> {
+        let mut parser = Parser {
+            chars: text.chars().collect(),
+            pos: 0,
+        };
+        let value = parser.value()?;
+        parser.whitespace();
+        if parser.pos == parser.chars.len() {
+            Some(value)
+        } else {
+            None
+        }
+    }
+}
+
+impl From<&str> for Json {
+    fn from(s: &str) -> Self {
+        Json::String(s.to_string())
+    }
+}
+
+impl From<String> for Json {
+    fn from(s: String) -> Self {
+        Json::String(s)
+    }
+}
+
+impl From<i64> for Json {
+    fn from(i: i64) -> Self {
+        Json::Int(i)
+    }
+}
+
+impl From<usize> for Json {
+    fn from(i: usize) -> Self {
+        Json::Int(i as i64)
+    }
+}
+
+impl From<bool> for Json {
+    fn from(b: bool) -> Self {
+        Json::Bool(b)
+    }
+}
+
+impl Display for Json {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        match self {
+            Json::Null => write!(f, "null"),
+            Json::Bool(b) => write!(f, "{}", b),
+            Json::Int(i) => write!(f, "{}", i),
+            Json::Float(x) => write!(f, "{}", x),
+            Json::String(s) => write_string(f, s),
+            Json::Array(values) => {
+                write!(f, "[")?;
+                for (i, value) in values.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}", value)?;
+                }
+                write!(f, "]")
+            }
+            Json::Object(fields) => {
+                write!(f, "{{")?;
+                for (i, (key, value)) in fields.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write_string(f, key)?;
+                    write!(f, ":{}", value)?;
+                }
+                write!(f, "}}")
+            }
+        }
+    }
+}
+
+fn write_string(f: &mut Formatter<'_>, s: &str) -> Result<(), Error> {
+    write!(f, "\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => write!(f, "\\\"")?,
+            '\\' => write!(f, "\\\\")?,
+            '\n' => write!(f, "\\n")?,
+            '\r' => write!(f, "\\r")?,
+            '\t' => write!(f, "\\t")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{}", c)?,
+        }
+    }
+    write!(f, "\"")
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl Parser {
+    fn whitespace(&mut self) {
+        while self.pos < self.chars.len() && self.chars[self.pos].is_whitespace() {
+            self.pos += 1;
+        }
+    }
+    fn next(&mut self) -> Option<char> {
+        let c = *self.chars.get(self.pos)?;
+        self.pos += 1;
+        Some(c)
+    }
+    fn expect(&mut self, word: &str) -> Option<()> {
+        for expected in word.chars() {
+            if self.next()? != expected {
+                return None;
+            }
+        }
+        Some(())
+    }
+    fn value(&mut self) -> Option<Json> {
+        self.whitespace();
+        match *self.chars.get(self.pos)? {
+            'n' => self.expect("null").map(|_| Json::Null),
+            't' => self.expect("true").map(|_| Json::Bool(true)),
+            'f' => self.expect("false").map(|_| Json::Bool(false)),
+            '"' => self.string().map(Json::String),
+            '[' => {
+                self.pos += 1;
+                let mut values = Vec::new();
+                self.whitespace();
+                if self.chars.get(self.pos) == Some(&']') {
+                    self.pos += 1;
+                    return Some(Json::Array(values));
+                }
+                loop {
+                    values.push(self.value()?);
+                    self.whitespace();
+                    match self.next()? {
+                        ',' => continue,
+                        ']' => return Some(Json::Array(values)),
+                        _ => return None,
+                    }
+                }
+            }
+            '{' => {
+                self.pos += 1;
+                let mut fields = BTreeMap::new();
+                self.whitespace();
+                if self.chars.get(self.pos) == Some(&'}') {
+                    self.pos += 1;
+                    return Some(Json::Object(fields));
+                }
+                loop {
+                    self.whitespace();
+                    let key = self.string()?;
+                    self.whitespace();
+                    if self.next()? != ':' {
+                        return None;
+                    }
+                    fields.insert(key, self.value()?);
+                    self.whitespace();
+                    match self.next()? {
+                        ',' => continue,
+                        '}' => return Some(Json::Object(fields)),
+                        _ => return None,
+                    }
+                }
+            }
+            _ => self.number(),
+        }
+    }
+    fn string(&mut self) -> Option<String> {
+        if self.next()? != '"' {
+            return None;
+        }
+        let mut s = String::new();
+        loop {
+            match self.next()? {
+                '"' => return Some(s),
+                '\\' => match self.next()? {
+                    'n' => s.push('\n'),
+                    'r' => s.push('\r'),
+                    't' => s.push('\t'),
+                    'b' => s.push('\u{8}'),
+                    'f' => s.push('\u{c}'),
+                    'u' => {
+                        let hex: String = (0..4).map(|_| self.next()).collect::<Option<_>>()?;
+                        let code = u32::from_str_radix(&hex, 16).ok()?;
+                        s.push(std::char::from_u32(code).unwrap_or('\u{fffd}'));
+                    }
+                    c => s.push(c),
+                },
+                c => s.push(c),
+            }
+        }
+    }
+    fn number(&mut self) -> Option<Json> {
+        let start = self.pos;
+        while self.pos < self.chars.len() && "+-0123456789.eE".contains(self.chars[self.pos]) {
+            self.pos += 1;
+        }
+        let text: String = self.chars[start..self.pos].iter().collect();
+        match text.parse() {
+            Ok(i) => Some(Json::Int(i)),
+            Err(_) => text.parse().ok().map(Json::Float),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_roundtrip() {
+        let text = r#"{"a":[1,-2,3.5,true,null],"b":"x\"y\n","c":{}}"#;
+        let json = Json::parse(text).unwrap();
+
+        assert_eq!(json.get("a").as_array()[1], Json::Int(-2));
+        assert_eq!(json.get("b").as_str(), Some("x\"y\n"));
+        assert_eq!(json.get("missing"), &Json::Null);
+        assert_eq!(json.to_string(), text);
+        assert_eq!(Json::parse("[1, 2"), None);
+    }
+}
@@ -1,34 +1,126 @@
-use std::collections::{HashMap, VecDeque};
+//! An intcode virtual machine and the tools around it.
+//!
+//! The `std` feature is on by default. Without it the crate is `no_std` and only needs `alloc`:
+//! the machine, its extensions, devices and the analyses over images stay, while the tools working
+//! with files, terminals, sockets and threads are left out. This is checked with
+//! `cargo test --no-default-features` on the host, where `no_std` rejects any use of `std` in the
+//! library; building for a target without `std` isn't part of the checks.
+
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
+#[cfg(all(test, not(feature = "std")))]
+extern crate std;
+
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::num::ParseIntError;
+
+pub mod binary;
+pub mod compiler;
+#[cfg(test)]
+mod conformance;
+pub mod cycle;
+#[cfg(feature = "std")]
+pub mod dap;
+mod decode;
+pub mod device;
+pub mod diff;
+pub mod disasm;
+pub mod extension;
+#[cfg(feature = "std")]
+pub mod futures;
+#[cfg(feature = "std")]
+pub mod gdb;
+#[cfg(feature = "std")]
+mod json;
+pub mod link;
+pub mod lint;
+pub mod optimize;
+pub mod outputs;
+pub mod patch;
+pub mod preempt;
+#[cfg(feature = "std")]
+pub mod repl;
+pub mod replay;
+#[cfg(feature = "std")]
+pub mod screen;
+#[cfg(feature = "std")]
+pub mod search;
+pub mod self_modifying;
+pub mod snapshot;
+pub mod stack;
+pub mod taint;
+#[cfg(feature = "std")]
+pub mod tui;
+
+use cycle::LoopDetector;
+use device::Devices;
+use extension::Extensions;
+use patch::Patch;
+use replay::{Event, Recording};
+use self_modifying::SelfModifyingPolicy;
+use stack::CallStack;
 
 pub type Value = i64;
 type Addr = usize;
 
-// TODO make Memory a HashMap
+/// Parses a comma separated image like the puzzle inputs, whitespace is ignored
+pub fn parse_image(text: &str) -> Result<Vec<Value>, ParseIntError> {
+    text.split(',')
+        .map(str::trim)
+        .filter(|value| !value.is_empty())
+        .map(str::parse)
+        .collect()
+}
+
 #[derive(Debug, Clone)]
 pub struct Program {
-    memory: HashMap<Addr, Value>,
+    image: Arc<Vec<Value>>,
+    memory: BTreeMap<Addr, Value>,
     instruction_ptr: Addr,
     relative_base: Addr,
     input: VecDeque<Value>,
     output: VecDeque<Value>,
     elapsed: usize,
+    patches: Vec<Patch>,
+    self_modifying: self_modifying::Tracker,
+    extensions: Extensions,
+    recording: Option<Recording>,
+    call_stack: Option<CallStack>,
+    loops: Option<LoopDetector>,
+    devices: Devices,
 }
 
 impl Program {
     pub fn new(data: &[Value]) -> Self {
         let memory = data.iter().cloned().enumerate().collect();
         Program {
+            image: Arc::new(data.to_vec()),
             memory,
             instruction_ptr: 0,
             relative_base: 0,
             input: VecDeque::new(),
             output: VecDeque::new(),
             elapsed: 0,
+            patches: Vec::new(),
+            self_modifying: Default::default(),
+            extensions: Extensions::new(),
+            recording: None,
+            call_stack: None,
+            loops: None,
+            devices: Devices::default(),
         }
     }
     pub fn set_input(&mut self, value: Value) {
         self.input.push_back(value);
     }
+    /// The original image this program was created from, before any patches or writes
+    pub fn image(&self) -> &[Value] {
+        &self.image
+    }
     // Temporary back compatibility layer, if there are high values in the data this will not work
     // and fill up your Memory
     pub fn dump_memory(&self) -> Vec<Value> {
@@ -36,7 +128,7 @@ impl Program {
             return Vec::new();
         }
         // Memory can't be empty here
-        let max_address = self.memory.keys().max().unwrap();
+        let max_address = self.memory.keys().next_back().unwrap();
         let mut memory = vec![0; *max_address + 1];
 
         for (key, value) in &self.memory {
@@ -46,10 +138,7 @@ impl Program {
     }
     /// `run()` will run the Program until it halts, and return all output generated
     pub fn run(&mut self) -> &VecDeque<Value> {
-        while let Some(steps) = self.execute_instruction() {
-            self.instruction_ptr += steps;
-            self.elapsed += 1;
-        }
+        while self.step() {}
         //        println!("Steps taken: {}", self.elapsed);
         &self.output
     }
@@ -57,9 +146,7 @@ impl Program {
     /// `run_pipe()` will pause execution after every output and return `Some(output)`
     /// When execution terminates as `OpCode::Halt` is reached, `None` is returned
     pub fn run_pipe(&mut self) -> Option<Value> {
-        while let Some(steps) = self.execute_instruction() {
-            self.instruction_ptr += steps;
-            self.elapsed += 1;
+        while self.step() {
             if !self.output.is_empty() {
                 return self.output.pop_front();
             }
@@ -75,12 +162,109 @@ impl Program {
             .expect("Inspecting unknown memory address");
         *value
     }
+    /// Reads memory without panicking, unset addresses read as 0
+    pub fn peek(&self, address: usize) -> Value {
+        self.value_at(address)
+    }
+    pub fn instruction_ptr(&self) -> usize {
+        self.instruction_ptr
+    }
+    pub fn set_instruction_ptr(&mut self, address: usize) {
+        self.instruction_ptr = address;
+        self.reset_loop_detection();
+    }
+    pub fn relative_base(&self) -> usize {
+        self.relative_base
+    }
+    pub fn set_relative_base(&mut self, address: usize) {
+        self.relative_base = address;
+        self.reset_loop_detection();
+    }
+    /// Number of instructions executed so far
+    pub fn elapsed(&self) -> usize {
+        self.elapsed
+    }
+    /// Values queued with `set_input()` which haven't been consumed yet
+    pub fn input_queue(&self) -> &VecDeque<Value> {
+        &self.input
+    }
+    /// Output produced so far which hasn't been taken yet
+    pub fn output_queue(&self) -> &VecDeque<Value> {
+        &self.output
+    }
+    /// Removes and returns all queued output
+    pub fn take_output(&mut self) -> Vec<Value> {
+        self.output.drain(..).collect()
+    }
+    /// Whether the next instruction is `OpCode::Halt`
+    pub fn is_halted(&self) -> bool {
+        self.value_at(self.instruction_ptr) % 100 == 99
+    }
+    /// Whether the next instruction is `OpCode::Input` and no input is queued
+    pub fn needs_input(&self) -> bool {
+        self.input.is_empty() && self.value_at(self.instruction_ptr) % 100 == 3
+    }
+    /// `step()` executes a single instruction and returns false if the program has halted.
+    /// Like `run()` it panics if input is needed but none is queued, check `needs_input()` first.
+    pub fn step(&mut self) -> bool {
+        if self.stuck().is_some() {
+            return false;
+        }
+        let queues = self
+            .loops
+            .as_ref()
+            .map(|_| (self.input.len(), self.output.len()));
+        if self.self_modifying.policy != SelfModifyingPolicy::Ignore {
+            self.track_execution();
+        }
+        let call = self.call_stack.as_ref().map(|_| {
+            let len = self.current_instruction().opcode.len();
+            (self.instruction_ptr, len, self.relative_base)
+        });
+        if let Some(steps) = self.execute_instruction() {
+            self.instruction_ptr += steps;
+            self.elapsed += 1;
+            if let Some((address, len, relative_base)) = call {
+                self.track_call(address, len, relative_base);
+            }
+            if let Some(queues) = queues {
+                self.track_loops(queues);
+            }
+            true
+        } else {
+            false
+        }
+    }
+    /// Like `step()`, but an unknown instruction or one which panics while executing is returned
+    /// as an error instead of unwinding into the caller, so a debugger survives a bad opcode
+    #[cfg(feature = "std")]
+    pub fn try_step(&mut self) -> Result<bool, String> {
+        let value = self.memory.get(&self.instruction_ptr).cloned().unwrap_or(0);
+        if custom_instruction_from_value(value, &self.extensions).is_none()
+            && try_instruction_from_value(value).is_none()
+        {
+            return Err(format!(
+                "Unknown instruction {} at {}",
+                value, self.instruction_ptr
+            ));
+        }
+        std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| self.step())).map_err(|panic| {
+            match (panic.downcast_ref::<String>(), panic.downcast_ref::<&str>()) {
+                (Some(message), _) => message.clone(),
+                (None, Some(message)) => message.to_string(),
+                (None, None) => format!("Instruction at {} failed", self.instruction_ptr),
+            }
+        })
+    }
     fn current_instruction(&self) -> Instruction {
         let instruction = self
             .memory
             .get(&self.instruction_ptr)
             .expect("Instruction pointer at unknown memory address."); //data[self.instruction_ptr];
-        instruction_from_value(*instruction)
+        match custom_instruction_from_value(*instruction, &self.extensions) {
+            Some(custom) => custom,
+            None => instruction_from_value(*instruction),
+        }
     }
     fn execute_instruction(&mut self) -> Option<usize> {
         let instruction = self.current_instruction();
@@ -104,12 +288,20 @@ impl Program {
                 let target_addr = self.address_at(self.instruction_ptr + 1);
 
                 let input = self.input.pop_front().expect("Not enough input provided!");
+                self.record(Event::Input {
+                    step: self.elapsed,
+                    value: input,
+                });
                 self.set(target_addr, input, instruction.parameter_modes[0]);
                 Some(2)
             }
             OpCode::Output => {
                 let value = self.param(1, instruction.parameter_modes[0]);
                 self.output.push_back(value);
+                self.record(Event::Output {
+                    step: self.elapsed,
+                    value,
+                });
                 // println!("Output: {}", value);
                 Some(2)
             }
@@ -162,6 +354,9 @@ impl Program {
                 self.relative_base = (self.relative_base as Value + offset) as usize;
                 Some(2)
             }
+            OpCode::Custom { opcode, .. } => {
+                Some(self.execute_custom(opcode, instruction.parameter_modes))
+            }
         }
     }
     // TODO get ParameterMode(offset) instead
@@ -176,6 +371,31 @@ impl Program {
             ParameterMode::Relative => self.value_at_relative_position(param_addr),
         }
     }
+    /// Address holding parameter `param_pos` of the current instruction, for immediate
+    /// parameters that is the parameter itself
+    pub(crate) fn parameter_address(&self, param_pos: usize, mode: ParameterMode) -> Addr {
+        let param_addr = self.instruction_ptr + param_pos;
+        match mode {
+            ParameterMode::Position => self.value_at(param_addr) as Addr,
+            ParameterMode::Immediate => param_addr,
+            ParameterMode::Relative => {
+                (self.relative_base as Value + self.value_at(param_addr)) as Addr
+            }
+        }
+    }
+    /// The address the current instruction writes to, None if it doesn't write to memory
+    pub(crate) fn write_target(&self) -> Option<Addr> {
+        let instruction = self.current_instruction();
+        let param_pos = match instruction.opcode {
+            OpCode::Add | OpCode::Mul | OpCode::LessThan | OpCode::Equals => 3,
+            OpCode::Input => 1,
+            _ => return None,
+        };
+        match instruction.parameter_modes[param_pos - 1] {
+            ParameterMode::Immediate => None,
+            mode => Some(self.parameter_address(param_pos, mode)),
+        }
+    }
     fn set(&mut self, addr: usize, value: Value, mode: ParameterMode) {
         let dest_addr = match mode {
             ParameterMode::Position => addr,
@@ -183,6 +403,16 @@ impl Program {
             ParameterMode::Relative => (self.relative_base as Value + addr as Value) as usize,
         };
 
+        if !self.devices.is_empty() && self.devices.write(dest_addr, value, self.elapsed) {
+            return;
+        }
+        if self.self_modifying.policy != SelfModifyingPolicy::Ignore {
+            self.check_write(dest_addr, value);
+        }
+        if let Some(loops) = &mut self.loops {
+            let old = self.memory.get(&dest_addr).cloned().unwrap_or(0);
+            loops.write(dest_addr, old, value);
+        }
         self.memory.insert(dest_addr, value);
     }
     fn address_at(&self, addr: usize) -> usize {
@@ -190,17 +420,22 @@ impl Program {
         self.memory[&addr] as usize
     }
     fn value_at(&self, addr: usize) -> Value {
+        if !self.devices.is_empty() {
+            if let Some(value) = self.devices.read(addr, self.elapsed) {
+                return value;
+            }
+        }
         self.memory.get(&addr).cloned().unwrap_or(0)
     }
     fn value_at_position(&self, addr: usize) -> Value {
         let value_address = self.address_at(addr);
-        self.memory.get(&value_address).cloned().unwrap_or(0)
+        self.value_at(value_address)
         //        self.memory[self.address_at(addr)]
     }
     fn value_at_relative_position(&self, addr: usize) -> Value {
         let offset = self.value_at(addr);
         let addr = (self.relative_base as Value + offset) as usize;
-        self.memory.get(&addr).cloned().unwrap_or(0)
+        self.value_at(addr)
     }
 }
 
@@ -216,15 +451,29 @@ enum OpCode {
     LessThan,
     Equals,
     SetRelativeBase,
+    Custom { opcode: Value, parameters: usize },
 }
 
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
-enum ParameterMode {
+pub enum ParameterMode {
     Position,
     Immediate,
     Relative,
 }
 
+impl OpCode {
+    // Length of the instruction including its parameters
+    fn len(self) -> usize {
+        match self {
+            OpCode::Halt => 1,
+            OpCode::Input | OpCode::Output | OpCode::SetRelativeBase => 2,
+            OpCode::JumpIfTrue | OpCode::JumpIfFalse => 3,
+            OpCode::Add | OpCode::Mul | OpCode::LessThan | OpCode::Equals => 4,
+            OpCode::Custom { parameters, .. } => parameters + 1,
+        }
+    }
+}
+
 type ParameterModes = [ParameterMode; 3];
 
 #[derive(Clone, Debug)]
@@ -244,7 +493,40 @@ fn instruction_from_value(value: Value) -> Instruction {
     }
 }
 
+// Like `instruction_from_value()`, but returns None for values which aren't valid instructions
+fn try_instruction_from_value(value: Value) -> Option<Instruction> {
+    if value < 0 {
+        return None;
+    }
+    let opcode = try_opcode_from_value(value % 100)?;
+    let parameter_modes = try_parameter_mode_from_value(value / 100)?;
+    Some(Instruction {
+        opcode,
+        parameter_modes,
+    })
+}
+
+// Custom instructions are looked up by their opcode, built-in opcodes can't be registered
+fn custom_instruction_from_value(value: Value, extensions: &Extensions) -> Option<Instruction> {
+    if extensions.is_empty() || value < 0 {
+        return None;
+    }
+    let custom = extensions.get(&(value % 100))?;
+    Some(Instruction {
+        opcode: OpCode::Custom {
+            opcode: value % 100,
+            parameters: custom.parameters,
+        },
+        parameter_modes: parameter_mode_from_value(value / 100),
+    })
+}
+
 fn parameter_mode_from_value(value: Value) -> ParameterModes {
+    try_parameter_mode_from_value(value)
+        .unwrap_or_else(|| panic!("Unknown parameter mode in: {}", value))
+}
+
+fn try_parameter_mode_from_value(value: Value) -> Option<ParameterModes> {
     let mut remainder = value;
     let mut parameter_modes = [ParameterMode::Position; 3];
     for parameter_mode in &mut parameter_modes {
@@ -256,16 +538,20 @@ fn parameter_mode_from_value(value: Value) -> ParameterModes {
         } else if digit == 0 {
             // nothing to do
         } else {
-            panic!("Unknown parameter mode: {}", digit)
+            return None;
         }
 
         remainder /= 10;
     }
-    parameter_modes
+    Some(parameter_modes)
 }
 
 fn opcode_from_value(value: Value) -> OpCode {
-    match value {
+    try_opcode_from_value(value).unwrap_or_else(|| panic!("Oops, unrecognized opcode: {}", value))
+}
+
+fn try_opcode_from_value(value: Value) -> Option<OpCode> {
+    let opcode = match value {
         1 => OpCode::Add,
         2 => OpCode::Mul,
         3 => OpCode::Input,
@@ -276,8 +562,9 @@ fn opcode_from_value(value: Value) -> OpCode {
         8 => OpCode::Equals,
         9 => OpCode::SetRelativeBase,
         99 => OpCode::Halt,
-        _ => panic!("Oops, unrecognized opcode: {}", value),
-    }
+        _ => return None,
+    };
+    Some(opcode)
 }
 
 #[cfg(test)]
@@ -325,7 +612,7 @@ mod tests {
         let data = vec![0, 1, 2, 3];
         let p = Program::new(&data);
 
-        let mut expected: HashMap<Addr, Value> = HashMap::new();
+        let mut expected: BTreeMap<Addr, Value> = BTreeMap::new();
         expected.insert(0, 0);
         expected.insert(1, 1);
         expected.insert(2, 2);
@@ -490,47 +777,4 @@ mod tests {
         assert_eq!(oeq, OpCode::Equals);
         assert_eq!(osrb, OpCode::SetRelativeBase);
     }
-
-    // Day 2
-    #[test]
-    fn example_1() {
-        let mut p = Program::new(&vec![1, 9, 10, 3, 2, 3, 11, 0, 99, 30, 40, 50]);
-        p.run();
-        //        assert_eq!(p.data, [1, 9, 10, 70, 2, 3, 11, 0, 99, 30, 40, 50]);
-        //        p.run();
-        assert_eq!(
-            p.dump_memory(),
-            [3500, 9, 10, 70, 2, 3, 11, 0, 99, 30, 40, 50]
-        )
-    }
-    #[test]
-    fn example_2() {
-        let mut p = Program::new(&vec![1, 0, 0, 0, 99]);
-        p.run();
-        assert_eq!(p.dump_memory(), [2, 0, 0, 0, 99]);
-    }
-    #[test]
-    fn example_3() {
-        let mut p = Program::new(&vec![1, 0, 0, 0, 99]);
-        p.run();
-        assert_eq!(p.dump_memory(), [2, 0, 0, 0, 99]);
-    }
-    #[test]
-    fn example_4() {
-        let mut p = Program::new(&vec![2, 3, 0, 3, 99]);
-        p.run();
-        assert_eq!(p.dump_memory(), [2, 3, 0, 6, 99]);
-    }
-    #[test]
-    fn example_5() {
-        let mut p = Program::new(&vec![2, 4, 4, 5, 99, 0]);
-        p.run();
-        assert_eq!(p.dump_memory(), [2, 4, 4, 5, 99, 9801]);
-    }
-    #[test]
-    fn example_6() {
-        let mut p = Program::new(&vec![1, 1, 1, 4, 99, 5, 6, 0, 99]);
-        p.run();
-        assert_eq!(p.dump_memory(), [30, 1, 1, 4, 2, 5, 6, 0, 99]);
-    }
 }
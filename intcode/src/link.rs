@@ -0,0 +1,436 @@
+//! Relocatable objects and a linker which combines them into a single image.
+//!
+//! An object is code assembled as if it was loaded at address 0, together with the symbols it
+//! defines and the cells holding addresses which have to move with it:
+//! ```text
+//! # outputs twice its input
+//! code: 3,9,1002,9,2,9,4,9,99,0
+//! symbol: main 0
+//! symbol: value 9
+//! reloc: 1
+//! reloc: 3
+//! reloc: 5
+//! reloc: 7
+//! ```
+//! `reloc: 4` adds the address the object ends up at to the cell at offset 4, `reloc: 4 main`
+//! adds the address of the symbol `main` instead, which may be defined by another object.
+
+use crate::decode::reachable_instructions;
+use crate::extension::Extensions;
+use crate::{parse_image, Addr, OpCode, ParameterMode, Value};
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::fmt::{Display, Error, Formatter};
+#[cfg(feature = "std")]
+use std::fs;
+#[cfg(feature = "std")]
+use std::path::Path;
+
+/// Defined by the linker as the end of the linked image, where free memory starts
+pub const END_SYMBOL: &str = "__end";
+
+/// A cell of an object which holds an address
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Relocation {
+    pub offset: usize,
+    /// The symbol whose address is added to the cell, or None for the object's own address
+    pub symbol: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct Object {
+    pub code: Vec<Value>,
+    /// Names and offsets of the addresses other objects and the symbol map can refer to
+    pub symbols: Vec<(String, usize)>,
+    pub relocations: Vec<Relocation>,
+}
+
+impl Object {
+    pub fn new(code: Vec<Value>) -> Self {
+        Object {
+            code,
+            ..Self::default()
+        }
+    }
+    /// Makes an image relocatable by finding the addresses in its reachable instructions:
+    /// position mode parameters and immediate jump targets which point into the image.
+    /// Addresses past the image, stored as data or only used through jumps via memory are left
+    /// alone.
+    pub fn from_image(image: &[Value]) -> Self {
+        let mut object = Object::new(image.to_vec());
+        let in_image = |value: Value| value >= 0 && (value as usize) < image.len();
+        for (address, instruction) in reachable_instructions(image, &Extensions::new()) {
+            for i in 1..instruction.opcode.len() {
+                let is_address = match instruction.parameter_modes[i - 1] {
+                    ParameterMode::Position => true,
+                    ParameterMode::Immediate => {
+                        i == 2
+                            && (instruction.opcode == OpCode::JumpIfTrue
+                                || instruction.opcode == OpCode::JumpIfFalse)
+                    }
+                    ParameterMode::Relative => false,
+                };
+                if is_address && matches!(image.get(address + i), Some(&v) if in_image(v)) {
+                    object.relocate(address + i, None);
+                }
+            }
+        }
+        object
+    }
+    #[cfg(feature = "std")]
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, LinkError> {
+        let content = fs::read_to_string(path).map_err(|e| LinkError::Io(e.to_string()))?;
+        content.parse()
+    }
+    #[cfg(feature = "std")]
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), LinkError> {
+        fs::write(path, self.to_string()).map_err(|e| LinkError::Io(e.to_string()))
+    }
+    pub fn define(&mut self, name: &str, offset: usize) {
+        self.symbols.push((name.to_string(), offset));
+    }
+    pub fn relocate(&mut self, offset: usize, symbol: Option<&str>) {
+        self.relocations.push(Relocation {
+            offset,
+            symbol: symbol.map(str::to_string),
+        });
+    }
+}
+
+impl Display for Object {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        let code: Vec<_> = self.code.iter().map(Value::to_string).collect();
+        writeln!(f, "code: {}", code.join(","))?;
+        for (name, offset) in &self.symbols {
+            writeln!(f, "symbol: {} {}", name, offset)?;
+        }
+        for relocation in &self.relocations {
+            match &relocation.symbol {
+                Some(symbol) => writeln!(f, "reloc: {} {}", relocation.offset, symbol)?,
+                None => writeln!(f, "reloc: {}", relocation.offset)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl core::str::FromStr for Object {
+    type Err = LinkError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut object = Object::default();
+        for (i, line) in s.lines().enumerate() {
+            let content = line.split('#').next().unwrap().trim();
+            if content.is_empty() {
+                continue;
+            }
+            let mut parts = content.splitn(2, ':');
+            let key = parts.next().unwrap().trim();
+            let value = parts.next().map(str::trim);
+            let words: Vec<_> = value.unwrap_or("").split_whitespace().collect();
+            let valid = match (key, value, &words[..]) {
+                ("code", Some(value), _) => parse_image(value)
+                    .map(|code| object.code.extend(code))
+                    .is_ok(),
+                ("symbol", _, [name, offset]) => offset
+                    .parse()
+                    .map(|offset| object.define(name, offset))
+                    .is_ok(),
+                ("reloc", _, [offset]) => offset
+                    .parse()
+                    .map(|offset| object.relocate(offset, None))
+                    .is_ok(),
+                ("reloc", _, [offset, symbol]) => offset
+                    .parse()
+                    .map(|offset| object.relocate(offset, Some(symbol)))
+                    .is_ok(),
+                _ => false,
+            };
+            if !valid {
+                return Err(LinkError::Parse {
+                    line: i + 1,
+                    content: line.to_string(),
+                });
+            }
+        }
+        Ok(object)
+    }
+}
+
+/// Names for addresses of a linked image
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct SymbolMap {
+    // the first name defined for each address
+    names: BTreeMap<Addr, String>,
+    addresses: BTreeMap<String, Addr>,
+}
+
+impl SymbolMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    #[cfg(feature = "std")]
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, LinkError> {
+        let content = fs::read_to_string(path).map_err(|e| LinkError::Io(e.to_string()))?;
+        content.parse()
+    }
+    #[cfg(feature = "std")]
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), LinkError> {
+        fs::write(path, self.to_string()).map_err(|e| LinkError::Io(e.to_string()))
+    }
+    pub fn insert(&mut self, address: Addr, name: &str) {
+        self.names
+            .entry(address)
+            .or_insert_with(|| name.to_string());
+        self.addresses.insert(name.to_string(), address);
+    }
+    pub fn len(&self) -> usize {
+        self.addresses.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.addresses.is_empty()
+    }
+    pub fn address(&self, name: &str) -> Option<Addr> {
+        self.addresses.get(name).cloned()
+    }
+    /// The closest symbol at or before `address` and the distance to it
+    pub fn lookup(&self, address: Addr) -> Option<(&str, usize)> {
+        self.names
+            .range(..=address)
+            .next_back()
+            .map(|(&start, name)| (name.as_str(), address - start))
+    }
+    /// Formats an address like `main+12`, or as a plain number before the first symbol
+    pub fn format(&self, address: Addr) -> String {
+        match self.lookup(address) {
+            Some((name, 0)) => name.to_string(),
+            Some((name, offset)) => format!("{}+{}", name, offset),
+            None => address.to_string(),
+        }
+    }
+    /// Parses an address formatted by `format()` or given as a number
+    pub fn resolve(&self, text: &str) -> Option<Addr> {
+        let text = text.trim();
+        if let Ok(address) = text.parse() {
+            return Some(address);
+        }
+        let (name, offset) = match text.find('+') {
+            Some(i) => (text[..i].trim(), text[i + 1..].trim().parse().ok()?),
+            None => (text, 0),
+        };
+        self.address(name).map(|address| address + offset)
+    }
+}
+
+/// One symbol per line ordered by address, like `12 main`
+impl Display for SymbolMap {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        let mut symbols: Vec<_> = self.addresses.iter().collect();
+        symbols.sort_by_key(|&(name, address)| (address, name != &self.names[address]));
+        for (name, address) in symbols {
+            writeln!(f, "{} {}", address, name)?;
+        }
+        Ok(())
+    }
+}
+
+impl core::str::FromStr for SymbolMap {
+    type Err = LinkError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut symbols = SymbolMap::new();
+        for (i, line) in s.lines().enumerate() {
+            let content = line.split('#').next().unwrap().trim();
+            if content.is_empty() {
+                continue;
+            }
+            let words: Vec<_> = content.split_whitespace().collect();
+            match words[..] {
+                [address, name] if address.parse::<Addr>().is_ok() => {
+                    symbols.insert(address.parse().unwrap(), name)
+                }
+                _ => {
+                    return Err(LinkError::Parse {
+                        line: i + 1,
+                        content: line.to_string(),
+                    })
+                }
+            }
+        }
+        Ok(symbols)
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum LinkError {
+    Io(String),
+    Parse {
+        line: usize,
+        content: String,
+    },
+    UndefinedSymbol(String),
+    DuplicateSymbol(String),
+    /// A symbol or relocation of the object at `object` lies outside its code
+    OutOfRange {
+        object: usize,
+        offset: usize,
+    },
+}
+
+impl Display for LinkError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        match self {
+            LinkError::Io(e) => write!(f, "Could not access file: {}", e),
+            LinkError::Parse { line, content } => write!(f, "Invalid line {}: {}", line, content),
+            LinkError::UndefinedSymbol(name) => write!(f, "Undefined symbol {}", name),
+            LinkError::DuplicateSymbol(name) => write!(f, "Symbol {} is defined twice", name),
+            LinkError::OutOfRange { object, offset } => {
+                write!(f, "Offset {} is outside of object {}", offset, object)
+            }
+        }
+    }
+}
+
+impl core::error::Error for LinkError {}
+
+/// Places the objects one after the other, starting at address 0, and resolves their
+/// relocations. Returns the image and the addresses of all symbols, including `END_SYMBOL`.
+pub fn link(objects: &[Object]) -> Result<(Vec<Value>, SymbolMap), LinkError> {
+    let mut bases = Vec::with_capacity(objects.len());
+    let mut end = 0;
+    for object in objects {
+        bases.push(end);
+        end += object.code.len();
+    }
+
+    let mut addresses = BTreeMap::new();
+    let mut symbols = SymbolMap::new();
+    let mut define = |name: &str, address| {
+        if addresses.insert(name.to_string(), address).is_some() {
+            return Err(LinkError::DuplicateSymbol(name.to_string()));
+        }
+        symbols.insert(address, name);
+        Ok(())
+    };
+    for (i, object) in objects.iter().enumerate() {
+        for (name, offset) in &object.symbols {
+            if *offset > object.code.len() {
+                return Err(LinkError::OutOfRange {
+                    object: i,
+                    offset: *offset,
+                });
+            }
+            define(name, bases[i] + offset)?;
+        }
+    }
+    define(END_SYMBOL, end)?;
+
+    let mut image = Vec::with_capacity(end);
+    for (i, object) in objects.iter().enumerate() {
+        let mut code = object.code.clone();
+        for relocation in &object.relocations {
+            let address = match &relocation.symbol {
+                Some(symbol) => *addresses
+                    .get(symbol)
+                    .ok_or_else(|| LinkError::UndefinedSymbol(symbol.clone()))?,
+                None => bases[i],
+            };
+            let cell = code
+                .get_mut(relocation.offset)
+                .ok_or(LinkError::OutOfRange {
+                    object: i,
+                    offset: relocation.offset,
+                })?;
+            *cell += address as Value;
+        }
+        image.extend(code);
+    }
+    Ok((image, symbols))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Program;
+    use alloc::vec;
+
+    const DOUBLER: &str = "
+        # outputs twice its input
+        code: 3,9,1002,9,2,9,4,9,99,0
+        symbol: main 0
+        symbol: value 9
+        reloc: 1
+        reloc: 3
+        reloc: 5
+        reloc: 7
+    ";
+
+    #[test]
+    fn test_object_format() {
+        let object: Object = DOUBLER.parse().unwrap();
+        assert_eq!(object.symbols[1], ("value".to_string(), 9));
+        assert_eq!(object.to_string().parse(), Ok(object.clone()));
+        assert_eq!(Object::from_image(&object.code), {
+            let mut inferred = object.clone();
+            inferred.symbols.clear();
+            inferred
+        });
+        assert_eq!(
+            "code: 1,2\nreloc: x".parse::<Object>(),
+            Err(LinkError::Parse {
+                line: 2,
+                content: "reloc: x".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn test_link() {
+        // jumps over to the doubler, which is placed after it
+        let mut start = Object::new(vec![1105, 1, 0, 99]);
+        start.define("start", 0);
+        start.relocate(2, Some("main"));
+        let doubler: Object = DOUBLER.parse().unwrap();
+
+        let (image, symbols) = link(&[start.clone(), doubler.clone()]).unwrap();
+        assert_eq!(
+            image,
+            [1105, 1, 4, 99, 3, 13, 1002, 13, 2, 13, 4, 13, 99, 0]
+        );
+        assert_eq!(symbols.to_string(), "0 start\n4 main\n13 value\n14 __end\n");
+        assert_eq!(symbols.to_string().parse(), Ok(symbols.clone()));
+
+        let mut p = Program::new(&image);
+        p.set_input(21);
+        assert_eq!(p.run_pipe(), Some(42));
+
+        assert_eq!(
+            link(&[start.clone()]),
+            Err(LinkError::UndefinedSymbol("main".to_string()))
+        );
+        assert_eq!(
+            link(&[doubler.clone(), start, doubler]),
+            Err(LinkError::DuplicateSymbol("main".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_symbol_map() {
+        let mut symbols = SymbolMap::new();
+        symbols.insert(10, "main");
+        symbols.insert(25, "fib");
+        symbols.insert(25, "fib_start");
+
+        assert_eq!(symbols.format(5), "5");
+        assert_eq!(symbols.format(10), "main");
+        assert_eq!(symbols.format(22), "main+12");
+        assert_eq!(symbols.format(30), "fib+5");
+        assert_eq!(symbols.lookup(30), Some(("fib", 5)));
+        assert_eq!(symbols.resolve("main+12"), Some(22));
+        assert_eq!(symbols.resolve("fib_start"), Some(25));
+        assert_eq!(symbols.resolve("7"), Some(7));
+        assert_eq!(symbols.resolve("nope"), None);
+    }
+}
@@ -0,0 +1,284 @@
+use crate::decode::successors;
+use crate::extension::Extensions;
+use crate::{
+    custom_instruction_from_value, try_instruction_from_value, try_parameter_mode_from_value, Addr,
+    OpCode, ParameterMode, Program, Value,
+};
+use alloc::collections::BTreeSet;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt::{Display, Error, Formatter};
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd)]
+pub enum Severity {
+    /// Suspicious, but executes
+    Warning,
+    /// Panics inside `Program::run()` when executed
+    Error,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Problem {
+    UnknownOpcode(Value),
+    /// A parameter mode digit other than 0, 1 or 2
+    InvalidParameterMode(Value),
+    /// Non-zero mode digits for parameters the instruction doesn't have
+    UnusedModeDigits(Value),
+    /// The 1-based parameter an instruction writes to is in immediate mode
+    ImmediateWrite {
+        parameter: usize,
+    },
+    /// An immediate jump target before or after the image
+    JumpOutOfImage {
+        target: Value,
+    },
+    /// The parameters of the instruction run past the end of the image
+    Truncated,
+    /// The instruction is the last one of the image and isn't a halt or jump
+    FallsOffEnd,
+    /// Not a valid instruction in the image, but the program writes to it
+    Overwritten,
+}
+
+impl Problem {
+    pub fn severity(&self) -> Severity {
+        match self {
+            Problem::UnusedModeDigits(_) | Problem::Overwritten => Severity::Warning,
+            _ => Severity::Error,
+        }
+    }
+}
+
+impl Display for Problem {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        match self {
+            Problem::UnknownOpcode(value) => write!(f, "unknown opcode {}", value),
+            Problem::InvalidParameterMode(value) => {
+                write!(f, "invalid parameter mode in {}", value)
+            }
+            Problem::UnusedModeDigits(value) => {
+                write!(f, "mode digits for missing parameters in {}", value)
+            }
+            Problem::ImmediateWrite { parameter } => {
+                write!(f, "parameter {} is written to in immediate mode", parameter)
+            }
+            Problem::JumpOutOfImage { target } => {
+                write!(f, "jump target {} is outside the image", target)
+            }
+            Problem::Truncated => write!(f, "instruction is cut off by the end of the image"),
+            Problem::FallsOffEnd => write!(f, "execution runs past the end without halt"),
+            Problem::Overwritten => write!(f, "self-modifying code, can't check what runs here"),
+        }
+    }
+}
+
+/// A problem found at `address`
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Diagnostic {
+    pub address: Addr,
+    pub problem: Problem,
+}
+
+impl Diagnostic {
+    pub fn severity(&self) -> Severity {
+        self.problem.severity()
+    }
+}
+
+/// Formats like `error at 12: unknown opcode 42`
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        let severity = match self.severity() {
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        };
+        write!(f, "{} at {}: {}", severity, self.address, self.problem)
+    }
+}
+
+/// Checks every instruction reachable from address 0. Diagnostics are ordered by address.
+///
+/// Execution is followed through fall-through and immediate jump targets. Conditions read from
+/// cells which no reachable instruction writes to in position mode are taken from the image, so
+/// branches which can never be taken are skipped. Writes in relative mode are assumed not to hit
+/// those cells.
+pub fn lint(image: &[Value]) -> Vec<Diagnostic> {
+    lint_with_extensions(image, &Extensions::new())
+}
+
+fn lint_with_extensions(image: &[Value], extensions: &Extensions) -> Vec<Diagnostic> {
+    // More written cells make more branches reachable, which can add written cells
+    let mut writes = BTreeSet::new();
+    loop {
+        let (diagnostics, found) = check_reachable(image, extensions, &writes);
+        if found == writes {
+            return diagnostics;
+        }
+        writes = found;
+    }
+}
+
+/// Returns the problems found and the cells written to in position mode
+fn check_reachable(
+    image: &[Value],
+    extensions: &Extensions,
+    writes: &BTreeSet<Addr>,
+) -> (Vec<Diagnostic>, BTreeSet<Addr>) {
+    let mut diagnostics = Vec::new();
+    let mut found = BTreeSet::new();
+    let mut visited = BTreeSet::new();
+    let mut pending = vec![0];
+
+    while let Some(address) = pending.pop() {
+        if address >= image.len() || !visited.insert(address) {
+            continue;
+        }
+        let mut report = |problem| diagnostics.push(Diagnostic { address, problem });
+
+        let value = image[address];
+        let valid_modes = try_parameter_mode_from_value(value / 100).is_some();
+        let instruction = match custom_instruction_from_value(value, extensions)
+            .or_else(|| try_instruction_from_value(value))
+        {
+            Some(instruction) if valid_modes => instruction,
+            _ if writes.contains(&address) => {
+                report(Problem::Overwritten);
+                continue;
+            }
+            _ if value < 0 => {
+                report(Problem::UnknownOpcode(value));
+                continue;
+            }
+            _ if !valid_modes => {
+                report(Problem::InvalidParameterMode(value));
+                continue;
+            }
+            _ => {
+                report(Problem::UnknownOpcode(value % 100));
+                continue;
+            }
+        };
+
+        let parameters = instruction.opcode.len() - 1;
+        if value / 100 / 10_i64.pow(parameters as u32) != 0 {
+            report(Problem::UnusedModeDigits(value));
+        }
+        let written = match instruction.opcode {
+            OpCode::Add | OpCode::Mul | OpCode::LessThan | OpCode::Equals => Some(3),
+            OpCode::Input => Some(1),
+            _ => None,
+        };
+        if let Some(parameter) = written {
+            match (
+                instruction.parameter_modes[parameter - 1],
+                image.get(address + parameter),
+            ) {
+                (ParameterMode::Immediate, _) => report(Problem::ImmediateWrite { parameter }),
+                (ParameterMode::Position, Some(&target)) if target >= 0 => {
+                    found.insert(target as Addr);
+                }
+                _ => {}
+            }
+        }
+
+        if address + instruction.opcode.len() > image.len() {
+            report(Problem::Truncated);
+            continue;
+        }
+        let (next, target) =
+            successors(image, address, &instruction, |cell| !writes.contains(&cell));
+        match next {
+            Some(next) if next == image.len() => report(Problem::FallsOffEnd),
+            Some(next) => pending.push(next),
+            None => {}
+        }
+        match target {
+            Some(target) if target < 0 || target as Addr >= image.len() => {
+                report(Problem::JumpOutOfImage { target })
+            }
+            Some(target) => pending.push(target as Addr),
+            None => {}
+        }
+    }
+    diagnostics.sort_by_key(|diagnostic| diagnostic.address);
+    (diagnostics, found)
+}
+
+impl Program {
+    /// Lints the image the program was created from, including its custom opcodes
+    pub fn lint(&self) -> Vec<Diagnostic> {
+        lint_with_extensions(&self.image, &self.extensions)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::string::ToString;
+
+    #[test]
+    fn test_clean_program() {
+        // day 5 example, compares the input to 8
+        let image = [3, 9, 8, 9, 10, 9, 4, 9, 99, -1, 8];
+        assert_eq!(lint(&image), []);
+    }
+
+    #[test]
+    fn test_problems() {
+        let image = [
+            11101, 1, 2, 3, // writes to an immediate
+            1105, 1, 9, // always jumps
+            42, 42, // unreachable, not reported
+            1106, 0, 100, // always jumps out of the image
+        ];
+        let diagnostics: Vec<_> = lint(&image).iter().map(Diagnostic::to_string).collect();
+        assert_eq!(
+            diagnostics,
+            [
+                "error at 0: parameter 3 is written to in immediate mode",
+                "error at 9: jump target 100 is outside the image"
+            ]
+        );
+
+        let image = [1005, 12, 6, 103, 0, 11199, 1006, 12, 10, 34, 1, 2];
+        let diagnostics: Vec<_> = lint(&image).iter().map(Diagnostic::to_string).collect();
+        assert_eq!(
+            diagnostics,
+            [
+                "error at 3: parameter 1 is written to in immediate mode",
+                "warning at 5: mode digits for missing parameters in 11199",
+                "error at 9: unknown opcode 34",
+                "error at 10: instruction is cut off by the end of the image"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_constant_conditions() {
+        // [0] is never written so the first jump is never taken, 11 is patched before it runs
+        let image = [1006, 0, 12, 1101, 0, 99, 11, 1105, 1, 11, 42, 42, 42];
+        assert_eq!(
+            lint(&image),
+            [Diagnostic {
+                address: 11,
+                problem: Problem::Overwritten
+            }]
+        );
+        assert_eq!(lint(&image)[0].severity(), Severity::Warning);
+    }
+
+    #[test]
+    fn test_custom_opcodes() {
+        let mut p = Program::new(&[42, 5]);
+        assert_eq!(p.lint()[0].problem, Problem::UnknownOpcode(42));
+        p.register_opcode(42, "nop", 1, |_| {});
+        assert_eq!(
+            p.lint(),
+            [Diagnostic {
+                address: 0,
+                problem: Problem::FallsOffEnd
+            }]
+        );
+        assert_eq!(p.lint()[0].severity(), Severity::Error);
+    }
+}
@@ -0,0 +1,625 @@
+//! Peephole optimization of images, with a differential runner to check the result.
+//!
+//! Every instruction keeps its address and length, so jumps computed at run time still land on
+//! the same instructions. The optimized image is faster because it executes fewer instructions:
+//! jumps skip over jumps and instructions without effect, and conditions read from cells which
+//! never change are decided up front.
+//!
+//! Like `lint()`, the analysis assumes writes in relative mode don't hit the image, and it assumes
+//! the same for reads: a program which reads its own code through the relative base, like the
+//! day 9 quine's `204,-1`, may read optimized instructions. Code which is written to or read as
+//! data in position mode is left alone. Run `verify()` on some inputs to check the assumptions
+//! hold for a program. Images which are patched before they run, like in
+//! day 2, need to be patched before they are optimized.
+
+use crate::decode::reachable_instructions;
+use crate::extension::Extensions;
+use crate::{try_instruction_from_value, Addr, Instruction, OpCode, ParameterMode, Program, Value};
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt::{Display, Error, Formatter};
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Transform {
+    /// The 1-based parameter read from `cell`, which is never written, is now an immediate
+    Constant {
+        parameter: usize,
+        cell: Addr,
+        value: Value,
+    },
+    /// An add or mul of two immediates now stores `value` directly
+    Folded { value: Value },
+    /// A jump to an unconditional jump or to instructions without effect goes to `new` instead
+    Threaded { old: Addr, new: Addr },
+    /// `count` instructions without effect, starting here, are replaced by a jump to `next`
+    Removed { count: usize, next: Addr },
+}
+
+impl Display for Transform {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        match self {
+            Transform::Constant {
+                parameter,
+                cell,
+                value,
+            } => write!(
+                f,
+                "parameter {} reads constant {} from [{}]",
+                parameter, value, cell
+            ),
+            Transform::Folded { value } => write!(f, "folded to {}", value),
+            Transform::Threaded { old, new } => write!(f, "jump to {} threaded to {}", old, new),
+            Transform::Removed { count: 1, next } => {
+                write!(
+                    f,
+                    "instruction without effect replaced by a jump to {}",
+                    next
+                )
+            }
+            Transform::Removed { count, next } => write!(
+                f,
+                "{} instructions without effect replaced by a jump to {}",
+                count, next
+            ),
+        }
+    }
+}
+
+/// A transform applied to the instruction at `address`
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Rewrite {
+    pub address: Addr,
+    pub transform: Transform,
+}
+
+/// Formats like `at 12: folded to 7`
+impl Display for Rewrite {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        write!(f, "at {}: {}", self.address, self.transform)
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Optimized {
+    pub image: Vec<Value>,
+    /// Everything changed, ordered by address
+    pub rewrites: Vec<Rewrite>,
+}
+
+/// What an instruction does to control flow, once its parameters are known
+#[derive(Debug, Clone, Copy)]
+enum Flow {
+    /// Continues with the next instruction without changing anything
+    NoOp,
+    /// Always jumps to the target
+    Jump(Value),
+}
+
+/// The parameter an instruction writes to, 1-based
+fn written_parameter(opcode: OpCode) -> Option<usize> {
+    match opcode {
+        OpCode::Add | OpCode::Mul | OpCode::LessThan | OpCode::Equals => Some(3),
+        OpCode::Input => Some(1),
+        _ => None,
+    }
+}
+
+fn parameters(
+    image: &[Value],
+    address: Addr,
+    instruction: &Instruction,
+) -> Vec<(ParameterMode, Value)> {
+    (1..instruction.opcode.len())
+        .map(|i| (instruction.parameter_modes[i - 1], image[address + i]))
+        .collect()
+}
+
+/// Cells read and written in position mode by `instructions`
+fn accessed_cells<'a, I>(image: &[Value], instructions: I) -> (BTreeSet<Addr>, BTreeSet<Addr>)
+where
+    I: Iterator<Item = (Addr, &'a Instruction)>,
+{
+    let mut read = BTreeSet::new();
+    let mut written = BTreeSet::new();
+    for (address, instruction) in instructions {
+        let write = written_parameter(instruction.opcode);
+        for (i, (mode, value)) in parameters(image, address, instruction)
+            .into_iter()
+            .enumerate()
+        {
+            if mode != ParameterMode::Position || value < 0 {
+                continue;
+            }
+            if write == Some(i + 1) {
+                written.insert(value as Addr);
+            } else {
+                read.insert(value as Addr);
+            }
+        }
+    }
+    (read, written)
+}
+
+/// Instructions reachable from address 0 when jumps may also fall through, like at the return
+/// sites of calls through computed jumps
+fn fall_through_reachable(image: &[Value]) -> BTreeMap<Addr, Instruction> {
+    let mut instructions = BTreeMap::new();
+    let mut pending = vec![0];
+    while let Some(address) = pending.pop() {
+        if address >= image.len() || instructions.contains_key(&address) {
+            continue;
+        }
+        let instruction = match try_instruction_from_value(image[address]) {
+            Some(instruction) if address + instruction.opcode.len() <= image.len() => instruction,
+            _ => continue,
+        };
+        if instruction.opcode != OpCode::Halt {
+            pending.push(address + instruction.opcode.len());
+        }
+        let target = image[address + instruction.opcode.len() - 1];
+        let is_jump =
+            instruction.opcode == OpCode::JumpIfTrue || instruction.opcode == OpCode::JumpIfFalse;
+        if is_jump && instruction.parameter_modes[1] == ParameterMode::Immediate && target >= 0 {
+            pending.push(target as Addr);
+        }
+        instructions.insert(address, instruction);
+    }
+    instructions
+}
+
+fn flow(image: &[Value], address: Addr, instruction: &Instruction) -> Option<Flow> {
+    let parameters = parameters(image, address, instruction);
+    let is_zero =
+        |(mode, value): (ParameterMode, Value)| mode == ParameterMode::Immediate && value == 0;
+    let is_one =
+        |(mode, value): (ParameterMode, Value)| mode == ParameterMode::Immediate && value == 1;
+    match instruction.opcode {
+        OpCode::JumpIfTrue | OpCode::JumpIfFalse => {
+            let (condition_mode, condition) = parameters[0];
+            if condition_mode != ParameterMode::Immediate {
+                return None;
+            }
+            let jumps = (condition != 0) == (instruction.opcode == OpCode::JumpIfTrue);
+            match parameters[1] {
+                _ if !jumps => Some(Flow::NoOp),
+                (ParameterMode::Immediate, target) => Some(Flow::Jump(target)),
+                _ => None,
+            }
+        }
+        OpCode::Add | OpCode::Mul => {
+            let neutral = if instruction.opcode == OpCode::Add {
+                is_zero
+            } else {
+                is_one
+            };
+            let destination = parameters[2];
+            let unchanged = destination.0 != ParameterMode::Immediate
+                && ((neutral(parameters[0]) && parameters[1] == destination)
+                    || (neutral(parameters[1]) && parameters[0] == destination));
+            if unchanged {
+                Some(Flow::NoOp)
+            } else {
+                None
+            }
+        }
+        _ => None,
+    }
+}
+
+/// Where execution ends up after starting at `address`, following no-ops and jumps
+fn resolve(flows: &BTreeMap<Addr, (usize, Flow)>, address: Addr) -> Addr {
+    let mut address = address;
+    let mut seen = BTreeSet::new();
+    while seen.insert(address) {
+        address = match flows.get(&address) {
+            Some((len, Flow::NoOp)) => address + len,
+            Some((_, Flow::Jump(target))) if *target >= 0 => *target as Addr,
+            _ => break,
+        };
+    }
+    address
+}
+
+/// Optimizes every instruction reachable from address 0, see the module documentation
+pub fn optimize(image: &[Value]) -> Optimized {
+    let reachable: BTreeMap<_, _> = reachable_instructions(image, &Extensions::new())
+        .into_iter()
+        .filter(|(address, instruction)| address + instruction.opcode.len() <= image.len())
+        .collect();
+    let code: BTreeSet<_> = reachable
+        .iter()
+        .flat_map(|(&address, instruction)| address..address + instruction.opcode.len())
+        .collect();
+    // Covers the reachable instructions and code only reached through computed jumps
+    let (read, written) = accessed_cells(
+        image,
+        fall_through_reachable(image)
+            .iter()
+            .map(|(&address, instruction)| (address, instruction)),
+    );
+    let is_constant = |cell: Value| {
+        cell >= 0 && (cell as Addr) < image.len() && !written.contains(&(cell as Addr))
+    };
+    // Instructions which may change or be read as data keep their values
+    let instructions: Vec<_> = reachable
+        .iter()
+        .filter(|(&address, instruction)| {
+            (address..address + instruction.opcode.len())
+                .all(|cell| !read.contains(&cell) && !written.contains(&cell))
+        })
+        .map(|(&address, instruction)| (address, instruction.opcode.len()))
+        .collect();
+
+    let mut optimized = image.to_vec();
+    let mut rewrites = Vec::new();
+    let decode =
+        |image: &[Value], address: Addr| try_instruction_from_value(image[address]).unwrap();
+
+    // Reads of cells which never change become immediates, which may make adds and muls
+    // constant
+    for &(address, len) in &instructions {
+        let instruction = decode(&optimized, address);
+        let write = written_parameter(instruction.opcode);
+        for i in 1..len {
+            let cell = optimized[address + i];
+            if instruction.parameter_modes[i - 1] != ParameterMode::Position
+                || write == Some(i)
+                || !is_constant(cell)
+            {
+                continue;
+            }
+            let value = image[cell as Addr];
+            optimized[address] += 100 * 10_i64.pow(i as u32 - 1);
+            optimized[address + i] = value;
+            let transform = Transform::Constant {
+                parameter: i,
+                cell: cell as Addr,
+                value,
+            };
+            rewrites.push(Rewrite { address, transform });
+        }
+
+        let instruction = decode(&optimized, address);
+        let value = match parameters(&optimized, address, &instruction).as_slice() {
+            [(ParameterMode::Immediate, a), (ParameterMode::Immediate, b), (mode, destination)]
+                if *mode != ParameterMode::Immediate
+                    && !(*mode == ParameterMode::Position
+                        && *destination >= 0
+                        && code.contains(&(*destination as Addr))) =>
+            {
+                // results which overflow are left for the machine to compute
+                let value = match instruction.opcode {
+                    OpCode::Add if *a != 0 && *b != 0 => a.checked_add(*b),
+                    OpCode::Mul => a.checked_mul(*b),
+                    _ => continue,
+                };
+                match value {
+                    Some(value) => value,
+                    None => continue,
+                }
+            }
+            _ => continue,
+        };
+        optimized[address] = optimized[address] / 100 * 100 + 1;
+        optimized[address + 1] = value;
+        optimized[address + 2] = 0;
+        let transform = Transform::Folded { value };
+        rewrites.push(Rewrite { address, transform });
+    }
+
+    // Jumps skip over no-ops and unconditional jumps
+    let flows: BTreeMap<_, _> = instructions
+        .iter()
+        .filter_map(|&(address, len)| {
+            let flow = flow(&optimized, address, &decode(&optimized, address))?;
+            Some((address, (len, flow)))
+        })
+        .collect();
+    for &(address, _) in &instructions {
+        let instruction = decode(&optimized, address);
+        if !(instruction.opcode == OpCode::JumpIfTrue || instruction.opcode == OpCode::JumpIfFalse)
+            || instruction.parameter_modes[1] != ParameterMode::Immediate
+            || matches!(flows.get(&address), Some((_, Flow::NoOp)))
+        {
+            continue;
+        }
+        let old = optimized[address + 2];
+        if old < 0 {
+            continue;
+        }
+        let new = resolve(&flows, old as Addr);
+        if new != old as Addr {
+            optimized[address + 2] = new as Value;
+            let transform = Transform::Threaded {
+                old: old as Addr,
+                new,
+            };
+            rewrites.push(Rewrite { address, transform });
+        }
+    }
+
+    // Runs of no-ops become a jump past them, where that saves steps
+    let mut address = 0;
+    while let Some((&start, &(len, flow))) = flows.range(address..).next() {
+        address = start + len;
+        if let Flow::Jump(_) = flow {
+            continue;
+        }
+        let mut count = 1;
+        while let Some(&(len, Flow::NoOp)) = flows.get(&address) {
+            address += len;
+            count += 1;
+        }
+        let next = resolve(&flows, start);
+        if next == start + len {
+            continue;
+        }
+        optimized[start..start + 3].copy_from_slice(&[1105, 1, next as Value]);
+        let transform = Transform::Removed { count, next };
+        rewrites.push(Rewrite {
+            address: start,
+            transform,
+        });
+    }
+
+    rewrites.sort_by_key(|rewrite| rewrite.address);
+    Optimized {
+        image: optimized,
+        rewrites,
+    }
+}
+
+/// How a run of a program ended
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum End {
+    Halted,
+    /// Waiting for more input than was given
+    NeedsInput,
+    /// Still running when the step limit was reached
+    StepLimit,
+}
+
+/// The observable behavior of a program for one input
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Outcome {
+    pub output: Vec<Value>,
+    pub end: End,
+}
+
+impl Display for Outcome {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        let end = match self.end {
+            End::Halted => "halted",
+            End::NeedsInput => "needs input",
+            End::StepLimit => "still running",
+        };
+        write!(f, "output {:?}, {}", self.output, end)
+    }
+}
+
+/// The same outcome of the original and the optimized image, with the steps they took
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Comparison {
+    pub input: Vec<Value>,
+    pub outcome: Outcome,
+    pub steps: usize,
+    pub optimized_steps: usize,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum VerifyError {
+    /// The original didn't halt or wait for input within the step limit
+    Timeout { input: Vec<Value> },
+    Mismatch {
+        input: Vec<Value>,
+        expected: Outcome,
+        found: Outcome,
+    },
+}
+
+impl Display for VerifyError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        match self {
+            VerifyError::Timeout { input } => {
+                write!(f, "input {:?}: didn't finish within the step limit", input)
+            }
+            VerifyError::Mismatch {
+                input,
+                expected,
+                found,
+            } => write!(
+                f,
+                "input {:?}: expected {}, found {}",
+                input, expected, found
+            ),
+        }
+    }
+}
+
+impl core::error::Error for VerifyError {}
+
+/// Runs `image` on `input` for at most `max_steps`, returning the outcome and the steps taken
+fn run(image: &[Value], input: &[Value], max_steps: usize) -> (Outcome, usize) {
+    let mut program = Program::new(image);
+    for &value in input {
+        program.set_input(value);
+    }
+    let end = loop {
+        if program.is_halted() {
+            break End::Halted;
+        } else if program.needs_input() {
+            break End::NeedsInput;
+        } else if program.elapsed() == max_steps {
+            break End::StepLimit;
+        }
+        program.step();
+    };
+    let outcome = Outcome {
+        output: program.take_output(),
+        end,
+    };
+    (outcome, program.elapsed())
+}
+
+/// Runs `original` and `optimized` on each of `inputs` and checks they produce the same output
+/// and end the same way. The optimized image may take at most as many steps as the original.
+pub fn verify(
+    original: &[Value],
+    optimized: &[Value],
+    inputs: &[Vec<Value>],
+    max_steps: usize,
+) -> Result<Vec<Comparison>, VerifyError> {
+    inputs
+        .iter()
+        .map(|input| {
+            let input = input.clone();
+            let (outcome, steps) = run(original, &input, max_steps);
+            if outcome.end == End::StepLimit {
+                return Err(VerifyError::Timeout { input });
+            }
+            let (found, optimized_steps) = run(optimized, &input, steps);
+            if found != outcome {
+                return Err(VerifyError::Mismatch {
+                    input,
+                    expected: outcome,
+                    found,
+                });
+            }
+            Ok(Comparison {
+                input,
+                outcome,
+                steps,
+                optimized_steps,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::string::{String, ToString};
+
+    fn rewrites(image: &[Value]) -> Vec<String> {
+        optimize(image)
+            .rewrites
+            .iter()
+            .map(Rewrite::to_string)
+            .collect()
+    }
+
+    #[test]
+    fn test_constants() {
+        // adds two cells which are never written, then outputs the sum
+        let image = [1, 9, 10, 11, 4, 11, 99, 0, 0, 2, 3, 0];
+        let optimized = optimize(&image);
+        assert_eq!(optimized.image[..4], [1101, 5, 0, 11]);
+        assert_eq!(
+            rewrites(&image),
+            [
+                "at 0: parameter 1 reads constant 2 from [9]",
+                "at 0: parameter 2 reads constant 3 from [10]",
+                "at 0: folded to 5"
+            ]
+        );
+        let comparisons = verify(&image, &optimized.image, &[vec![]], 100).unwrap();
+        assert_eq!(comparisons[0].outcome.output, [5]);
+
+        // the result location is code, writing an instruction
+        let image = [1101, 2, 2, 5, 1105, 0, 99];
+        assert_eq!(rewrites(&image), Vec::<String>::new());
+    }
+
+    #[test]
+    fn test_jumps() {
+        // [10] is never written, so the condition of the second jump is known
+        let image = [1105, 1, 3, 1005, 10, 7, 99, 104, 1, 99, 1];
+        let optimized = optimize(&image);
+        assert_eq!(
+            rewrites(&image),
+            [
+                "at 0: jump to 3 threaded to 7",
+                "at 3: parameter 1 reads constant 1 from [10]"
+            ]
+        );
+        let comparisons = verify(&image, &optimized.image, &[vec![]], 100).unwrap();
+        assert_eq!(comparisons[0].steps, 3);
+        assert_eq!(comparisons[0].optimized_steps, 2);
+    }
+
+    #[test]
+    fn test_no_ops() {
+        // adds 0 and multiplies by 1 in place, then outputs the unchanged value
+        let image = [1001, 11, 0, 11, 1002, 11, 1, 11, 4, 11, 99, 42];
+        let optimized = optimize(&image);
+        assert_eq!(
+            rewrites(&image),
+            ["at 0: 2 instructions without effect replaced by a jump to 8"]
+        );
+        assert_eq!(optimized.image[..3], [1105, 1, 8]);
+        let comparisons = verify(&image, &optimized.image, &[vec![]], 100).unwrap();
+        assert_eq!(comparisons[0].optimized_steps, 2);
+    }
+
+    #[test]
+    fn test_fold_overflow() {
+        let image = [1102, i64::MAX, 2, 7, 4, 7, 99, 0];
+        assert_eq!(optimize(&image).image, image);
+    }
+
+    #[test]
+    fn test_code_as_data() {
+        // day 9 quine, outputs its own image. Its relative reads aren't detected, it only stays
+        // the same because none of its instructions can be optimized.
+        let image = [
+            109, 1, 204, -1, 1001, 100, 1, 100, 1008, 100, 16, 101, 1006, 101, 0, 99,
+        ];
+        assert_eq!(optimize(&image).image, image);
+
+        // outputs the opcode of the instruction after it through the relative base
+        let image = [109, 4, 204, 0, 4, 7, 99, 42];
+        let optimized = optimize(&image);
+        assert_eq!(optimized.image[4], 104);
+        assert!(verify(&image, &optimized.image, &[vec![]], 100).is_err());
+    }
+
+    #[test]
+    fn test_compiled() {
+        let image = crate::compiler::compile(
+            "fn fib(n) {
+                 if n < 2 { return n; }
+                 return fib(n - 1) + fib(n - 2);
+             }
+             fn main() {
+                 let n = input();
+                 while n > 0 { output(fib(n)); n = input(); }
+             }",
+        )
+        .unwrap();
+        let optimized = optimize(&image);
+        let inputs = [vec![0], vec![1, 0], vec![10, 5, 0], vec![12]];
+        let comparisons = verify(&image, &optimized.image, &inputs, 1_000_000).unwrap();
+        assert_eq!(comparisons[2].outcome.output, [55, 5]);
+        assert_eq!(comparisons[3].outcome.end, End::NeedsInput);
+        assert!(comparisons
+            .iter()
+            .all(|comparison| comparison.optimized_steps <= comparison.steps));
+    }
+
+    #[test]
+    fn test_mismatch() {
+        let image = [3, 0, 4, 0, 99];
+        let changed = [3, 0, 104, 0, 99];
+        assert_eq!(
+            verify(&image, &changed, &[vec![7]], 100)
+                .unwrap_err()
+                .to_string(),
+            "input [7]: expected output [7], halted, found output [0], halted"
+        );
+        assert_eq!(
+            verify(&[1105, 1, 0], &[99], &[vec![]], 100),
+            Err(VerifyError::Timeout { input: vec![] })
+        );
+    }
+}
@@ -0,0 +1,121 @@
+//! Output values as iterators.
+//!
+//! `Program::outputs()` runs the program only as far as needed for the next value, so the caller
+//! can feed input between values. Programs which output commands made of several values are read
+//! with the adapters of `GroupOutputs`:
+//! ```
+//! use intcode::outputs::GroupOutputs;
+//! use intcode::Program;
+//!
+//! // draws two tiles as x, y and tile id
+//! let mut p = Program::new(&[104, 1, 104, 2, 104, 3, 104, 4, 104, 5, 104, 6, 99]);
+//! let tiles: Vec<_> = p.outputs().triples().collect();
+//! assert_eq!(tiles, [(1, 2, 3), (4, 5, 6)]);
+//! ```
+
+use crate::{Program, Value};
+use core::iter::Map;
+
+/// The output of a program, ends when it halts
+#[derive(Debug)]
+pub struct Outputs<'a> {
+    program: &'a mut Program,
+}
+
+impl Iterator for Outputs<'_> {
+    type Item = Value;
+
+    fn next(&mut self) -> Option<Value> {
+        match self.program.output.pop_front() {
+            Some(value) => Some(value),
+            None => self.program.run_pipe(),
+        }
+    }
+}
+
+impl Program {
+    /// Iterates over the output, running the program whenever the next value is needed.
+    /// Like `run_pipe()` it panics if input is needed but none is queued.
+    pub fn outputs(&mut self) -> Outputs<'_> {
+        Outputs { program: self }
+    }
+}
+
+/// Consecutive values of `iter` in groups of `N`, see `GroupOutputs::groups()`
+#[derive(Debug, Clone)]
+pub struct Groups<I, const N: usize> {
+    iter: I,
+}
+
+impl<I: Iterator<Item = Value>, const N: usize> Iterator for Groups<I, N> {
+    type Item = [Value; N];
+
+    fn next(&mut self) -> Option<[Value; N]> {
+        let mut group = [0; N];
+        for value in &mut group {
+            *value = self.iter.next()?;
+        }
+        Some(group)
+    }
+}
+
+pub type Pairs<I> = Map<Groups<I, 2>, fn([Value; 2]) -> (Value, Value)>;
+pub type Triples<I> = Map<Groups<I, 3>, fn([Value; 3]) -> (Value, Value, Value)>;
+
+/// Adapters grouping values, like the commands made of several outputs. They take only as many
+/// values as the groups they yield, and an incomplete group at the end is dropped.
+pub trait GroupOutputs: Iterator<Item = Value> + Sized {
+    fn groups<const N: usize>(self) -> Groups<Self, N> {
+        Groups { iter: self }
+    }
+    fn pairs(self) -> Pairs<Self> {
+        self.groups().map(|[a, b]| (a, b))
+    }
+    fn triples(self) -> Triples<Self> {
+        self.groups().map(|[a, b, c]| (a, b, c))
+    }
+}
+
+impl<I: Iterator<Item = Value>> GroupOutputs for I {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+    use alloc::vec::Vec;
+
+    #[test]
+    fn test_outputs() {
+        // outputs the input and its double, until the input is 0
+        let image = [3, 14, 4, 14, 1002, 14, 2, 14, 4, 14, 1005, 14, 0, 99, 0];
+        let mut p = Program::new(&image);
+        p.set_input(5);
+        assert_eq!(p.outputs().next(), Some(5));
+        p.set_input(7);
+        assert_eq!(p.outputs().pairs().next(), Some((10, 7)));
+        assert_eq!(p.outputs().next(), Some(14));
+        p.set_input(0);
+        assert_eq!(p.outputs().collect::<Vec<_>>(), [0, 0]);
+        assert!(p.is_halted());
+        assert_eq!(p.outputs().next(), None);
+    }
+
+    #[test]
+    fn test_queued_output() {
+        let mut p = Program::new(&[104, 1, 104, 2, 104, 3, 99]);
+        p.step();
+        p.step();
+        assert_eq!(p.outputs().collect::<Vec<_>>(), [1, 2, 3]);
+    }
+
+    #[test]
+    fn test_groups() {
+        let mut p = Program::new(&[104, 1, 104, 2, 104, 3, 104, 4, 104, 5, 99]);
+        let groups: Vec<_> = p.outputs().groups::<2>().collect();
+        assert_eq!(groups, [[1, 2], [3, 4]]);
+        assert!(p.is_halted());
+
+        let triples: Vec<_> = vec![1, 2, 3, 4].into_iter().triples().collect();
+        assert_eq!(triples, [(1, 2, 3)]);
+    }
+}
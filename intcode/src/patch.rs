@@ -0,0 +1,260 @@
+use crate::{Program, Value};
+use alloc::string::{String, ToString};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt::{Display, Error, Formatter};
+use core::ops::Range;
+use core::str::FromStr;
+#[cfg(feature = "std")]
+use std::fs;
+#[cfg(feature = "std")]
+use std::path::Path;
+
+/// Ranges in patch files may cover at most this many addresses, a fill stores every value
+const MAX_FILL: usize = 1 << 20;
+
+/// A patch writes `values` to consecutive addresses starting at `address`
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Patch {
+    pub address: usize,
+    pub values: Vec<Value>,
+}
+
+impl Patch {
+    pub fn poke(address: usize, value: Value) -> Self {
+        Patch {
+            address,
+            values: vec![value],
+        }
+    }
+    pub fn fill(range: Range<usize>, value: Value) -> Self {
+        Patch {
+            address: range.start,
+            values: vec![value; range.len()],
+        }
+    }
+    /// Addresses covered by this patch, None if it runs past the end of the address space
+    pub fn range(&self) -> Option<Range<usize>> {
+        Some(self.address..self.address.checked_add(self.values.len())?)
+    }
+}
+
+impl Display for Patch {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        write!(f, "{}:", self.address)?;
+        for (i, value) in self.values.iter().enumerate() {
+            let separator = if i == 0 { " " } else { ", " };
+            write!(f, "{}{}", separator, value)?;
+        }
+        Ok(())
+    }
+}
+
+/// A list of patches, usually loaded from a patch file.
+///
+/// Patch files contain one patch per line, `#` starts a comment:
+/// ```text
+/// # restore the gravity assist program to the "1202 program alarm" state
+/// 1: 12, 2
+/// # zero out a range, the end is exclusive
+/// 100..110: 0
+/// ```
+/// Ranges are limited to `2^20` addresses.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct PatchSet {
+    patches: Vec<Patch>,
+}
+
+impl PatchSet {
+    pub fn new() -> Self {
+        PatchSet::default()
+    }
+    #[cfg(feature = "std")]
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, PatchError> {
+        let content = fs::read_to_string(path).map_err(|e| PatchError::Io(e.to_string()))?;
+        content.parse()
+    }
+    pub fn push(&mut self, patch: Patch) {
+        self.patches.push(patch)
+    }
+    pub fn patches(&self) -> &[Patch] {
+        &self.patches
+    }
+}
+
+impl Display for PatchSet {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        for patch in &self.patches {
+            writeln!(f, "{}", patch)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for PatchSet {
+    type Err = PatchError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut patch_set = PatchSet::new();
+        for (i, line) in s.lines().enumerate() {
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let patch = parse_patch(line).ok_or_else(|| PatchError::Parse {
+                line: i + 1,
+                content: line.to_string(),
+            })?;
+            patch_set.push(patch);
+        }
+        Ok(patch_set)
+    }
+}
+
+fn parse_patch(line: &str) -> Option<Patch> {
+    let mut parts = line.splitn(2, ':');
+    let target = parts.next()?.trim();
+    let values = parts
+        .next()?
+        .split(',')
+        .map(|v| v.trim().parse())
+        .collect::<Result<Vec<Value>, _>>()
+        .ok()?;
+
+    if let Some(separator) = target.find("..") {
+        let start = target[..separator].trim().parse().ok()?;
+        let end = target[separator + 2..].trim().parse().ok()?;
+        match values.as_slice() {
+            [value] if start <= end && end - start <= MAX_FILL => {
+                Some(Patch::fill(start..end, *value))
+            }
+            _ => None,
+        }
+    } else {
+        let address = target.parse().ok()?;
+        Some(Patch { address, values }).filter(|patch| patch.range().is_some())
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum PatchError {
+    Io(String),
+    Parse { line: usize, content: String },
+}
+
+impl Display for PatchError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        match self {
+            PatchError::Io(e) => write!(f, "Could not read patch file: {}", e),
+            PatchError::Parse { line, content } => {
+                write!(f, "Invalid patch on line {}: {}", line, content)
+            }
+        }
+    }
+}
+
+impl core::error::Error for PatchError {}
+
+impl Program {
+    /// Write `value` to `address`
+    pub fn poke(&mut self, address: usize, value: Value) {
+        self.apply_patch(Patch::poke(address, value))
+    }
+    /// Write `value` to every address in `range`
+    pub fn fill(&mut self, range: Range<usize>, value: Value) {
+        self.apply_patch(Patch::fill(range, value))
+    }
+    /// Apply `patch` to memory and record it in the patch history. Panics if the patch runs past
+    /// the end of the address space.
+    pub fn apply_patch(&mut self, patch: Patch) {
+        let range = patch
+            .range()
+            .expect("The patch runs past the end of the address space");
+        for (address, value) in range.zip(&patch.values) {
+            self.memory.insert(address, *value);
+        }
+        self.patches.push(patch);
+        self.reset_loop_detection();
+    }
+    pub fn apply_patch_set(&mut self, patch_set: &PatchSet) {
+        for patch in patch_set.patches() {
+            self.apply_patch(patch.clone())
+        }
+    }
+    /// All patches applied since the program was created from its image, in order
+    pub fn patches(&self) -> &[Patch] {
+        &self.patches
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::format;
+
+    #[test]
+    fn test_poke_and_fill() {
+        let mut p = Program::new(&[1, 0, 0, 0, 99]);
+        p.poke(1, 4);
+        p.fill(2..4, 5);
+        p.poke(6, 1);
+
+        assert_eq!(p.dump_memory(), [1, 4, 5, 5, 99, 0, 1]);
+        assert_eq!(
+            p.patches(),
+            [
+                Patch::poke(1, 4),
+                Patch {
+                    address: 2,
+                    values: vec![5, 5]
+                },
+                Patch::poke(6, 1)
+            ]
+        );
+        p.run();
+        assert_eq!(p.inspect(5), 99);
+    }
+
+    #[test]
+    fn test_parse_patch_set() {
+        let text = "# day 2\n1: 12, 2\n\n10..13: -1 # fill\n";
+        let patch_set: PatchSet = text.parse().unwrap();
+
+        assert_eq!(
+            patch_set.patches(),
+            [
+                Patch {
+                    address: 1,
+                    values: vec![12, 2]
+                },
+                Patch::fill(10..13, -1)
+            ]
+        );
+        assert_eq!(patch_set.to_string(), "1: 12, 2\n10: -1, -1, -1\n");
+        assert_eq!(patch_set.to_string().parse(), Ok(patch_set));
+    }
+
+    #[test]
+    fn test_parse_patch_set_errors() {
+        let past_end = format!("{}: 1, 2", usize::MAX);
+        let cases = vec![
+            "1 12",
+            "a: 1",
+            "1: x",
+            "3..1: 0",
+            "1..3: 0, 1",
+            "0..99999999999999: 0",
+            &past_end,
+        ];
+        for case in cases {
+            let result: Result<PatchSet, _> = format!("0: 1\n{}", case).parse();
+            assert_eq!(
+                result,
+                Err(PatchError::Parse {
+                    line: 2,
+                    content: case.to_string()
+                })
+            );
+        }
+    }
+}
@@ -0,0 +1,364 @@
+//! Preemption: running a program for a slice of steps, or until an interrupt is raised.
+//!
+//! `run_pipe()` only returns on output, so a machine which computes for a long time without
+//! output keeps every other machine waiting. `Program::run_for()` returns after a given number of
+//! steps or once an `Interrupt` is raised, always between two instructions, so the program
+//! continues exactly where it stopped the next time it runs. The `Scheduler` builds on it to run
+//! machines connected like the day 7 amplifiers in turns:
+//! ```
+//! use intcode::preempt::{Scheduler, Stop};
+//! use intcode::Program;
+//!
+//! let image = [
+//!     3, 26, 1001, 26, -4, 26, 3, 27, 1002, 27, 2, 27, 1, 27, 26, 27, 4, 27, 1001, 28, -1, 28,
+//!     1005, 28, 6, 99, 0, 0, 5,
+//! ];
+//! let phases = [9, 8, 7, 6, 5];
+//! let mut scheduler = Scheduler::new(10);
+//! for &phase in &phases {
+//!     let mut amplifier = Program::new(&image);
+//!     amplifier.set_input(phase);
+//!     scheduler.spawn(amplifier);
+//! }
+//! for i in 0..phases.len() - 1 {
+//!     scheduler.connect(i, i + 1);
+//! }
+//! scheduler.machine_mut(0).set_input(0);
+//! // the last amplifier feeds the first, until they halt and its output is left over
+//! scheduler.connect(4, 0);
+//! assert_eq!(scheduler.run(), Stop::Halted);
+//! assert_eq!(scheduler.machines()[0].input_queue(), &[139629729]);
+//! ```
+
+use crate::Program;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+/// Why `Program::run_for()` or `Scheduler::run()` returned
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Stop {
+    Halted,
+    /// The program is stuck in a loop found by `Program::detect_loops()`, see `stuck()`
+    Stuck,
+    /// The next instruction reads input and none is queued
+    NeedsInput,
+    Preempted(Preempted),
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Reason {
+    /// The slice of steps ran out
+    Timer,
+    /// The interrupt was raised
+    Interrupt,
+}
+
+/// Where a program was preempted. It stopped between two instructions with its memory, registers
+/// and queues intact, so running it again continues with the instruction at `instruction_ptr`.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Preempted {
+    pub reason: Reason,
+    pub instruction_ptr: usize,
+    pub relative_base: usize,
+    /// Steps executed since `run_for()` was called
+    pub steps: usize,
+}
+
+/// A flag which preempts programs running with it. Clones share the flag, so it can be raised
+/// from another thread. It stays raised until a program is preempted by it.
+#[derive(Debug, Clone, Default)]
+pub struct Interrupt(Arc<AtomicBool>);
+
+impl Interrupt {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn raise(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+    pub fn is_raised(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+    /// Lowers the flag, returns whether it was raised
+    fn acknowledge(&self) -> bool {
+        self.is_raised() && self.0.swap(false, Ordering::Relaxed)
+    }
+}
+
+impl Program {
+    /// Runs at most `steps` instructions, stopping early when the program halts, needs input
+    /// which isn't queued, or `interrupt` is raised. Output is queued instead of returned, take
+    /// it with `take_output()`.
+    pub fn run_for(&mut self, steps: usize, interrupt: Option<&Interrupt>) -> Stop {
+        let start = self.elapsed;
+        loop {
+            if self.is_halted() {
+                return Stop::Halted;
+            }
+            if self.stuck().is_some() {
+                return Stop::Stuck;
+            }
+            if self.needs_input() {
+                return Stop::NeedsInput;
+            }
+            let reason = if interrupt.is_some_and(Interrupt::acknowledge) {
+                Reason::Interrupt
+            } else if self.elapsed - start >= steps {
+                Reason::Timer
+            } else {
+                self.step();
+                continue;
+            };
+            return Stop::Preempted(Preempted {
+                reason,
+                instruction_ptr: self.instruction_ptr,
+                relative_base: self.relative_base,
+                steps: self.elapsed - start,
+            });
+        }
+    }
+}
+
+/// Runs machines in turns of at most `quantum` steps, so one which never outputs can't starve
+/// the others. Output of a machine goes to the input of the machine it is connected to, or stays
+/// in its output queue.
+#[derive(Debug, Clone)]
+pub struct Scheduler {
+    machines: Vec<Program>,
+    routes: Vec<Option<usize>>,
+    quantum: usize,
+    interrupt: Interrupt,
+    /// The machine whose turn is next
+    next: usize,
+}
+
+impl Scheduler {
+    /// Panics if `quantum` is 0, no machine could ever make progress
+    pub fn new(quantum: usize) -> Self {
+        assert!(quantum > 0, "The quantum must be at least one step");
+        Scheduler {
+            machines: Vec::new(),
+            routes: Vec::new(),
+            quantum,
+            interrupt: Interrupt::new(),
+            next: 0,
+        }
+    }
+    /// Adds a machine and returns its id, ids count up from 0
+    pub fn spawn(&mut self, program: Program) -> usize {
+        self.machines.push(program);
+        self.routes.push(None);
+        self.machines.len() - 1
+    }
+    /// Sends the output of machine `from` to the input of machine `to`
+    pub fn connect(&mut self, from: usize, to: usize) {
+        assert!(to < self.machines.len(), "No machine {}", to);
+        self.routes[from] = Some(to);
+    }
+    /// A handle which stops `run()`, also from another thread
+    pub fn interrupt(&self) -> Interrupt {
+        self.interrupt.clone()
+    }
+    pub fn machines(&self) -> &[Program] {
+        &self.machines
+    }
+    pub fn machine_mut(&mut self, id: usize) -> &mut Program {
+        &mut self.machines[id]
+    }
+    /// Runs the machine whose turn it is for at most `quantum` steps and passes on its output.
+    /// Without machines there is nothing to run and it returns `Stop::Halted`.
+    pub fn turn(&mut self) -> Stop {
+        if self.machines.is_empty() {
+            return Stop::Halted;
+        }
+        let id = self.next;
+        self.next = (id + 1) % self.machines.len();
+        let stop = self.machines[id].run_for(self.quantum, Some(&self.interrupt));
+        if let Some(to) = self.routes[id] {
+            for value in self.machines[id].take_output() {
+                self.machines[to].set_input(value);
+            }
+        }
+        stop
+    }
+    /// Runs the machines in turns until none of them can make progress: `Stop::Halted` if all
+    /// halted, `Stop::Stuck` if some are stuck in loops, otherwise `Stop::NeedsInput` as some
+    /// wait for input no machine provides. When the interrupt is raised it returns the machine's
+    /// preemption right away, running again continues with the next turn.
+    pub fn run(&mut self) -> Stop {
+        let mut idle = 0;
+        while idle < self.machines.len() {
+            let id = self.next;
+            let elapsed = self.machines[id].elapsed();
+            let stop = self.turn();
+            idle = if self.machines[id].elapsed() == elapsed {
+                idle + 1
+            } else {
+                0
+            };
+            if let Stop::Preempted(Preempted {
+                reason: Reason::Interrupt,
+                ..
+            }) = stop
+            {
+                return stop;
+            }
+        }
+        if self.machines.iter().all(|machine| machine.is_halted()) {
+            Stop::Halted
+        } else if self
+            .machines
+            .iter()
+            .any(|machine| machine.stuck().is_some())
+        {
+            Stop::Stuck
+        } else {
+            Stop::NeedsInput
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    // Counts [12] up forever without output
+    const SPIN: [i64; 13] = [1001, 12, 1, 12, 1105, 1, 0, 0, 0, 0, 0, 0, 0];
+
+    #[test]
+    fn test_timer() {
+        // outputs 1, 2 and 3
+        let image = [104, 1, 104, 2, 104, 3, 99];
+        let mut p = Program::new(&image);
+        let stop = p.run_for(2, None);
+        assert_eq!(
+            stop,
+            Stop::Preempted(Preempted {
+                reason: Reason::Timer,
+                instruction_ptr: 4,
+                relative_base: 0,
+                steps: 2,
+            })
+        );
+        assert_eq!(p.take_output(), [1, 2]);
+        assert_eq!(p.run_for(2, None), Stop::Halted);
+        assert_eq!(p.take_output(), [3]);
+        assert_eq!(p.elapsed(), 3);
+
+        let mut p = Program::new(&[3, 0, 99]);
+        assert_eq!(p.run_for(10, None), Stop::NeedsInput);
+    }
+
+    #[test]
+    fn test_resume_exactly() {
+        // day 9 quine, preempted after every step
+        let image = [
+            109, 1, 204, -1, 1001, 100, 1, 100, 1008, 100, 16, 101, 1006, 101, 0, 99,
+        ];
+        let mut whole = Program::new(&image);
+        whole.run();
+        let mut sliced = Program::new(&image);
+        while let Stop::Preempted(preempted) = sliced.run_for(1, None) {
+            assert_eq!(preempted.steps, 1);
+            assert_eq!(preempted.instruction_ptr, sliced.instruction_ptr());
+        }
+        assert_eq!(sliced.take_output(), image);
+        assert_eq!(sliced.elapsed(), whole.elapsed());
+        assert_eq!(sliced.dump_memory(), whole.dump_memory());
+    }
+
+    #[test]
+    fn test_interrupt() {
+        let interrupt = Interrupt::new();
+        let mut p = Program::new(&SPIN);
+        p.run_for(5, Some(&interrupt));
+        interrupt.raise();
+        match p.run_for(usize::MAX, Some(&interrupt)) {
+            Stop::Preempted(preempted) => {
+                assert_eq!(preempted.reason, Reason::Interrupt);
+                assert_eq!(preempted.steps, 0);
+            }
+            stop => panic!("{:?}", stop),
+        }
+        assert!(!interrupt.is_raised());
+    }
+
+    #[test]
+    fn test_interrupt_from_thread() {
+        let interrupt = Interrupt::new();
+        let raise = interrupt.clone();
+        let mut p = Program::new(&SPIN);
+        let thread = std::thread::spawn(move || raise.raise());
+        let stop = p.run_for(usize::MAX, Some(&interrupt));
+        thread.join().unwrap();
+        assert!(matches!(
+            stop,
+            Stop::Preempted(Preempted {
+                reason: Reason::Interrupt,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_no_starvation() {
+        // a machine spinning forever doesn't keep one which halts from running
+        let mut scheduler = Scheduler::new(100);
+        let spin = scheduler.spawn(Program::new(&SPIN));
+        let mut echo = Program::new(&[3, 9, 4, 9, 1105, 1, 0, 0, 0, 0]);
+        echo.set_input(5);
+        let echo = scheduler.spawn(echo);
+        let printer = scheduler.spawn(Program::new(&[3, 5, 4, 5, 99, 0]));
+        scheduler.connect(echo, printer);
+
+        let mut turns = 0;
+        while !scheduler.machines()[printer].is_halted() {
+            scheduler.turn();
+            turns += 1;
+        }
+        assert_eq!(turns, 3);
+        assert_eq!(scheduler.machine_mut(printer).take_output(), [5]);
+        assert_eq!(scheduler.machines()[spin].elapsed(), 100);
+    }
+
+    #[test]
+    fn test_deadlock() {
+        let mut scheduler = Scheduler::new(3);
+        let a = scheduler.spawn(Program::new(&[3, 0, 4, 0, 99]));
+        let b = scheduler.spawn(Program::new(&[3, 0, 4, 0, 99]));
+        scheduler.connect(a, b);
+        scheduler.connect(b, a);
+        assert_eq!(scheduler.run(), Stop::NeedsInput);
+
+        scheduler.machine_mut(a).set_input(7);
+        assert_eq!(scheduler.run(), Stop::Halted);
+        assert_eq!(scheduler.machines()[a].input_queue(), &vec![7]);
+    }
+
+    #[test]
+    fn test_stuck() {
+        let mut spin = Program::new(&[1105, 1, 0]);
+        spin.detect_loops();
+        assert_eq!(spin.run_for(100, None), Stop::Stuck);
+
+        let mut scheduler = Scheduler::new(10);
+        scheduler.spawn(spin);
+        scheduler.spawn(Program::new(&[3, 0, 99]));
+        assert_eq!(scheduler.run(), Stop::Stuck);
+    }
+
+    #[test]
+    fn test_no_machines() {
+        let mut scheduler = Scheduler::new(10);
+        assert_eq!(scheduler.turn(), Stop::Halted);
+        assert_eq!(scheduler.run(), Stop::Halted);
+    }
+
+    #[test]
+    #[should_panic(expected = "at least one step")]
+    fn test_zero_quantum() {
+        Scheduler::new(0);
+    }
+}
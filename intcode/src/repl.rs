@@ -0,0 +1,520 @@
+use crate::link::SymbolMap;
+use crate::patch::Patch;
+use crate::snapshot::Snapshot;
+use crate::{parse_image, Program, Value};
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::Path;
+
+// Steps `run` and `next` execute before returning to the prompt, unless changed with `limit`
+const STEP_LIMIT: usize = 10_000_000;
+// Most values, instructions or steps `peek`, `dis` and `step` show at once
+const MAX_COUNT: usize = 10_000;
+
+const HELP: &str = "\
+load <path>            load an image, replacing the current machine, and the symbol map
+                       with the same name ending in .map if there is one
+syms <path>            load a symbol map, addresses can then be given like main+12
+in <values>            queue input values, separated by commas or spaces
+ascii <text>           queue a line of text as ASCII codes followed by a newline
+run                    run until halted, waiting for input or at a breakpoint
+next                   like run, but also stop after the next output
+limit [n]              show or set how many steps run and next execute at most
+step [n]               execute n instructions (default 1) and show them
+break <addr>           set a breakpoint
+delete <addr>          remove a breakpoint
+breaks                 list breakpoints
+peek <addr> [count]    show memory
+poke <addr> <values>   write consecutive values to memory
+regs                   show instruction pointer, relative base and queues
+bt                     show the call stack, from calls through the relative base
+dis [addr] [count]     disassemble, by default at the instruction pointer
+save <path>            save the machine state
+restore <path>         restore a machine state saved with save
+history                show previous commands
+quit                   leave";
+
+/// Line based command interpreter to explore a program by hand.
+///
+/// Every command returns the text to show, so the terminal loop lives in the `intcode-repl`
+/// binary and the commands can be tested here.
+#[derive(Debug)]
+pub struct Repl {
+    program: Option<Program>,
+    breakpoints: BTreeSet<usize>,
+    symbols: SymbolMap,
+    history: Vec<String>,
+    step_limit: usize,
+}
+
+impl Default for Repl {
+    fn default() -> Self {
+        Repl {
+            program: None,
+            breakpoints: BTreeSet::new(),
+            symbols: SymbolMap::default(),
+            history: Vec::new(),
+            step_limit: STEP_LIMIT,
+        }
+    }
+}
+
+impl Repl {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Starts a session with the commands of an earlier one
+    pub fn with_history(history: Vec<String>) -> Self {
+        Repl {
+            history,
+            ..Self::default()
+        }
+    }
+    pub fn program(&self) -> Option<&Program> {
+        self.program.as_ref()
+    }
+    pub fn history(&self) -> &[String] {
+        &self.history
+    }
+    /// Executes one command line and returns its output
+    pub fn execute(&mut self, line: &str) -> String {
+        let line = line.trim();
+        if line.is_empty() {
+            return String::new();
+        }
+        self.history.push(line.to_string());
+
+        let (command, args) = match line.find(char::is_whitespace) {
+            Some(i) => (&line[..i], line[i..].trim()),
+            None => (line, ""),
+        };
+        match command {
+            "help" | "?" => HELP.to_string(),
+            "history" => self
+                .history
+                .iter()
+                .enumerate()
+                .map(|(i, command)| format!("{:4}  {}", i + 1, command))
+                .collect::<Vec<_>>()
+                .join("\n"),
+            "load" => self.load(args),
+            "syms" => match SymbolMap::load(args) {
+                Ok(symbols) => {
+                    self.symbols = symbols;
+                    format!("Loaded {} symbols from {}", self.symbols.len(), args)
+                }
+                Err(e) => e.to_string(),
+            },
+            "restore" => match Snapshot::load(args) {
+                Ok(snapshot) => {
+                    let mut program = Program::from_snapshot(&snapshot);
+                    program.track_calls();
+                    self.program = Some(program);
+                    format!("Restored {}", args)
+                }
+                Err(e) => e.to_string(),
+            },
+            "break" | "b" => match self.parse_address(args) {
+                Some(address) => {
+                    self.breakpoints.insert(address);
+                    format!("Breakpoint at {}", self.symbols.format(address))
+                }
+                None => format!("Invalid address: {}", args),
+            },
+            "delete" => match self.parse_address(args) {
+                Some(address) if self.breakpoints.remove(&address) => {
+                    format!("Removed breakpoint at {}", self.symbols.format(address))
+                }
+                _ => format!("No breakpoint at {}", args),
+            },
+            "breaks" => {
+                if self.breakpoints.is_empty() {
+                    "No breakpoints".to_string()
+                } else {
+                    let breakpoints: Vec<_> = self
+                        .breakpoints
+                        .iter()
+                        .map(|&address| self.symbols.format(address))
+                        .collect();
+                    breakpoints.join(", ")
+                }
+            }
+            "limit" => match args {
+                "" => format!("Step limit: {}", self.step_limit),
+                _ => match args.parse() {
+                    Ok(limit) if limit > 0 => {
+                        self.step_limit = limit;
+                        format!("Step limit: {}", limit)
+                    }
+                    _ => format!("Invalid limit: {}", args),
+                },
+            },
+            _ if self.program.is_none() => "No program loaded, use load <path>".to_string(),
+            "in" | "i" => match parse_values(args) {
+                Some(values) if !values.is_empty() => {
+                    let program = self.program.as_mut().unwrap();
+                    values.iter().for_each(|&value| program.set_input(value));
+                    format!("Queued {} values", values.len())
+                }
+                _ => format!("Invalid values: {}", args),
+            },
+            "ascii" | "a" => {
+                let program = self.program.as_mut().unwrap();
+                args.chars()
+                    .for_each(|c| program.set_input(Value::from(c as u32)));
+                program.set_input(10);
+                format!("Queued {} values", args.chars().count() + 1)
+            }
+            "run" | "r" | "c" => self.resume(false),
+            "next" | "n" => self.resume(true),
+            "step" | "s" => match args {
+                "" => self.step(1),
+                _ => match args.parse() {
+                    Ok(count) if count > MAX_COUNT => too_many(count),
+                    Ok(count) => self.step(count),
+                    Err(_) => format!("Invalid count: {}", args),
+                },
+            },
+            "peek" | "x" => self.peek(args),
+            "poke" => {
+                let mut parts = args.splitn(2, char::is_whitespace);
+                let address = parts.next().and_then(|text| self.parse_address(text));
+                let values = parts.next().and_then(parse_values);
+                match (address, values) {
+                    (Some(address), Some(values)) if !values.is_empty() => {
+                        let count = values.len();
+                        let patch = Patch { address, values };
+                        self.program.as_mut().unwrap().apply_patch(patch);
+                        format!("Wrote {} values at {}", count, address)
+                    }
+                    _ => "Usage: poke <addr> <values>".to_string(),
+                }
+            }
+            "regs" => {
+                let program = self.program.as_ref().unwrap();
+                let join = |values: Vec<Value>| {
+                    let values: Vec<_> = values.iter().map(Value::to_string).collect();
+                    values.join(",")
+                };
+                format!(
+                    "ip: {}\nrb: {}\nelapsed: {}\ninput: {}\noutput: {}",
+                    program.instruction_ptr(),
+                    program.relative_base(),
+                    program.elapsed(),
+                    join(program.input_queue().iter().cloned().collect()),
+                    join(program.output_queue().iter().cloned().collect())
+                )
+            }
+            "bt" | "backtrace" => self
+                .program
+                .as_ref()
+                .unwrap()
+                .backtrace()
+                .iter()
+                .enumerate()
+                .map(|(i, frame)| format!("#{} {}", i, frame.to_string_with(&self.symbols)))
+                .collect::<Vec<_>>()
+                .join("\n"),
+            "dis" | "d" => self.disassemble(args),
+            "save" => match self.program.as_ref().unwrap().snapshot().save(args) {
+                Ok(()) => format!("Saved {}", args),
+                Err(e) => e.to_string(),
+            },
+            _ => format!("Unknown command: {}, try help", command),
+        }
+    }
+
+    fn load(&mut self, path: &str) -> String {
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) => return format!("Could not read {}: {}", path, e),
+        };
+        match parse_image(&text) {
+            Ok(image) => {
+                let mut program = Program::new(&image);
+                program.track_calls();
+                self.program = Some(program);
+                let map = Path::new(path).with_extension("map");
+                self.symbols = SymbolMap::load(&map).unwrap_or_default();
+                match self.symbols.len() {
+                    0 => format!("Loaded {} values from {}", image.len(), path),
+                    count => format!(
+                        "Loaded {} values from {} and {} symbols from {}",
+                        image.len(),
+                        path,
+                        count,
+                        map.display()
+                    ),
+                }
+            }
+            Err(e) => format!("Could not parse {}: {}", path, e),
+        }
+    }
+
+    /// Runs until the machine halts, needs input, reaches a breakpoint or, with
+    /// `until_output`, has produced a value. The breakpoint at the start is skipped. Returns to
+    /// the prompt after `step_limit` steps, so endless loops can be inspected.
+    fn resume(&mut self, until_output: bool) -> String {
+        let program = self.program.as_mut().unwrap();
+        let mut steps = 0;
+        let reason = loop {
+            let ip = program.instruction_ptr();
+            let at = self.symbols.format(ip);
+            if program.is_halted() {
+                break format!("Halted at {}", at);
+            }
+            if program.needs_input() {
+                break format!("Waiting for input at {}", at);
+            }
+            if steps > 0 && self.breakpoints.contains(&ip) {
+                break format!("Breakpoint at {}", at);
+            }
+            if until_output && !program.output_queue().is_empty() {
+                break format!("Output at {}", at);
+            }
+            if steps == self.step_limit {
+                break format!("Still running after {} steps at {}", steps, at);
+            }
+            if let Err(message) = program.try_step() {
+                break format!("Error at {}: {}", at, message);
+            }
+            steps += 1;
+        };
+        let output = format_output(&program.take_output());
+        if output.is_empty() {
+            reason
+        } else {
+            format!("{}\n{}", output, reason)
+        }
+    }
+
+    fn step(&mut self, count: usize) -> String {
+        let program = self.program.as_mut().unwrap();
+        let mut lines = Vec::new();
+        for _ in 0..count {
+            let at = self.symbols.format(program.instruction_ptr());
+            if program.is_halted() {
+                lines.push(format!("Halted at {}", at));
+                break;
+            }
+            if program.needs_input() {
+                lines.push(format!("Waiting for input at {}", at));
+                break;
+            }
+            lines.push(listing_line(
+                program,
+                &self.symbols,
+                program.instruction_ptr(),
+            ));
+            if let Err(message) = program.try_step() {
+                lines.push(format!("Error at {}: {}", at, message));
+                break;
+            }
+        }
+        let output = format_output(&program.take_output());
+        if !output.is_empty() {
+            lines.push(output);
+        }
+        lines.join("\n")
+    }
+
+    fn peek(&self, args: &str) -> String {
+        let program = self.program.as_ref().unwrap();
+        let mut parts = args.split_whitespace();
+        let address = parts.next().and_then(|text| self.parse_address(text));
+        let count = parts.next().map_or(Some(1), |count| count.parse().ok());
+        match (address, count) {
+            (Some(address), Some(count)) if address.checked_add(count).is_none() => {
+                format!(
+                    "Address range out of bounds: {} values at {}",
+                    count, address
+                )
+            }
+            (Some(_), Some(count)) if count > MAX_COUNT => too_many(count),
+            (Some(address), Some(count)) => (address..address + count)
+                .step_by(8)
+                .map(|row| {
+                    let values: Vec<_> = (row..(row + 8).min(address + count))
+                        .map(|a| format!("{:>8}", program.peek(a)))
+                        .collect();
+                    format!("{:6}: {}", row, values.join(" "))
+                })
+                .collect::<Vec<_>>()
+                .join("\n"),
+            _ => "Usage: peek <addr> [count]".to_string(),
+        }
+    }
+
+    fn disassemble(&self, args: &str) -> String {
+        let program = self.program.as_ref().unwrap();
+        let mut parts = args.split_whitespace();
+        let address = parts
+            .next()
+            .map_or(Some(program.instruction_ptr()), |text| {
+                self.parse_address(text)
+            });
+        let count = parts.next().map_or(Some(10), |count| count.parse().ok());
+        match (address, count) {
+            (Some(_), Some(count)) if count > MAX_COUNT => too_many(count),
+            (Some(address), Some(count)) => program
+                .disassemble_range(address, count)
+                .into_iter()
+                .map(|(address, _)| listing_line(program, &self.symbols, address))
+                .collect::<Vec<_>>()
+                .join("\n"),
+            _ => "Usage: dis [addr] [count]".to_string(),
+        }
+    }
+
+    fn parse_address(&self, text: &str) -> Option<usize> {
+        self.symbols.resolve(text)
+    }
+}
+
+fn too_many(count: usize) -> String {
+    format!(
+        "Count {} is too large, at most {} at once",
+        count, MAX_COUNT
+    )
+}
+
+/// One disassembly line, marked with `>` at the instruction pointer
+fn listing_line(program: &Program, symbols: &SymbolMap, address: usize) -> String {
+    let marker = if address == program.instruction_ptr() {
+        '>'
+    } else {
+        ' '
+    };
+    let label = symbols.format(address);
+    match program.disassemble(address) {
+        Some(disassembly) => format!(
+            "{} {:>6}: {}",
+            marker,
+            label,
+            disassembly.to_string_with(symbols)
+        ),
+        None => format!("{} {:>6}: {}", marker, label, program.peek(address)),
+    }
+}
+
+/// Output as text when it is printable ASCII containing a newline, as numbers otherwise
+fn format_output(output: &[Value]) -> String {
+    let is_text = output.contains(&10) && output.iter().all(|&v| v == 10 || (32..127).contains(&v));
+    if is_text {
+        let text: String = output.iter().map(|&v| v as u8 as char).collect();
+        text.trim_end_matches('\n').to_string()
+    } else {
+        let values: Vec<_> = output.iter().map(Value::to_string).collect();
+        values.join(",")
+    }
+}
+
+fn parse_values(text: &str) -> Option<Vec<Value>> {
+    text.split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|value| !value.is_empty())
+        .map(|value| value.parse().ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn repl(data: &[Value]) -> Repl {
+        let mut repl = Repl::new();
+        repl.program = Some(Program::new(data));
+        repl
+    }
+
+    #[test]
+    fn test_run_input_and_breakpoints() {
+        // adds the input to itself and outputs it, forever
+        let mut repl = repl(&[3, 13, 1, 13, 13, 13, 4, 13, 1105, 1, 0, 99, 99, 0]);
+
+        assert_eq!(repl.execute("run"), "Waiting for input at 0");
+        assert_eq!(repl.execute("in 21, 4"), "Queued 2 values");
+        assert_eq!(repl.execute("next"), "42\nOutput at 8");
+        assert_eq!(repl.execute("break 6"), "Breakpoint at 6");
+        assert_eq!(repl.execute("run"), "Breakpoint at 6");
+        assert_eq!(repl.execute("step"), ">      6: out [13]\n8");
+        assert_eq!(repl.execute("peek 12 2"), "    12:       99        8");
+        assert_eq!(repl.execute("ascii ab"), "Queued 3 values");
+        assert_eq!(repl.execute("run"), "Breakpoint at 6");
+        assert_eq!(repl.execute("delete 6"), "Removed breakpoint at 6");
+        assert_eq!(repl.execute("run"), "194,196,20\nWaiting for input at 0");
+        assert_eq!(repl.history().len(), 11);
+    }
+
+    #[test]
+    fn test_poke_and_disassemble() {
+        let mut repl = repl(&[1001, 9, -3, 9, 99]);
+
+        assert_eq!(repl.execute("poke 4 4 9"), "Wrote 2 values at 4");
+        assert_eq!(repl.execute("poke 6 99"), "Wrote 1 values at 6");
+        assert_eq!(
+            repl.execute("dis 0 3"),
+            ">      0: add [9], -3, [9]\n       4: out [9]\n       6: hlt"
+        );
+        assert_eq!(repl.execute("run"), "-3\nHalted at 6");
+        assert_eq!(repl.execute("bt"), "#0 6 in 0");
+        assert_eq!(
+            repl.execute("frobnicate"),
+            "Unknown command: frobnicate, try help"
+        );
+        assert_eq!(
+            Repl::new().execute("run"),
+            "No program loaded, use load <path>"
+        );
+    }
+
+    #[test]
+    fn test_step_limit() {
+        // loops forever without output
+        let mut repl = repl(&[1105, 1, 0]);
+        assert_eq!(repl.execute("limit 0"), "Invalid limit: 0");
+        assert_eq!(repl.execute("limit 1000"), "Step limit: 1000");
+        assert_eq!(repl.execute("run"), "Still running after 1000 steps at 0");
+        assert_eq!(repl.execute("next"), "Still running after 1000 steps at 0");
+        assert_eq!(repl.program().unwrap().elapsed(), 2000);
+        assert_eq!(
+            repl.execute(&format!("peek {} 2", usize::MAX)),
+            format!("Address range out of bounds: 2 values at {}", usize::MAX)
+        );
+    }
+
+    #[test]
+    fn test_bad_commands() {
+        let mut repl = repl(&[1105, 1, 0]);
+        assert_eq!(repl.execute("poke 0 50"), "Wrote 1 values at 0");
+        assert!(repl
+            .execute("step")
+            .ends_with("Error at 0: Unknown instruction 50 at 0"));
+        assert_eq!(
+            repl.execute("run"),
+            "Error at 0: Unknown instruction 50 at 0"
+        );
+        assert_eq!(
+            repl.execute(&format!("dis 0 {}", usize::MAX)),
+            format!("Count {} is too large, at most 10000 at once", usize::MAX)
+        );
+        assert_eq!(
+            repl.execute("peek 0 1000000000000"),
+            "Count 1000000000000 is too large, at most 10000 at once"
+        );
+        assert_eq!(repl.execute("peek 0 10").lines().count(), 2);
+    }
+
+    #[test]
+    fn test_symbols() {
+        let mut repl = repl(&[1001, 9, -3, 9, 4, 9, 1105, 1, 0, 42]);
+        repl.symbols = "0 loop\n4 print\n9 counter".parse().unwrap();
+
+        assert_eq!(repl.execute("break print+2"), "Breakpoint at print+2");
+        assert_eq!(repl.execute("run"), "39\nBreakpoint at print+2");
+        assert_eq!(
+            repl.execute("dis print 2"),
+            "   print: out [counter]\n> print+2: jt 1, loop"
+        );
+        assert_eq!(repl.execute("bt"), "#0 print+2 in loop");
+    }
+}
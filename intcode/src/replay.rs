@@ -0,0 +1,416 @@
+use crate::{Program, Value};
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::fmt::{Display, Error, Formatter};
+#[cfg(feature = "std")]
+use std::fs;
+#[cfg(feature = "std")]
+use std::path::Path;
+
+/// A value which crossed the I/O queues, with the number of instructions executed before it.
+/// `End` is where the recording was stopped, at a halt, an input prompt or anywhere else.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Event {
+    Input { step: usize, value: Value },
+    Output { step: usize, value: Value },
+    End { step: usize },
+}
+
+impl Event {
+    pub fn step(&self) -> usize {
+        match self {
+            Event::Input { step, .. } | Event::Output { step, .. } | Event::End { step } => *step,
+        }
+    }
+}
+
+/// Formats like `120 in 5`, `134 out 1` or `140 end`
+impl Display for Event {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        match self {
+            Event::Input { step, value } => write!(f, "{} in {}", step, value),
+            Event::Output { step, value } => write!(f, "{} out {}", step, value),
+            Event::End { step } => write!(f, "{} end", step),
+        }
+    }
+}
+
+/// The I/O of a session, one event per line in the order they happened:
+/// ```text
+/// # day 11, starting on a white panel
+/// 0 in 1
+/// 12 out 1
+/// 19 out 0
+/// 25 end
+/// ```
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct Recording {
+    events: Vec<Event>,
+}
+
+impl Recording {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    #[cfg(feature = "std")]
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, RecordingError> {
+        let content = fs::read_to_string(path).map_err(|e| RecordingError::Io(e.to_string()))?;
+        content.parse()
+    }
+    #[cfg(feature = "std")]
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), RecordingError> {
+        fs::write(path, self.to_string()).map_err(|e| RecordingError::Io(e.to_string()))
+    }
+    pub fn push(&mut self, event: Event) {
+        self.events.push(event)
+    }
+    pub fn events(&self) -> &[Event] {
+        &self.events
+    }
+    pub fn inputs(&self) -> Vec<Value> {
+        self.events
+            .iter()
+            .filter_map(|event| match event {
+                Event::Input { value, .. } => Some(*value),
+                _ => None,
+            })
+            .collect()
+    }
+    pub fn outputs(&self) -> Vec<Value> {
+        self.events
+            .iter()
+            .filter_map(|event| match event {
+                Event::Output { value, .. } => Some(*value),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+impl Display for Recording {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        for event in &self.events {
+            writeln!(f, "{}", event)?;
+        }
+        Ok(())
+    }
+}
+
+impl core::str::FromStr for Recording {
+    type Err = RecordingError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut recording = Recording::new();
+        for (i, line) in s.lines().enumerate() {
+            let content = line.split('#').next().unwrap().trim();
+            if content.is_empty() {
+                continue;
+            }
+            let parts: Vec<_> = content.split_whitespace().collect();
+            let event = match parts[..] {
+                [step, "end"] => step.parse().ok().map(|step| Event::End { step }),
+                [step, kind, value] => match (step.parse(), kind, value.parse()) {
+                    (Ok(step), "in", Ok(value)) => Some(Event::Input { step, value }),
+                    (Ok(step), "out", Ok(value)) => Some(Event::Output { step, value }),
+                    _ => None,
+                },
+                _ => None,
+            };
+            match event {
+                Some(event) => recording.push(event),
+                None => {
+                    return Err(RecordingError::Parse {
+                        line: i + 1,
+                        content: line.to_string(),
+                    })
+                }
+            }
+        }
+        Ok(recording)
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum RecordingError {
+    Io(String),
+    Parse { line: usize, content: String },
+}
+
+impl Display for RecordingError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        match self {
+            RecordingError::Io(e) => write!(f, "Could not access recording: {}", e),
+            RecordingError::Parse { line, content } => {
+                write!(f, "Invalid recording line {}: {}", line, content)
+            }
+        }
+    }
+}
+
+impl core::error::Error for RecordingError {}
+
+/// What the machine did where a replay stopped matching the recording. `InputRequest` means it
+/// needed input and the recording had none for that step, `Silent` that the step did no I/O or
+/// the machine is stuck in a loop without I/O.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Observed {
+    InputRequest { step: usize },
+    Silent { step: usize },
+    Input { step: usize, value: Value },
+    Output { step: usize, value: Value },
+    Halt { step: usize },
+}
+
+impl Display for Observed {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        match self {
+            Observed::InputRequest { step } => write!(f, "{} in ?", step),
+            Observed::Silent { step } => write!(f, "{} no I/O", step),
+            Observed::Input { step, value } => write!(f, "{} in {}", step, value),
+            Observed::Output { step, value } => write!(f, "{} out {}", step, value),
+            Observed::Halt { step } => write!(f, "{} halt", step),
+        }
+    }
+}
+
+/// The first point where a replay differs from its recording
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Divergence {
+    /// Index of the recorded event which was expected next
+    pub index: usize,
+    pub expected: Event,
+    pub observed: Observed,
+}
+
+impl Display for Divergence {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        write!(
+            f,
+            "Replay diverged at event {}: expected `{}`, found `{}`",
+            self.index, self.expected, self.observed
+        )
+    }
+}
+
+impl core::error::Error for Divergence {}
+
+impl Program {
+    /// Starts recording consumed input and produced output, replacing any earlier recording
+    pub fn start_recording(&mut self) {
+        self.recording = Some(Recording::new());
+    }
+    /// Stops recording and returns what was recorded, ending with an `Event::End` at the current
+    /// step
+    pub fn stop_recording(&mut self) -> Option<Recording> {
+        let mut recording = self.recording.take()?;
+        recording.push(Event::End { step: self.elapsed });
+        Some(recording)
+    }
+    pub fn recording(&self) -> Option<&Recording> {
+        self.recording.as_ref()
+    }
+    pub(crate) fn record(&mut self, event: Event) {
+        if let Some(recording) = &mut self.recording {
+            recording.push(event);
+        }
+    }
+    /// Runs the program until the recording ends, queueing each recorded input right before the
+    /// step it was consumed at and checking that every input and output happens at the recorded
+    /// step with the recorded value. The replay ends at the `Event::End`, or after the last event
+    /// of a recording without one, and diverges as soon as the machine passes the step of the
+    /// next event without it happening.
+    pub fn replay(&mut self, recording: &Recording) -> Result<(), Divergence> {
+        let events = recording.events();
+        let mut index = 0;
+        // Recorded inputs before this one have been queued
+        let mut fed = 0;
+        loop {
+            let step = self.elapsed;
+            let expected = match events.get(index) {
+                None => return Ok(()),
+                Some(Event::End { step: end }) if *end == step => return Ok(()),
+                Some(&expected) if expected.step() < step => {
+                    return Err(Divergence {
+                        index,
+                        expected,
+                        observed: Observed::Silent { step: step - 1 },
+                    })
+                }
+                Some(&expected) => expected,
+            };
+            while let Some(&event) = events.get(fed) {
+                match event {
+                    Event::Input { step: at, value } if at == step => self.input.push_back(value),
+                    Event::Input { .. } | Event::End { .. } => break,
+                    Event::Output { .. } => {}
+                }
+                fed += 1;
+            }
+
+            let mut observed = Vec::new();
+            if self.is_halted() {
+                observed.push(Observed::Halt { step });
+            } else if self.stuck().is_some() {
+                observed.push(Observed::Silent { step });
+            } else if self.needs_input() {
+                observed.push(Observed::InputRequest { step });
+            } else {
+                let queued = self.input.len();
+                let next = self.input.front().cloned();
+                let produced = self.output.len();
+                self.step();
+                if let Some(value) = next.filter(|_| self.input.len() < queued) {
+                    observed.push(Observed::Input { step, value });
+                }
+                let outputs = self.output.iter().skip(produced);
+                observed.extend(outputs.map(|&value| Observed::Output { step, value }));
+            }
+
+            let mut expected = Some(expected);
+            for observed in observed {
+                match (expected, observed) {
+                    // the recording ended within this step
+                    (None, _) => return Ok(()),
+                    (Some(Event::Input { step, value }), Observed::Input { step: s, value: v })
+                    | (
+                        Some(Event::Output { step, value }),
+                        Observed::Output { step: s, value: v },
+                    ) if step == s && value == v => {}
+                    (Some(expected), observed) => {
+                        return Err(Divergence {
+                            index,
+                            expected,
+                            observed,
+                        })
+                    }
+                }
+                index += 1;
+                expected = events.get(index).cloned();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Outputs twice its input until it reads a 0
+    const DOUBLER: [Value; 16] = [
+        3, 15, 1006, 15, 14, 102, 2, 15, 15, 4, 15, 1105, 1, 0, 99, 0,
+    ];
+
+    fn session() -> Recording {
+        let mut p = Program::new(&DOUBLER);
+        p.start_recording();
+        p.set_input(3);
+        assert_eq!(p.run_pipe(), Some(6));
+        p.set_input(5);
+        assert_eq!(p.run_pipe(), Some(10));
+        p.set_input(0);
+        p.run();
+        p.stop_recording().unwrap()
+    }
+
+    #[test]
+    fn test_record() {
+        let recording = session();
+        assert_eq!(
+            recording.to_string(),
+            "0 in 3\n3 out 6\n5 in 5\n8 out 10\n10 in 0\n12 end\n"
+        );
+        assert_eq!(recording.inputs(), [3, 5, 0]);
+        assert_eq!(recording.outputs(), [6, 10]);
+        assert_eq!(
+            "# comment\n\n0 in 3\n3 out 6\n5 in 5\n8 out 10\n10 in 0\n12 end".parse(),
+            Ok(recording)
+        );
+        assert_eq!(
+            "0 in 3\n3 put 6".parse::<Recording>(),
+            Err(RecordingError::Parse {
+                line: 2,
+                content: "3 put 6".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn test_replay() {
+        let recording = session();
+        assert_eq!(Program::new(&DOUBLER).replay(&recording), Ok(()));
+
+        let mut tripler = Program::new(&DOUBLER);
+        tripler.poke(6, 3);
+        assert_eq!(
+            tripler.replay(&recording),
+            Err(Divergence {
+                index: 1,
+                expected: Event::Output { step: 3, value: 6 },
+                observed: Observed::Output { step: 3, value: 9 }
+            })
+        );
+
+        // input queued before the replay is read instead of the recorded one
+        let mut preloaded = Program::new(&DOUBLER);
+        preloaded.set_input(4);
+        assert_eq!(
+            preloaded.replay(&recording),
+            Err(Divergence {
+                index: 0,
+                expected: Event::Input { step: 0, value: 3 },
+                observed: Observed::Input { step: 0, value: 4 }
+            })
+        );
+
+        // without an end the replay stops after the last event
+        let truncated: Recording = "0 in 3\n3 out 6".parse().unwrap();
+        let mut p = Program::new(&DOUBLER);
+        assert_eq!(p.replay(&truncated), Ok(()));
+        assert_eq!(p.elapsed(), 4);
+
+        let divergence = Program::new(&DOUBLER)
+            .replay(&"0 in 3\n3 out 6\n9 end".parse().unwrap())
+            .unwrap_err();
+        assert_eq!(divergence.observed, Observed::InputRequest { step: 5 });
+        assert_eq!(
+            divergence.to_string(),
+            "Replay diverged at event 2: expected `9 end`, found `5 in ?`"
+        );
+    }
+
+    #[test]
+    fn test_replay_loop_without_io() {
+        let mut p = Program::new(&[104, 1, 99]);
+        p.start_recording();
+        p.run();
+        let recording = p.stop_recording().unwrap();
+        assert_eq!(
+            Program::new(&[1105, 1, 0]).replay(&recording),
+            Err(Divergence {
+                index: 0,
+                expected: Event::Output { step: 0, value: 1 },
+                observed: Observed::Silent { step: 0 }
+            })
+        );
+
+        let mut stuck = Program::new(&[1105, 1, 0]);
+        stuck.detect_loops();
+        assert!(stuck.replay(&"5 end".parse().unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_replay_ends_at_prompt() {
+        // echoes input forever, quit while waiting for the second value
+        let image = [3, 10, 4, 10, 1105, 1, 0];
+        let mut p = Program::new(&image);
+        p.start_recording();
+        p.set_input(5);
+        assert_eq!(p.run_pipe(), Some(5));
+        while !p.needs_input() {
+            p.step();
+        }
+        let recording = p.stop_recording().unwrap();
+        assert_eq!(recording.to_string(), "0 in 5\n1 out 5\n3 end\n");
+        assert_eq!(Program::new(&image).replay(&recording), Ok(()));
+    }
+}
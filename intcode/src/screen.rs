@@ -0,0 +1,292 @@
+//! A screen drawn by programs which output `(x, y, tile)` triples.
+//!
+//! The screen grows to fit whatever is drawn. Tiles are shown with a pluggable mapping to glyphs
+//! for the terminal and to colors for image files. Coordinates which hold a value instead of a
+//! tile, like a score, can be set aside as registers so they aren't drawn:
+//! ```
+//! use intcode::screen::Screen;
+//! use intcode::Program;
+//!
+//! // draws a wall and a ball, then sets the score
+//! let mut p = Program::new(&[
+//!     104, 0, 104, 0, 104, 1, 104, 1, 104, 0, 104, 1, 104, 2, 104, 1, 104, 4, //
+//!     104, -1, 104, 0, 104, 12, 99,
+//! ]);
+//! let mut screen = Screen::new()
+//!     .with_register(-1, 0)
+//!     .with_glyphs(|tile| match tile {
+//!         1 => '#',
+//!         4 => 'o',
+//!         _ => ' ',
+//!     });
+//! screen.update(&mut p);
+//! assert_eq!(screen.to_string(), "## \n  o\n");
+//! assert_eq!(screen.find(4), Some((2, 1)));
+//! assert_eq!(screen.tile(-1, 0), 12);
+//! ```
+
+use crate::{Program, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::{Debug, Display, Error, Formatter};
+use std::fs;
+use std::io;
+use std::ops::RangeInclusive;
+use std::path::Path;
+
+type Glyphs = Box<dyn Fn(Value) -> char + Send + Sync>;
+type Colors = Box<dyn Fn(Value) -> [u8; 3] + Send + Sync>;
+
+/// Most tiles rendered per row or column, a stray draw far away mustn't take all memory
+const MAX_SIZE: usize = 1024;
+
+/// The first `MAX_SIZE` values of `range`
+fn clamp(range: RangeInclusive<Value>) -> RangeInclusive<Value> {
+    let (start, end) = range.into_inner();
+    start..=end.min(start.saturating_add(MAX_SIZE as Value - 1))
+}
+
+/// Colors for tiles 1 and up, tile 0 is black
+const PALETTE: [[u8; 3]; 6] = [
+    [255, 255, 255],
+    [230, 60, 50],
+    [60, 180, 75],
+    [0, 130, 200],
+    [255, 225, 25],
+    [145, 30, 180],
+];
+
+pub struct Screen {
+    /// Rows by y, tiles by x
+    tiles: BTreeMap<Value, BTreeMap<Value, Value>>,
+    registers: HashMap<(Value, Value), Value>,
+    x: Option<RangeInclusive<Value>>,
+    y: Option<RangeInclusive<Value>>,
+    glyphs: Glyphs,
+    colors: Colors,
+}
+
+impl Default for Screen {
+    fn default() -> Self {
+        Screen {
+            tiles: BTreeMap::new(),
+            registers: HashMap::new(),
+            x: None,
+            y: None,
+            glyphs: Box::new(|tile| if tile == 0 { ' ' } else { '█' }),
+            colors: Box::new(|tile| match tile {
+                0 => [0, 0, 0],
+                tile => PALETTE[(tile - 1).rem_euclid(PALETTE.len() as Value) as usize],
+            }),
+        }
+    }
+}
+
+impl Debug for Screen {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        f.debug_struct("Screen")
+            .field("tiles", &self.tiles)
+            .field("registers", &self.registers)
+            .finish()
+    }
+}
+
+impl Screen {
+    /// An empty screen, showing tile 0 as a space and every other tile as a block
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn with_glyphs<F: Fn(Value) -> char + Send + Sync + 'static>(mut self, glyphs: F) -> Self {
+        self.glyphs = Box::new(glyphs);
+        self
+    }
+    /// Sets the RGB colors of tiles in image files
+    pub fn with_colors<F: Fn(Value) -> [u8; 3] + Send + Sync + 'static>(
+        mut self,
+        colors: F,
+    ) -> Self {
+        self.colors = Box::new(colors);
+        self
+    }
+    /// Values drawn at `(x, y)` are kept as a register instead of a tile
+    pub fn with_register(mut self, x: Value, y: Value) -> Self {
+        self.registers.insert((x, y), 0);
+        self
+    }
+    pub fn draw(&mut self, x: Value, y: Value, tile: Value) {
+        if let Some(register) = self.registers.get_mut(&(x, y)) {
+            *register = tile;
+            return;
+        }
+        self.tiles.entry(y).or_default().insert(x, tile);
+        let grow = |range: &Option<RangeInclusive<Value>>, value: Value| match range {
+            Some(range) => *range.start().min(&value)..=*range.end().max(&value),
+            None => value..=value,
+        };
+        self.x = Some(grow(&self.x, x));
+        self.y = Some(grow(&self.y, y));
+    }
+    /// Runs `program` until it halts, needs input or is stuck in a loop and draws everything it
+    /// outputs. An incomplete triple stays in the output queue for the next update.
+    pub fn update(&mut self, program: &mut Program) {
+        while !program.is_halted() && !program.needs_input() {
+            if !program.step() {
+                break;
+            }
+        }
+        while program.output.len() >= 3 {
+            let mut next = || program.output.pop_front().unwrap();
+            let (x, y, tile) = (next(), next(), next());
+            self.draw(x, y, tile);
+        }
+    }
+    /// The tile or register at `(x, y)`, 0 if nothing was drawn there
+    pub fn tile(&self, x: Value, y: Value) -> Value {
+        if let Some(&register) = self.registers.get(&(x, y)) {
+            return register;
+        }
+        self.tiles
+            .get(&y)
+            .and_then(|row| row.get(&x))
+            .cloned()
+            .unwrap_or(0)
+    }
+    /// Coordinates of every tile, row by row
+    pub fn tiles(&self) -> impl Iterator<Item = ((Value, Value), Value)> + '_ {
+        self.tiles
+            .iter()
+            .flat_map(|(&y, row)| row.iter().map(move |(&x, &tile)| ((x, y), tile)))
+    }
+    /// The first position showing `tile`, row by row
+    pub fn find(&self, tile: Value) -> Option<(Value, Value)> {
+        self.tiles()
+            .find(|&(_, found)| found == tile)
+            .map(|(position, _)| position)
+    }
+    /// The number of tiles showing `tile`
+    pub fn count(&self, tile: Value) -> usize {
+        self.tiles().filter(|&(_, found)| found == tile).count()
+    }
+    /// The ranges of x and y covered by drawn tiles, None if nothing was drawn
+    pub fn bounds(&self) -> Option<(RangeInclusive<Value>, RangeInclusive<Value>)> {
+        Some((self.x.clone()?, self.y.clone()?))
+    }
+    /// Renders the screen as a binary PPM image, each tile `scale` pixels wide and high. Only
+    /// the top left 1024x1024 tiles are rendered.
+    pub fn to_ppm(&self, scale: usize) -> Vec<u8> {
+        let (x, y) = match self.bounds() {
+            Some((x, y)) => (clamp(x), clamp(y)),
+            None => return b"P6\n0 0\n255\n".to_vec(),
+        };
+        let (width, height) = (x.clone().count() * scale, y.clone().count() * scale);
+        let mut image = format!("P6\n{} {}\n255\n", width, height).into_bytes();
+        for y in y {
+            let mut row = Vec::with_capacity(width * 3);
+            for x in x.clone() {
+                let color = (self.colors)(self.tile(x, y));
+                for _ in 0..scale {
+                    row.extend_from_slice(&color);
+                }
+            }
+            for _ in 0..scale {
+                image.extend_from_slice(&row);
+            }
+        }
+        image
+    }
+    /// Saves the screen as a PPM image, see `to_ppm()`
+    pub fn save_image<P: AsRef<Path>>(&self, path: P, scale: usize) -> io::Result<()> {
+        fs::write(path, self.to_ppm(scale))
+    }
+}
+
+/// Shows the screen within its bounds, one line per row, at most 1024x1024 tiles from the top
+/// left
+impl Display for Screen {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        let (x, y) = match self.bounds() {
+            Some((x, y)) => (clamp(x), clamp(y)),
+            None => return Ok(()),
+        };
+        for y in y {
+            let row: String = x.clone().map(|x| (self.glyphs)(self.tile(x, y))).collect();
+            writeln!(f, "{}", row)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bounds() {
+        let mut screen = Screen::new();
+        assert_eq!(screen.bounds(), None);
+        assert_eq!(screen.to_string(), "");
+        screen.draw(2, -1, 1);
+        screen.draw(-1, 1, 1);
+        screen.draw(0, 0, 0);
+        assert_eq!(screen.bounds(), Some((-1..=2, -1..=1)));
+        assert_eq!(screen.to_string(), "   █\n    \n█   \n");
+        assert_eq!(screen.count(0), 1);
+        assert_eq!(screen.tile(5, 5), 0);
+    }
+
+    #[test]
+    fn test_update() {
+        // draws a tile, then a second one in the row read as input
+        let image = [
+            104, 0, 104, 0, 104, 2, 104, 5, 3, 20, 4, 20, 104, 1, 99, 0, 0, 0, 0, 0, 0,
+        ];
+        let mut p = Program::new(&image);
+        let mut screen = Screen::new();
+        screen.update(&mut p);
+        assert!(p.needs_input());
+        assert_eq!(screen.tile(0, 0), 2);
+        assert_eq!(p.output_queue().len(), 1);
+
+        let (_, y) = screen.find(2).unwrap();
+        p.set_input(y + 3);
+        screen.update(&mut p);
+        assert!(p.is_halted());
+        assert_eq!(screen.find(1), Some((5, 3)));
+        assert_eq!(screen.bounds(), Some((0..=5, 0..=3)));
+    }
+
+    #[test]
+    fn test_far_away_and_stuck() {
+        let mut screen = Screen::new();
+        screen.draw(0, 0, 1);
+        screen.draw(1_000_000_000_000, Value::MIN, 1);
+        let text = screen.to_string();
+        assert_eq!(text.lines().count(), MAX_SIZE);
+        assert!(text.lines().all(|line| line.chars().count() == MAX_SIZE));
+        assert_eq!(
+            screen.to_ppm(1).len(),
+            "P6\n1024 1024\n255\n".len() + 3 * MAX_SIZE * MAX_SIZE
+        );
+
+        // draws a tile, then jumps to itself forever
+        let mut p = Program::new(&[104, 0, 104, 0, 104, 1, 1105, 1, 6]);
+        p.detect_loops();
+        let mut screen = Screen::new();
+        screen.update(&mut p);
+        assert!(p.stuck().is_some());
+        assert_eq!(screen.tile(0, 0), 1);
+    }
+
+    #[test]
+    fn test_ppm() {
+        let mut screen = Screen::new().with_colors(|tile| [tile as u8; 3]);
+        screen.draw(0, 0, 1);
+        screen.draw(1, 0, 2);
+        let header = b"P6\n4 2\n255\n";
+        let ppm = screen.to_ppm(2);
+        assert_eq!(ppm[..header.len()], header[..]);
+        assert_eq!(
+            ppm[header.len()..],
+            [[1; 6], [2; 6], [1; 6], [2; 6]].concat()[..]
+        );
+    }
+}
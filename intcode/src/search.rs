@@ -0,0 +1,373 @@
+use crate::{Program, Value};
+use std::convert::TryFrom;
+use std::ops::Range;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+
+/// One point in a parameter space: memory patches to apply and input values to queue
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Candidate {
+    pub patches: Vec<(usize, Value)>,
+    pub inputs: Vec<Value>,
+}
+
+impl Candidate {
+    /// Apply the patches and queue the inputs of this candidate on `program`
+    pub fn apply(&self, program: &mut Program) {
+        for &(address, value) in &self.patches {
+            program.poke(address, value);
+        }
+        for &input in &self.inputs {
+            program.set_input(input);
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Dimension {
+    Patch(usize, Range<Value>),
+    Input(Range<Value>),
+    InputPermutations(Vec<Value>),
+}
+
+impl Dimension {
+    /// None if the number of values doesn't fit in a usize
+    fn len(&self) -> Option<usize> {
+        match self {
+            Dimension::Patch(_, values) | Dimension::Input(values) => range_len(values),
+            Dimension::InputPermutations(values) => factorial(values.len()),
+        }
+    }
+    fn apply(&self, index: usize, candidate: &mut Candidate) {
+        match self {
+            Dimension::Patch(address, values) => candidate
+                .patches
+                .push((*address, values.start.wrapping_add(index as Value))),
+            Dimension::Input(values) => candidate
+                .inputs
+                .push(values.start.wrapping_add(index as Value)),
+            Dimension::InputPermutations(values) => {
+                candidate.inputs.extend(nth_permutation(values, index))
+            }
+        }
+    }
+}
+
+/// `Search` evaluates every candidate of a parameter space against a base program on all cores.
+///
+/// Dimensions are combined as a cartesian product, in the order they were added:
+/// ```
+/// use intcode::{search::Search, Program};
+///
+/// let program = Program::new(&[1, 0, 0, 0, 99]);
+/// let search = Search::new(&program).patch(1, 0..5).patch(2, 0..5);
+/// let found = search.find_first(|_, mut p| {
+///     p.run();
+///     p.inspect(0) == 100
+/// });
+/// assert_eq!(found.unwrap().patches, vec![(1, 0), (2, 4)]);
+/// ```
+#[derive(Debug, Clone)]
+pub struct Search {
+    program: Program,
+    dimensions: Vec<Dimension>,
+    threads: usize,
+}
+
+impl Search {
+    pub fn new(program: &Program) -> Self {
+        let threads = thread::available_parallelism()
+            .map(|n| n.get())
+            .unwrap_or(1);
+        Search {
+            program: program.clone(),
+            dimensions: Vec::new(),
+            threads,
+        }
+    }
+    /// Try every value in `values` at memory `address`
+    pub fn patch(mut self, address: usize, values: Range<Value>) -> Self {
+        self.dimensions.push(Dimension::Patch(address, values));
+        self
+    }
+    /// Queue every value in `values` as the next input
+    pub fn input(mut self, values: Range<Value>) -> Self {
+        self.dimensions.push(Dimension::Input(values));
+        self
+    }
+    /// Queue every ordering of `values` as the next inputs, like day 7's phase settings
+    pub fn input_permutations(mut self, values: &[Value]) -> Self {
+        self.dimensions
+            .push(Dimension::InputPermutations(values.to_vec()));
+        self
+    }
+    /// Limit the number of worker threads, defaults to the available parallelism
+    pub fn threads(mut self, threads: usize) -> Self {
+        self.threads = threads.max(1);
+        self
+    }
+    /// Number of candidates in the parameter space, None if it doesn't fit in a usize
+    pub fn len(&self) -> Option<usize> {
+        self.dimensions
+            .iter()
+            .try_fold(1, |len: usize, dimension| len.checked_mul(dimension.len()?))
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len() == Some(0)
+    }
+    /// Like `len()`, for the functions going through all candidates
+    fn enumerable_len(&self) -> usize {
+        self.len()
+            .expect("The parameter space has too many candidates to enumerate")
+    }
+    /// Returns the candidate at `index`, the last dimension varies fastest. None if `index` is
+    /// past the last candidate.
+    pub fn candidate(&self, index: usize) -> Option<Candidate> {
+        let mut indices = vec![0; self.dimensions.len()];
+        let mut remainder = index;
+        for (i, dimension) in self.dimensions.iter().enumerate().rev() {
+            match dimension.len() {
+                Some(0) => return None,
+                Some(len) => {
+                    indices[i] = remainder % len;
+                    remainder /= len;
+                }
+                // more values than any index can reach
+                None => indices[i] = std::mem::take(&mut remainder),
+            }
+        }
+        if remainder > 0 {
+            return None;
+        }
+
+        let mut candidate = Candidate {
+            patches: Vec::new(),
+            inputs: Vec::new(),
+        };
+        for (dimension, index) in self.dimensions.iter().zip(indices) {
+            dimension.apply(index, &mut candidate);
+        }
+        Some(candidate)
+    }
+    /// Iterate over all candidates in order. Panics if `len()` is None, like the searches.
+    pub fn candidates(&self) -> impl Iterator<Item = Candidate> + '_ {
+        (0..self.enumerable_len()).map(move |i| self.candidate(i).unwrap())
+    }
+    /// Returns a clone of the base program with `candidate` applied
+    pub fn prepare(&self, candidate: &Candidate) -> Program {
+        let mut program = self.program.clone();
+        candidate.apply(&mut program);
+        program
+    }
+
+    /// `find_first()` returns the first candidate in order for which `objective` returns true.
+    /// Workers stop as soon as no earlier candidate can match anymore.
+    pub fn find_first<F>(&self, objective: F) -> Option<Candidate>
+    where
+        F: Fn(&Candidate, Program) -> bool + Sync,
+    {
+        let len = self.enumerable_len();
+        let next = AtomicUsize::new(0);
+        let found = AtomicUsize::new(usize::MAX);
+
+        thread::scope(|scope| {
+            for _ in 0..self.threads {
+                scope.spawn(|| loop {
+                    let index = next.fetch_add(1, Ordering::Relaxed);
+                    if index >= len || index > found.load(Ordering::Relaxed) {
+                        break;
+                    }
+                    let candidate = self.candidate(index).unwrap();
+                    let program = self.prepare(&candidate);
+                    if objective(&candidate, program) {
+                        found.fetch_min(index, Ordering::Relaxed);
+                    }
+                });
+            }
+        });
+
+        match found.into_inner() {
+            usize::MAX => None,
+            index => self.candidate(index),
+        }
+    }
+
+    /// `find_max()` evaluates every candidate and returns the one with the highest score.
+    /// On ties the earliest candidate wins.
+    pub fn find_max<F, T>(&self, objective: F) -> Option<(Candidate, T)>
+    where
+        F: Fn(&Candidate, Program) -> T + Sync,
+        T: Ord + Send,
+    {
+        let len = self.enumerable_len();
+        let next = AtomicUsize::new(0);
+        let best: Mutex<Option<(usize, T)>> = Mutex::new(None);
+
+        thread::scope(|scope| {
+            for _ in 0..self.threads {
+                scope.spawn(|| {
+                    let mut local: Option<(usize, T)> = None;
+                    loop {
+                        let index = next.fetch_add(1, Ordering::Relaxed);
+                        if index >= len {
+                            break;
+                        }
+                        let candidate = self.candidate(index).unwrap();
+                        let score = objective(&candidate, self.prepare(&candidate));
+                        if is_better(&local, index, &score) {
+                            local = Some((index, score));
+                        }
+                    }
+                    if let Some((index, score)) = local {
+                        let mut best = best.lock().unwrap();
+                        if is_better(&best, index, &score) {
+                            *best = Some((index, score));
+                        }
+                    }
+                });
+            }
+        });
+
+        let (index, score) = best.into_inner().unwrap()?;
+        Some((self.candidate(index).unwrap(), score))
+    }
+}
+
+fn is_better<T: Ord>(current: &Option<(usize, T)>, index: usize, score: &T) -> bool {
+    match current {
+        None => true,
+        Some((best_index, best_score)) => {
+            score > best_score || (score == best_score && index < *best_index)
+        }
+    }
+}
+
+/// None if the number of values doesn't fit in a usize
+fn range_len(range: &Range<Value>) -> Option<usize> {
+    if range.end > range.start {
+        usize::try_from(range.end.abs_diff(range.start)).ok()
+    } else {
+        Some(0)
+    }
+}
+
+fn factorial(n: usize) -> Option<usize> {
+    (1..=n).try_fold(1, usize::checked_mul)
+}
+
+// Decodes `index` as a factorial number to pick the permutation, in lexicographic order of the
+// positions in `values`
+fn nth_permutation(values: &[Value], index: usize) -> Vec<Value> {
+    let mut remaining = values.to_vec();
+    let mut permutation = Vec::with_capacity(values.len());
+    let mut index = index;
+    for i in (0..values.len()).rev() {
+        // an index never reaches the permutations of the rest when their number doesn't fit
+        let position = factorial(i).map_or(0, |factorial| {
+            let position = index / factorial;
+            index %= factorial;
+            position
+        });
+        permutation.push(remaining.remove(position));
+    }
+    permutation
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_candidate_order() {
+        let program = Program::new(&[99]);
+        let search = Search::new(&program).patch(1, 0..2).input(5..8);
+
+        assert_eq!(search.len(), Some(6));
+        let candidates: Vec<_> = search.candidates().collect();
+        assert_eq!(candidates[0].patches, [(1, 0)]);
+        assert_eq!(candidates[0].inputs, [5]);
+        assert_eq!(candidates[2].inputs, [7]);
+        assert_eq!(candidates[3].patches, [(1, 1)]);
+        assert_eq!(candidates[3].inputs, [5]);
+    }
+
+    #[test]
+    fn test_permutations() {
+        let program = Program::new(&[99]);
+        let search = Search::new(&program).input_permutations(&[0, 1, 2]);
+
+        let permutations: Vec<_> = search.candidates().map(|c| c.inputs).collect();
+        assert_eq!(
+            permutations,
+            [
+                [0, 1, 2],
+                [0, 2, 1],
+                [1, 0, 2],
+                [1, 2, 0],
+                [2, 0, 1],
+                [2, 1, 0]
+            ]
+        );
+    }
+
+    #[test]
+    fn test_len_overflow() {
+        let program = Program::new(&[99]);
+        let values: Vec<_> = (0..21).collect();
+        assert_eq!(
+            Search::new(&program)
+                .input_permutations(&values[..20])
+                .len(),
+            Some(2_432_902_008_176_640_000)
+        );
+        assert_eq!(
+            Search::new(&program).input_permutations(&values).len(),
+            None
+        );
+        let search = Search::new(&program)
+            .input_permutations(&values[..20])
+            .input(0..10);
+        assert_eq!(search.len(), None);
+        assert!(!search.is_empty());
+        assert_eq!(search.candidate(3).unwrap().inputs[20], 3);
+
+        let search = Search::new(&program).input_permutations(&values);
+        assert_eq!(search.candidate(1).unwrap().inputs[19..], [20, 19]);
+
+        let search = Search::new(&program).input(Value::MIN..Value::MAX);
+        assert_eq!(search.len(), Some(usize::MAX));
+        assert_eq!(
+            search.candidate(usize::MAX - 1).unwrap().inputs,
+            [Value::MAX - 1]
+        );
+        assert_eq!(Search::new(&program).input(5..5).candidate(0), None);
+        assert_eq!(Search::new(&program).input(0..5).candidate(5), None);
+    }
+
+    #[test]
+    fn test_find_first_returns_earliest_match() {
+        // outputs input * 2
+        let program = Program::new(&[3, 9, 1002, 9, 2, 9, 4, 9, 99, 0]);
+        let search = Search::new(&program).input(0..1000).threads(4);
+
+        let found = search.find_first(|_, mut p| p.run()[0] >= 100);
+        assert_eq!(found.unwrap().inputs, [50]);
+
+        let found = search.find_first(|_, mut p| p.run()[0] < 0);
+        assert_eq!(found, None);
+    }
+
+    #[test]
+    fn test_find_max() {
+        // outputs 10 * first input + second input
+        let program = Program::new(&[
+            3, 15, 3, 16, 1002, 15, 10, 15, 1, 15, 16, 15, 4, 15, 99, 0, 0,
+        ]);
+        let search = Search::new(&program).input_permutations(&[1, 3, 2]);
+
+        let (candidate, score) = search.find_max(|_, mut p| p.run().back().cloned()).unwrap();
+        assert_eq!(candidate.inputs, [3, 2, 1]);
+        assert_eq!(score, Some(32));
+    }
+}
@@ -0,0 +1,168 @@
+use crate::decode::code_cells;
+use crate::{Addr, Program, Value};
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+/// What to do when a program writes to its own code
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub enum SelfModifyingPolicy {
+    /// Don't track anything, this is the default and has no overhead
+    #[default]
+    Ignore,
+    /// Record every write in `Program::self_modifications()` and continue
+    Warn,
+    /// Panic on the first write to code
+    Fault,
+}
+
+/// A single write that landed on code
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct SelfModification {
+    /// Number of instructions executed before the write
+    pub step: usize,
+    /// Address of the instruction performing the write
+    pub writer: usize,
+    /// Address of the instruction that was written to
+    pub instruction: usize,
+    pub address: usize,
+    pub old: Value,
+    pub new: Value,
+    /// Whether the instruction had already been executed, or was only statically decoded
+    pub executed: bool,
+}
+
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Tracker {
+    pub(crate) policy: SelfModifyingPolicy,
+    // cell -> instruction address, decoded from the image
+    code: Arc<BTreeMap<Addr, Addr>>,
+    // cell -> instruction address, for every instruction executed so far
+    executed: BTreeMap<Addr, Addr>,
+    modifications: Vec<SelfModification>,
+}
+
+impl Program {
+    /// Set how writes to code are handled.
+    /// Code is every instruction executed so far plus the statically reachable code of the image.
+    pub fn set_self_modifying_policy(&mut self, policy: SelfModifyingPolicy) {
+        if policy != SelfModifyingPolicy::Ignore && self.self_modifying.code.is_empty() {
+            self.self_modifying.code = Arc::new(code_cells(&self.image, &self.extensions));
+        }
+        self.self_modifying.policy = policy;
+    }
+    /// All writes to code recorded so far, in order
+    pub fn self_modifications(&self) -> &[SelfModification] {
+        &self.self_modifying.modifications
+    }
+    /// Addresses of the instructions which were rewritten at runtime
+    pub fn rewritten_instructions(&self) -> BTreeSet<usize> {
+        self.self_modifying
+            .modifications
+            .iter()
+            .filter(|m| m.old != m.new)
+            .map(|m| m.instruction)
+            .collect()
+    }
+
+    pub(crate) fn track_execution(&mut self) {
+        let addr = self.instruction_ptr;
+        let len = self.current_instruction().opcode.len();
+        for cell in addr..addr + len {
+            self.self_modifying.executed.insert(cell, addr);
+        }
+    }
+    pub(crate) fn check_write(&mut self, address: Addr, value: Value) {
+        let tracker = &self.self_modifying;
+        let (instruction, executed) = match tracker.executed.get(&address) {
+            Some(instruction) => (*instruction, true),
+            None => match tracker.code.get(&address) {
+                Some(instruction) => (*instruction, false),
+                None => return,
+            },
+        };
+
+        let modification = SelfModification {
+            step: self.elapsed,
+            writer: self.instruction_ptr,
+            instruction,
+            address,
+            old: self.value_at(address),
+            new: value,
+            executed,
+        };
+        if tracker.policy == SelfModifyingPolicy::Fault {
+            panic!("Self-modifying code: {:?}", modification)
+        }
+        self.self_modifying.modifications.push(modification);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    #[test]
+    fn test_detects_rewritten_instruction() {
+        // rewrites the multiplication at 4 into an addition before executing it
+        let mut p = Program::new(&[1101, 0, 1, 4, 2, 0, 0, 9, 99, 0]);
+        p.set_self_modifying_policy(SelfModifyingPolicy::Warn);
+        p.run();
+
+        assert_eq!(p.inspect(9), 2202);
+        assert_eq!(
+            p.self_modifications(),
+            [SelfModification {
+                step: 0,
+                writer: 0,
+                instruction: 4,
+                address: 4,
+                old: 2,
+                new: 1,
+                executed: false,
+            }]
+        );
+        assert_eq!(
+            p.rewritten_instructions().into_iter().collect::<Vec<_>>(),
+            [4]
+        );
+    }
+
+    #[test]
+    fn test_detects_write_to_executed_code() {
+        // the input instruction at 0 is executed, then its parameter is overwritten
+        let mut p = Program::new(&[3, 9, 1101, 5, 5, 1, 99, 0, 0, 0]);
+        p.set_self_modifying_policy(SelfModifyingPolicy::Warn);
+        p.set_input(7);
+        p.run();
+
+        let modification = &p.self_modifications()[0];
+        assert_eq!(p.self_modifications().len(), 1);
+        assert_eq!(modification.instruction, 0);
+        assert_eq!(modification.address, 1);
+        assert_eq!((modification.old, modification.new), (9, 10));
+        assert!(modification.executed);
+    }
+
+    #[test]
+    fn test_data_writes_are_ignored() {
+        // Day 9 quine only modifies its data
+        let data = vec![
+            109, 1, 204, -1, 1001, 100, 1, 100, 1008, 100, 16, 101, 1006, 101, 0, 99,
+        ];
+        let mut p = Program::new(&data);
+        p.set_self_modifying_policy(SelfModifyingPolicy::Fault);
+        p.run();
+
+        assert!(p.self_modifications().is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "Self-modifying code")]
+    fn test_fault_policy() {
+        let mut p = Program::new(&[1101, 0, 1, 4, 2, 0, 0, 0, 99]);
+        p.set_self_modifying_policy(SelfModifyingPolicy::Fault);
+        p.run();
+    }
+}
@@ -0,0 +1,248 @@
+#[cfg(feature = "std")]
+use crate::binary::is_binary;
+use crate::binary::BinaryError;
+use crate::patch::Patch;
+use crate::{parse_image, Program, Value};
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::fmt::{Display, Error, Formatter};
+#[cfg(feature = "std")]
+use std::fs;
+#[cfg(feature = "std")]
+use std::path::Path;
+
+/// A saved machine state, which can be written to and read from a text file, or a binary one
+/// with `to_bytes()` and `from_bytes()`.
+///
+/// Memory is stored as the original image plus every address that differs from it, so the
+/// patches applied to the image stay visible:
+/// ```text
+/// image: 1,0,0,0,99
+/// ip: 4
+/// rb: 0
+/// elapsed: 1
+/// input:
+/// output:
+/// patch: 1: 5
+/// memory: 0: 10, 5
+/// ```
+/// Custom opcodes and self-modifying code tracking are not part of a snapshot.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Snapshot {
+    pub image: Vec<Value>,
+    pub instruction_ptr: usize,
+    pub relative_base: usize,
+    pub elapsed: usize,
+    pub input: Vec<Value>,
+    pub output: Vec<Value>,
+    pub patches: Vec<Patch>,
+    /// Runs of memory which differ from the image
+    pub memory: Vec<Patch>,
+}
+
+impl Snapshot {
+    /// Loads a text or binary snapshot
+    #[cfg(feature = "std")]
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, SnapshotError> {
+        let content = fs::read(path).map_err(|e| SnapshotError::Io(e.to_string()))?;
+        if is_binary(&content) {
+            return Snapshot::from_bytes(&content).map_err(SnapshotError::Binary);
+        }
+        String::from_utf8_lossy(&content).parse()
+    }
+    #[cfg(feature = "std")]
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), SnapshotError> {
+        fs::write(path, self.to_string()).map_err(|e| SnapshotError::Io(e.to_string()))
+    }
+    /// Saves the snapshot in the compact binary format
+    #[cfg(feature = "std")]
+    pub fn save_binary<P: AsRef<Path>>(&self, path: P) -> Result<(), SnapshotError> {
+        fs::write(path, self.to_bytes()).map_err(|e| SnapshotError::Io(e.to_string()))
+    }
+}
+
+impl Display for Snapshot {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        let join = |values: &[Value]| {
+            values
+                .iter()
+                .map(Value::to_string)
+                .collect::<Vec<_>>()
+                .join(",")
+        };
+        writeln!(f, "image: {}", join(&self.image))?;
+        writeln!(f, "ip: {}", self.instruction_ptr)?;
+        writeln!(f, "rb: {}", self.relative_base)?;
+        writeln!(f, "elapsed: {}", self.elapsed)?;
+        writeln!(f, "input: {}", join(&self.input))?;
+        writeln!(f, "output: {}", join(&self.output))?;
+        for patch in &self.patches {
+            writeln!(f, "patch: {}", patch)?;
+        }
+        for run in &self.memory {
+            writeln!(f, "memory: {}", run)?;
+        }
+        Ok(())
+    }
+}
+
+impl core::str::FromStr for Snapshot {
+    type Err = SnapshotError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut snapshot = Snapshot {
+            image: Vec::new(),
+            instruction_ptr: 0,
+            relative_base: 0,
+            elapsed: 0,
+            input: Vec::new(),
+            output: Vec::new(),
+            patches: Vec::new(),
+            memory: Vec::new(),
+        };
+        for (i, line) in s.lines().enumerate() {
+            let invalid = || SnapshotError::Parse {
+                line: i + 1,
+                content: line.to_string(),
+            };
+            if line.trim().is_empty() {
+                continue;
+            }
+            let mut parts = line.splitn(2, ':');
+            let key = parts.next().unwrap().trim();
+            let value = parts.next().ok_or_else(invalid)?.trim();
+            match key {
+                "image" => snapshot.image = parse_image(value).map_err(|_| invalid())?,
+                "ip" => snapshot.instruction_ptr = value.parse().map_err(|_| invalid())?,
+                "rb" => snapshot.relative_base = value.parse().map_err(|_| invalid())?,
+                "elapsed" => snapshot.elapsed = value.parse().map_err(|_| invalid())?,
+                "input" => snapshot.input = parse_image(value).map_err(|_| invalid())?,
+                "output" => snapshot.output = parse_image(value).map_err(|_| invalid())?,
+                "patch" | "memory" => {
+                    let run = parse_run(value).ok_or_else(invalid)?;
+                    if key == "patch" {
+                        snapshot.patches.push(run)
+                    } else {
+                        snapshot.memory.push(run)
+                    }
+                }
+                _ => return Err(invalid()),
+            }
+        }
+        Ok(snapshot)
+    }
+}
+
+fn parse_run(text: &str) -> Option<Patch> {
+    let mut parts = text.splitn(2, ':');
+    let address = parts.next()?.trim().parse().ok()?;
+    let values = parse_image(parts.next()?).ok()?;
+    Some(Patch { address, values }).filter(|run| run.range().is_some())
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum SnapshotError {
+    Io(String),
+    Parse { line: usize, content: String },
+    Binary(BinaryError),
+}
+
+impl Display for SnapshotError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        match self {
+            SnapshotError::Io(e) => write!(f, "Could not access snapshot: {}", e),
+            SnapshotError::Parse { line, content } => {
+                write!(f, "Invalid snapshot line {}: {}", line, content)
+            }
+            SnapshotError::Binary(e) => write!(f, "Invalid binary snapshot: {}", e),
+        }
+    }
+}
+
+impl core::error::Error for SnapshotError {}
+
+impl Program {
+    /// Captures the current machine state
+    pub fn snapshot(&self) -> Snapshot {
+        let memory = self
+            .diff_image()
+            .memory
+            .into_iter()
+            .map(|run| Patch {
+                address: run.start,
+                values: run.new,
+            })
+            .collect();
+        Snapshot {
+            image: self.image.to_vec(),
+            instruction_ptr: self.instruction_ptr,
+            relative_base: self.relative_base,
+            elapsed: self.elapsed,
+            input: self.input.iter().cloned().collect(),
+            output: self.output.iter().cloned().collect(),
+            patches: self.patches.clone(),
+            memory,
+        }
+    }
+    /// Recreates a program in the state captured by `snapshot`. Panics if a memory run ends past
+    /// the end of the address space, parsed snapshots never do.
+    pub fn from_snapshot(snapshot: &Snapshot) -> Self {
+        let mut program = Program::new(&snapshot.image);
+        for run in &snapshot.memory {
+            let range = run
+                .range()
+                .expect("A memory run ends past the end of the address space");
+            for (address, value) in range.zip(&run.values) {
+                program.memory.insert(address, *value);
+            }
+        }
+        program.instruction_ptr = snapshot.instruction_ptr;
+        program.relative_base = snapshot.relative_base;
+        program.elapsed = snapshot.elapsed;
+        program.input = snapshot.input.iter().cloned().collect();
+        program.output = snapshot.output.iter().cloned().collect();
+        program.patches = snapshot.patches.clone();
+        program
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    #[test]
+    fn test_snapshot_roundtrip() {
+        // adds the input to itself and outputs it, forever
+        let data = vec![3, 13, 1, 13, 13, 13, 4, 13, 1105, 1, 0, 99, 99, 0];
+        let mut p = Program::new(&data);
+        p.poke(12, 7);
+        p.set_input(21);
+        p.set_input(1);
+        p.run_pipe();
+
+        let snapshot = p.snapshot();
+        let text = snapshot.to_string();
+        assert!(text.contains("patch: 12: 7\n"));
+        assert!(text.contains("memory: 12: 7, 42\n"));
+
+        let parsed: Snapshot = text.parse().unwrap();
+        assert_eq!(parsed, snapshot);
+
+        let mut restored = Program::from_snapshot(&parsed);
+        assert_eq!(restored.snapshot(), snapshot);
+        assert_eq!(restored.run_pipe(), Some(2));
+    }
+
+    #[test]
+    fn test_snapshot_errors() {
+        let result: Result<Snapshot, _> = "ip: 1\nrb: x".parse();
+        assert_eq!(
+            result,
+            Err(SnapshotError::Parse {
+                line: 2,
+                content: "rb: x".to_string()
+            })
+        );
+    }
+}
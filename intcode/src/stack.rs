@@ -0,0 +1,206 @@
+//! Call stacks reconstructed from the way compiled intcode uses the relative base.
+//!
+//! Compilers targeting intcode treat the relative base as a stack pointer. A call stores the
+//! return address at `[rb]` and jumps to the function, which moves the relative base past its
+//! arguments and locals with `SetRelativeBase`. It returns by moving the relative base back and
+//! jumping through `[rb]`:
+//! ```text
+//! add 12, 0, [rb+0]   ; return address
+//! add 5, 0, [rb+1]    ; argument
+//! jt 1, 30            ; call
+//! ...
+//! arb 3               ; function at 30
+//! ...
+//! arb -3
+//! jf 0, [rb+0]        ; return
+//! ```
+//! A call is detected as a jump which is taken while `[rb]` holds the return address, the address
+//! right after the jump instruction. A jump to the return address of an active call returns from
+//! it and from everything it called.
+
+use crate::link::SymbolMap;
+use crate::{Addr, Program, Value};
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::fmt::{Display, Error, Formatter};
+
+/// Locals shown per frame at most
+const MAX_LOCALS: usize = 32;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+struct Call {
+    call_site: Addr,
+    function: Addr,
+    return_address: Addr,
+    base: Addr,
+}
+
+#[derive(Debug, Clone, Default)]
+pub(crate) struct CallStack {
+    calls: Vec<Call>,
+}
+
+/// A function activation, see `Program::backtrace()`
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Frame {
+    /// Entry address of the function, 0 for the outermost frame
+    pub function: Addr,
+    /// The instruction pointer for the innermost frame, the pending call for the others
+    pub address: Addr,
+    /// None for the outermost frame
+    pub return_address: Option<Addr>,
+    /// The relative base at the call, where the return address is stored
+    pub base: Addr,
+    /// Arguments and locals: the cells after the return address up to the next frame, or up to
+    /// the relative base for the innermost frame
+    pub locals: Vec<Value>,
+}
+
+impl Frame {
+    /// Like `to_string()`, with the addresses written as symbols, e.g. `fib+14 in fib (rb 2010)`
+    pub fn to_string_with(&self, symbols: &SymbolMap) -> String {
+        let mut text = format!(
+            "{} in {}",
+            symbols.format(self.address),
+            symbols.format(self.function)
+        );
+        if self.return_address.is_some() {
+            text += &format!(" (rb {})", self.base);
+        }
+        if !self.locals.is_empty() {
+            let locals: Vec<_> = self.locals.iter().map(Value::to_string).collect();
+            text += &format!(": {}", locals.join(", "));
+        }
+        text
+    }
+}
+
+/// Formats like `1034 in 80 (rb 2010): 5, 7`
+impl Display for Frame {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        write!(f, "{}", self.to_string_with(&SymbolMap::new()))
+    }
+}
+
+impl Program {
+    /// Start following calls and returns, needed for `backtrace()`
+    pub fn track_calls(&mut self) {
+        if self.call_stack.is_none() {
+            self.call_stack = Some(CallStack::default());
+        }
+    }
+    /// The reconstructed call stack, innermost frame first. Only calls made after
+    /// `track_calls()` are known, without it there is just the outermost frame.
+    pub fn backtrace(&self) -> Vec<Frame> {
+        let calls = self
+            .call_stack
+            .as_ref()
+            .map_or(&[][..], |stack| &stack.calls[..]);
+        let locals = |start: Addr, end: Addr| -> Vec<Value> {
+            (start..end.min(start + MAX_LOCALS))
+                .map(|address| self.value_at(address))
+                .collect()
+        };
+
+        let mut frames = Vec::with_capacity(calls.len() + 1);
+        let mut address = self.instruction_ptr;
+        let mut end = self.relative_base;
+        for call in calls.iter().rev() {
+            frames.push(Frame {
+                function: call.function,
+                address,
+                return_address: Some(call.return_address),
+                base: call.base,
+                locals: locals(call.base + 1, end),
+            });
+            address = call.call_site;
+            end = call.base;
+        }
+        frames.push(Frame {
+            function: 0,
+            address,
+            return_address: None,
+            base: 0,
+            locals: Vec::new(),
+        });
+        frames
+    }
+    /// Called after executing the instruction at `address` of length `len`,
+    /// with the relative base it saw
+    pub(crate) fn track_call(&mut self, address: Addr, len: usize, relative_base: Addr) {
+        let next = address + len;
+        let target = self.instruction_ptr;
+        if target == next {
+            return;
+        }
+        let return_address = self.value_at(relative_base);
+        let stack = self.call_stack.as_mut().unwrap();
+        if return_address == next as Value {
+            stack.calls.push(Call {
+                call_site: address,
+                function: target,
+                return_address: next,
+                base: relative_base,
+            });
+        } else if let Some(i) = stack
+            .calls
+            .iter()
+            .rposition(|call| call.return_address == target)
+        {
+            stack.calls.truncate(i);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    #[test]
+    fn test_backtrace() {
+        // main calls f(5), f calls g(x + 1), g outputs its argument
+        let image = [
+            109, 100, // arb 100
+            21101, 13, 0, 0, // return address
+            21101, 5, 0, 1, // argument
+            1105, 1, 14, // call f
+            99, //
+            109, 2, // 14: f
+            21101, 27, 0, 0, // return address
+            22101, 1, -1, 1, // argument
+            1105, 1, 32, // call g
+            109, -2, //
+            2106, 0, 0, // return
+            109, 2, // 32: g
+            204, -1, // output argument
+            109, -2, //
+            2106, 0, 0, // return
+        ];
+        let mut p = Program::new(&image);
+        p.track_calls();
+        while p.output_queue().is_empty() {
+            p.step();
+        }
+
+        let frames: Vec<_> = p.backtrace().iter().map(Frame::to_string).collect();
+        assert_eq!(
+            frames,
+            ["36 in 32 (rb 102): 6", "24 in 14 (rb 100): 5", "10 in 0"]
+        );
+        assert_eq!(p.backtrace()[1].return_address, Some(13));
+
+        p.run();
+        assert_eq!(
+            p.backtrace(),
+            [Frame {
+                function: 0,
+                address: 13,
+                return_address: None,
+                base: 0,
+                locals: vec![]
+            }]
+        );
+    }
+}
@@ -0,0 +1,177 @@
+//! Data-flow tracking from inputs to outputs.
+//!
+//! Every input gets a label and every memory cell carries the labels of the inputs its value was
+//! computed from. `Add`, `Mul`, `LessThan` and `Equals` combine the labels of their operands,
+//! `Input` replaces the labels of its target and `Output` reports the labels of its value.
+//! Only explicit data flow is tracked: values which merely decide a jump, an address or the
+//! relative base don't pass on their labels, and custom opcodes are treated as not touching
+//! memory.
+
+use crate::{Addr, OpCode, Program, Value};
+use alloc::collections::{BTreeMap, BTreeSet, VecDeque};
+use alloc::string::ToString;
+use alloc::vec::Vec;
+use core::fmt::{Display, Error, Formatter};
+
+/// Indices of the inputs a value depends on
+pub type Labels = BTreeSet<usize>;
+
+/// A value with the inputs it was computed from
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Tainted {
+    pub value: Value,
+    pub labels: Labels,
+}
+
+/// Formats like `42 <- {0, 2}`
+impl Display for Tainted {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        let labels: Vec<_> = self.labels.iter().map(usize::to_string).collect();
+        write!(f, "{} <- {{{}}}", self.value, labels.join(", "))
+    }
+}
+
+/// Runs a program while following which inputs flow into which memory cells
+#[derive(Debug, Clone)]
+pub struct TaintTracker {
+    program: Program,
+    shadow: BTreeMap<Addr, Labels>,
+    input: VecDeque<Labels>,
+    next_label: usize,
+}
+
+impl TaintTracker {
+    /// Input already queued on `program` is labelled 0, 1, ... in queue order
+    pub fn new(program: Program) -> Self {
+        let queued = program.input_queue().len();
+        TaintTracker {
+            program,
+            shadow: BTreeMap::new(),
+            input: (0..queued).map(|label| Labels::from([label])).collect(),
+            next_label: queued,
+        }
+    }
+    pub fn program(&self) -> &Program {
+        &self.program
+    }
+    /// Queues `value` with a new label and returns the label
+    pub fn input(&mut self, value: Value) -> usize {
+        let label = self.next_label;
+        self.input_tainted(Tainted {
+            value,
+            labels: Labels::from([label]),
+        });
+        label
+    }
+    /// Queues a value which keeps the labels it got elsewhere, e.g. the output of another machine
+    pub fn input_tainted(&mut self, input: Tainted) {
+        if let Some(&last) = input.labels.iter().next_back() {
+            self.next_label = self.next_label.max(last + 1);
+        }
+        self.program.set_input(input.value);
+        self.input.push_back(input.labels);
+    }
+    /// Labels of the value at `address`
+    pub fn labels(&self, address: usize) -> Labels {
+        self.shadow.get(&address).cloned().unwrap_or_default()
+    }
+    /// Runs like `Program::run()` until the program halts or needs input and returns the
+    /// output produced on the way
+    pub fn run(&mut self) -> Vec<Tainted> {
+        let mut output = Vec::new();
+        while !self.program.is_halted() && !self.program.needs_input() {
+            output.extend(self.step());
+        }
+        output
+    }
+    /// Executes a single instruction and returns its output, if any.
+    /// Like `Program::step()` it panics if input is needed but none is queued.
+    pub fn step(&mut self) -> Option<Tainted> {
+        let program = &self.program;
+        let instruction = program.current_instruction();
+        let modes = instruction.parameter_modes;
+        let read = |param_pos: usize| {
+            let address = program.parameter_address(param_pos, modes[param_pos - 1]);
+            self.labels(address)
+        };
+
+        let mut output = None;
+        match instruction.opcode {
+            OpCode::Add | OpCode::Mul | OpCode::LessThan | OpCode::Equals => {
+                let labels = &read(1) | &read(2);
+                let target = program.write_target();
+                self.taint(target, labels);
+            }
+            OpCode::Input => {
+                let labels = self.input.pop_front().unwrap_or_default();
+                let target = self.program.write_target();
+                self.taint(target, labels);
+            }
+            OpCode::Output => output = Some(read(1)),
+            _ => {}
+        }
+
+        self.program.step();
+        output.map(|labels| Tainted {
+            value: *self.program.output_queue().back().unwrap(),
+            labels,
+        })
+    }
+    fn taint(&mut self, target: Option<Addr>, labels: Labels) {
+        if let Some(address) = target {
+            if labels.is_empty() {
+                self.shadow.remove(&address);
+            } else {
+                self.shadow.insert(address, labels);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_propagation() {
+        // reads a, b and c, outputs a + a, b * 3, a < c and the constant 7
+        let mut p = Program::new(&[
+            3, 100, 3, 101, 3, 102, 1, 100, 100, 103, 4, 103, 1002, 101, 3, 104, 4, 104, 7, 100,
+            102, 105, 4, 105, 104, 7, 99,
+        ]);
+        p.set_input(5);
+        let mut tracker = TaintTracker::new(p);
+        assert_eq!(tracker.input(2), 1);
+        assert_eq!(tracker.input(9), 2);
+
+        let output: Vec<_> = tracker.run().iter().map(Tainted::to_string).collect();
+        assert_eq!(output, ["10 <- {0}", "6 <- {1}", "1 <- {0, 2}", "7 <- {}"]);
+        assert_eq!(tracker.labels(104), Labels::from([1]));
+    }
+
+    #[test]
+    fn test_amplifier_chain() {
+        // day 7 example, each amplifier outputs 10 * signal + 5 - phase
+        let image = [
+            3, 23, 3, 24, 1002, 24, 10, 24, 1002, 23, -1, 23, 101, 5, 23, 23, 1, 24, 23, 23, 4, 23,
+            99, 0, 0,
+        ];
+        let mut signal = Tainted {
+            value: 0,
+            labels: Labels::new(),
+        };
+        let phases = [0, 1, 2, 3, 4];
+        for &phase in &phases {
+            let mut amplifier = TaintTracker::new(Program::new(&image));
+            // phase settings are labelled with their amplifier's index
+            amplifier.input_tainted(Tainted {
+                value: phase,
+                labels: Labels::from([phase as usize]),
+            });
+            amplifier.input_tainted(signal);
+            signal = amplifier.run().pop().unwrap();
+        }
+        assert_eq!(signal.value, 54321);
+        assert_eq!(signal.labels, Labels::from([0, 1, 2, 3, 4]));
+    }
+}
@@ -0,0 +1,328 @@
+//! Full-screen views of running machines, drawn with ANSI escape sequences.
+//!
+//! The views only produce text, the `intcode-tui` binary owns the terminal and the keyboard.
+
+use crate::{Program, Value};
+use std::collections::VecDeque;
+
+const RESET: &str = "\x1b[0m";
+const BOLD: &str = "\x1b[1m";
+const REVERSE: &str = "\x1b[7m";
+const LATEST_WRITE: &str = "\x1b[1;33m";
+const RECENT_WRITE: &str = "\x1b[33m";
+
+/// Number of writes which stay highlighted in the memory view
+const RECENT_WRITES: usize = 8;
+
+/// A machine with the bookkeeping needed to draw it
+#[derive(Debug)]
+pub struct Monitor {
+    name: String,
+    program: Program,
+    writes: VecDeque<usize>,
+}
+
+impl Monitor {
+    pub fn new(name: &str, program: Program) -> Self {
+        Monitor {
+            name: name.to_string(),
+            program,
+            writes: VecDeque::new(),
+        }
+    }
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+    pub fn program(&self) -> &Program {
+        &self.program
+    }
+    pub fn program_mut(&mut self) -> &mut Program {
+        &mut self.program
+    }
+    /// Addresses of the most recent writes, latest first
+    pub fn recent_writes(&self) -> impl Iterator<Item = &usize> {
+        self.writes.iter()
+    }
+    /// Whether the machine can execute its next instruction
+    pub fn is_runnable(&self) -> bool {
+        !self.program.is_halted() && !self.program.needs_input()
+    }
+    /// Executes one instruction unless halted or waiting for input, returns whether it did
+    pub fn step(&mut self) -> bool {
+        if !self.is_runnable() {
+            return false;
+        }
+        if let Some(address) = self.program.write_target() {
+            self.writes.retain(|&a| a != address);
+            self.writes.push_front(address);
+            self.writes.truncate(RECENT_WRITES);
+        }
+        self.program.step()
+    }
+    fn status(&self) -> &'static str {
+        if self.program.is_halted() {
+            "HALTED"
+        } else if self.program.needs_input() {
+            "WAITING"
+        } else {
+            "RUNNING"
+        }
+    }
+    /// Draws the machine into `height` lines of `width` visible characters: registers,
+    /// disassembly around the instruction pointer, memory around the latest write and the
+    /// I/O queues
+    pub fn render(&self, width: usize, height: usize, hex: bool) -> Vec<String> {
+        let program = &self.program;
+        let ip = program.instruction_ptr();
+        let mut lines = vec![
+            format!(
+                "{}{}{}",
+                BOLD,
+                fit(&format!("{} {}", self.name, self.status()), width),
+                RESET
+            ),
+            fit(
+                &format!(
+                    "ip {}  rb {}  steps {}",
+                    ip,
+                    program.relative_base(),
+                    program.elapsed()
+                ),
+                width,
+            ),
+            "─".repeat(width),
+        ];
+
+        let rows = height.saturating_sub(7);
+        let code_rows = rows / 2;
+        let memory_rows = rows - code_rows;
+
+        let starts = program.instruction_starts(ip);
+        let before = &starts[starts.len().saturating_sub(code_rows / 3)..];
+        let mut listing: Vec<_> = before
+            .iter()
+            .map(|&address| (address, program.disassemble(address)))
+            .collect();
+        listing.extend(program.disassemble_range(ip, code_rows - listing.len()));
+        for (address, disassembly) in listing {
+            let text = match disassembly {
+                Some(disassembly) => format!("{:>6}  {}", address, disassembly),
+                None => format!("{:>6}  {}", address, program.peek(address)),
+            };
+            if address == ip {
+                lines.push(format!("{}{}{}", REVERSE, fit(&text, width), RESET));
+            } else {
+                lines.push(fit(&text, width));
+            }
+        }
+        lines.push("─".repeat(width));
+
+        let columns = (width.saturating_sub(6) / 7).max(1);
+        let center = self.writes.front().cloned().unwrap_or(ip);
+        let first = (center / columns).saturating_sub(memory_rows / 2) * columns;
+        for row in 0..memory_rows {
+            let start = first + row * columns;
+            let mut line = format!("{:>5}:", start);
+            for address in start..start + columns {
+                let value = program.peek(address);
+                let cell = if hex {
+                    format!(" {:>6}", to_hex(value))
+                } else {
+                    format!(" {:>6}", value)
+                };
+                match self.writes.iter().position(|&a| a == address) {
+                    Some(0) => line.push_str(&format!("{}{}{}", LATEST_WRITE, cell, RESET)),
+                    Some(_) => line.push_str(&format!("{}{}{}", RECENT_WRITE, cell, RESET)),
+                    None => line.push_str(&cell),
+                }
+            }
+            lines.push(line);
+        }
+        lines.push("─".repeat(width));
+
+        lines.push(fit(&queue("in", program.input_queue(), width), width));
+        lines.push(fit(&queue("out", program.output_queue(), width), width));
+        lines.truncate(height);
+        lines
+    }
+}
+
+/// Several machines connected by their I/O queues
+#[derive(Debug, Default)]
+pub struct Dashboard {
+    monitors: Vec<Monitor>,
+    links: Vec<(usize, usize)>,
+}
+
+impl Dashboard {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Machines running `image` in a feedback loop like the day 7 amplifiers: each one gets its
+    /// phase as first input, the first one also gets a 0 to start the loop
+    pub fn ring(image: &[Value], phases: &[Value]) -> Self {
+        let mut dashboard = Dashboard::new();
+        for (i, &phase) in phases.iter().enumerate() {
+            let mut program = Program::new(image);
+            program.set_input(phase);
+            if i == 0 {
+                program.set_input(0);
+            }
+            dashboard.add(Monitor::new(&format!("amp {}", i), program));
+        }
+        for i in 0..phases.len() {
+            dashboard.link(i, (i + 1) % phases.len());
+        }
+        dashboard
+    }
+    /// Adds a machine and returns its index
+    pub fn add(&mut self, monitor: Monitor) -> usize {
+        self.monitors.push(monitor);
+        self.monitors.len() - 1
+    }
+    /// Feeds the output of machine `from` into the input of machine `to`
+    pub fn link(&mut self, from: usize, to: usize) {
+        self.links.push((from, to));
+    }
+    pub fn monitors(&self) -> &[Monitor] {
+        &self.monitors
+    }
+    pub fn monitors_mut(&mut self) -> &mut [Monitor] {
+        &mut self.monitors
+    }
+    /// Steps every machine once and moves output along the links.
+    /// Returns false once no machine can make progress.
+    pub fn tick(&mut self) -> bool {
+        let mut progress = false;
+        for monitor in &mut self.monitors {
+            progress |= monitor.step();
+        }
+        for &(from, to) in &self.links {
+            for value in self.monitors[from].program.take_output() {
+                self.monitors[to].program.set_input(value);
+                progress = true;
+            }
+        }
+        progress
+    }
+    /// Draws all machines side by side
+    pub fn render(&self, width: usize, height: usize, hex: bool) -> String {
+        let count = self.monitors.len().max(1);
+        let panel_width = (width.saturating_sub(3 * (count - 1)) / count).max(1);
+        let panels: Vec<_> = self
+            .monitors
+            .iter()
+            .map(|monitor| monitor.render(panel_width, height, hex))
+            .collect();
+        (0..height)
+            .map(|row| {
+                let cells: Vec<_> = panels
+                    .iter()
+                    .map(|panel| fit_styled(panel.get(row).map_or("", String::as_str), panel_width))
+                    .collect();
+                cells.join(" │ ")
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+fn queue(label: &str, values: &VecDeque<Value>, width: usize) -> String {
+    // Show the latest values when they don't fit
+    let mut text = String::new();
+    for value in values.iter().rev() {
+        let next = if text.is_empty() {
+            value.to_string()
+        } else {
+            format!("{},{}", value, text)
+        };
+        if label.len() + 2 + next.len() > width {
+            text = format!("…,{}", text);
+            break;
+        }
+        text = next;
+    }
+    format!("{}: {}", label, text)
+}
+
+fn to_hex(value: Value) -> String {
+    if value < 0 {
+        format!("-{:x}", -(value as i128))
+    } else {
+        format!("{:x}", value)
+    }
+}
+
+/// Cuts or pads plain text to exactly `width` characters
+fn fit(text: &str, width: usize) -> String {
+    let text: String = text.chars().take(width).collect();
+    format!("{:<1$}", text, width)
+}
+
+/// Cuts or pads text containing escape sequences to exactly `width` visible characters
+fn fit_styled(text: &str, width: usize) -> String {
+    let mut fitted = String::new();
+    let mut visible = 0;
+    let mut escape = false;
+    for c in text.chars() {
+        match c {
+            '\x1b' => escape = true,
+            'm' if escape => escape = false,
+            _ if escape => {}
+            _ if visible == width => continue,
+            _ => visible += 1,
+        }
+        fitted.push(c);
+    }
+    fitted.push_str(&" ".repeat(width - visible));
+    fitted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_recent_writes() {
+        let mut monitor = Monitor::new(
+            "test",
+            Program::new(&[1101, 1, 2, 9, 21101, 3, 4, 2, 99, 0]),
+        );
+        monitor.program_mut().set_relative_base(8);
+        monitor.step();
+        monitor.step();
+        assert!(!monitor.step());
+        assert_eq!(monitor.recent_writes().collect::<Vec<_>>(), [&10, &9]);
+
+        let lines = monitor.render(40, 14, false);
+        assert_eq!(lines.len(), 14);
+        assert_eq!(
+            lines[0],
+            format!("{}test HALTED{}{}", BOLD, " ".repeat(29), RESET)
+        );
+        assert!(lines
+            .iter()
+            .any(|line| line.contains(&format!("{}      7{}", LATEST_WRITE, RESET))));
+        assert!(lines
+            .iter()
+            .any(|line| line.starts_with(&format!("{}     8  hlt", REVERSE))));
+    }
+
+    #[test]
+    fn test_ring() {
+        let image = [
+            3, 26, 1001, 26, -4, 26, 3, 27, 1002, 27, 2, 27, 1, 27, 26, 27, 4, 27, 1001, 28, -1,
+            28, 1005, 28, 6, 99, 0, 0, 5,
+        ];
+        let mut dashboard = Dashboard::ring(&image, &[9, 8, 7, 6, 5]);
+        while dashboard.tick() {}
+
+        let first = dashboard.monitors()[0].program();
+        assert!(first.is_halted());
+        assert_eq!(first.input_queue().back(), Some(&139629729));
+
+        let screen = dashboard.render(200, 20, true);
+        assert_eq!(screen.lines().count(), 20);
+        assert!(screen.contains("amp 4 HALTED"));
+    }
+}